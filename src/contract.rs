@@ -1,23 +1,60 @@
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
 use cosmwasm_std::{
-    to_binary, Binary, Deps, DepsMut, Env, MessageInfo, Response, StdResult, Uint128,
+    to_binary, Addr, Binary, Decimal, Deps, DepsMut, Env, MessageInfo, Order, Reply, Response,
+    StdError, StdResult, Uint128,
 };
-use cw2::set_contract_version;
+use cw2::{get_contract_version, set_contract_version};
 use terra_cosmwasm::TerraMsgWrapper;
+use terraswap::asset::AssetInfo;
+use white_whale::deposit_info::ArbBaseAsset;
 
 use crate::error::ContractError;
-use crate::execute_arb_create::{try_arb_create, try_callback_create};
-use crate::execute_arb_redeem::{try_callback_redeem, try_swap_to_ust_and_take_profit};
-use crate::execute_flash_loan::{try_flash_loan, try_user_profit};
-use crate::msg::{ExecuteMsg, InstantiateMsg, QueryMsg, UstVaultAddressResponse};
-use crate::query::query_estimate_arbitrage;
-use crate::state::{State, STATE};
+use crate::execute_access::{
+    try_add_executor, try_claim_ownership, try_drop_ownership_proposal, try_pause,
+    try_pause_cluster, try_propose_new_owner, try_remove_executor, try_unpause,
+    try_unpause_cluster, try_update_access_mode, try_update_guardian,
+};
+use crate::execute_arb_create::{
+    reply_arb_create, reply_create_basket, reply_direct_mint, reply_direct_sell_ct,
+    try_callback_create,
+};
+use crate::execute_arb_redeem::{
+    reply_arb_redeem, reply_direct_buy_ct, reply_swap_to_base, try_callback_redeem,
+};
+use crate::execute_cluster::{
+    assert_cluster_strategy, try_add_cluster, try_remove_cluster, try_update_cluster,
+};
+use crate::execute_flash_loan::{try_callback_batch, try_flash_loan, try_flash_loan_batch};
+use crate::execute_profit::{reply_burn_whale, try_update_keeper_reward, try_update_profit_split};
+use crate::execute_withdraw::{try_withdraw_native, try_withdraw_token};
+use crate::execute_wrapper::{try_add_wrapper, try_remove_wrapper};
+use crate::msg::{
+    AccessMode, ClusterStrategy, ExecuteMsg, InstantiateMsg, MigrateMsg, QueryMsg,
+    UstVaultAddressResponse, WrapperAdapter,
+};
+use crate::query::{
+    query_balances, query_clusters, query_config, query_estimate_arbitrage, query_executors,
+    query_history, query_list_opportunities, query_protocol_revenue, query_simulate_arbitrage,
+    query_stats, query_wrappers,
+};
+use crate::state::{ClusterConfig, State, ADMIN, CLUSTERS, LEGACY_STATE, STATE, WRAPPERS};
+use crate::utils::assert_slippage_tolerance;
 
 // version info for migration info
 const CONTRACT_NAME: &str = "crates.io:bbv";
 const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+// reply ids of the arbitrage steps
+pub const REPLY_CREATE_BASKET: u64 = 1;
+pub const REPLY_ARB_CREATE: u64 = 2;
+pub const REPLY_ARB_REDEEM: u64 = 3;
+pub const REPLY_SWAP_TO_BASE: u64 = 4;
+pub const REPLY_DIRECT_BUY_CT: u64 = 5;
+pub const REPLY_DIRECT_MINT: u64 = 6;
+pub const REPLY_DIRECT_SELL_CT: u64 = 7;
+pub const REPLY_BURN_WHALE: u64 = 8;
+
 /// ## Description
 /// Creates a new contract with the specified parameters packed in the `msg` variable.
 /// Returns a [`Response`] with the specified attributes if the operation was successful,
@@ -38,21 +75,52 @@ pub fn instantiate(
     info: MessageInfo,
     msg: InstantiateMsg,
 ) -> Result<Response, ContractError> {
+    assert_slippage_tolerance(msg.slippage_tolerance)?;
+
     let state = State {
         vault_address: deps.api.addr_validate(msg.vault_address.as_str())?,
-        incentive_addres: deps.api.addr_validate(msg.incentive_address.as_str())?,
+        incentive_address: deps.api.addr_validate(msg.incentive_address.as_str())?,
         astroport_factory_address: deps
             .api
             .addr_validate(msg.astroport_factory_address.as_str())?,
+        astroport_router_address: deps
+            .api
+            .addr_validate(msg.astroport_router_address.as_str())?,
+        terraswap_factory_address: deps
+            .api
+            .addr_validate(msg.terraswap_factory_address.as_str())?,
         aust_token_address: deps.api.addr_validate(msg.aust_token_address.as_str())?,
         anchor_market_contract: deps
             .api
             .addr_validate(msg.anchor_market_contract.as_str())?,
         profit_threshold: msg.profit_threshold,
-        owner_address: info.sender,
+        slippage_tolerance: msg.slippage_tolerance,
+        base_asset: ArbBaseAsset {
+            asset_info: match msg.base_asset {
+                AssetInfo::Token { contract_addr } => AssetInfo::Token {
+                    contract_addr: deps.api.addr_validate(contract_addr.as_str())?.to_string(),
+                },
+                native => native,
+            },
+        },
+        nebula_factory_address: msg
+            .nebula_factory_address
+            .map(|address| deps.api.addr_validate(address.as_str()))
+            .transpose()?,
+        access_mode: AccessMode::Permissionless,
     };
     set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
     STATE.save(deps.storage, &state)?;
+    // aUST is minted and redeemed through Anchor by default
+    WRAPPERS.save(
+        deps.storage,
+        &state.aust_token_address,
+        &WrapperAdapter::Anchor {
+            market: state.anchor_market_contract.clone(),
+            denom: "uusd".to_string(),
+        },
+    )?;
+    ADMIN.set(deps, Some(info.sender))?;
     Ok(Response::new())
 }
 
@@ -69,28 +137,62 @@ pub fn instantiate(
 /// - **msg** is an object of type [`ExecuteMsg`].
 ///
 /// ## Commands
-/// - **ExecuteMsg::FlashLoan { cluster_address
-///         }** Select a strategy and estimate cost amount to arbitrage.
+/// - **ExecuteMsg::FlashLoan { cluster_address, user_address, slippage_tolerance
+///   }** Select a strategy and estimate cost amount to arbitrage.
+///
+/// - **ExecuteMsg::FlashLoanBatch { clusters, user_address, slippage_tolerance }** Arbitrage
+///   several clusters under one flash loan.
+///
+/// - **ExecuteMsg::CallbackBatch {}** Batch actions to be performed with the loaned funds.
 ///
 /// - **ExecuteMsg::CallbackRedeem {}** Redeem actions to be performed with the loaned funds.
 ///
 /// - **ExecuteMsg::CallbackCreate{}** Create actions to be performed with the loaned funds.
 ///
-/// - **ExecuteMsg::ArbCreate {}** Increases allowances and sends funds to call ArbClusterCreate.
+/// - **ExecuteMsg::Pause {}** Stops every arbitrage.
+///
+/// - **ExecuteMsg::PauseCluster { cluster }** Pauses a single cluster.
+///
+/// - **ExecuteMsg::UnpauseCluster { cluster }** Resumes a paused cluster.
+///
+/// - **ExecuteMsg::Unpause {}** Resumes the arbitrages.
+///
+/// - **ExecuteMsg::UpdateGuardian { guardian }** Sets the address allowed to pause the contract.
+///
+/// - **ExecuteMsg::AddCluster { cluster_address, profit_threshold, max_loan, strategy, pair_address
+///   }** Registers a cluster that can be arbitraged.
+///
+/// - **ExecuteMsg::UpdateCluster { cluster_address, .. }** Updates the settings of a registered cluster.
+///
+/// - **ExecuteMsg::RemoveCluster { cluster_address }** Removes a cluster from the registry.
+///
+/// - **ExecuteMsg::UpdateProfitSplit { .. }** Sets how the arbitrage profit is shared.
+///
+/// - **ExecuteMsg::ProposeNewOwner { owner, expires }** Proposes a new owner, who has to claim
+///   the ownership before it expires.
+///
+/// - **ExecuteMsg::DropOwnershipProposal {}** Removes the pending ownership proposal.
+///
+/// - **ExecuteMsg::ClaimOwnership {}** Claims the ownership proposed to the sender.
 ///
-/// - **ExecuteMsg::_UserProfit {}** Sends all profit to user.
+/// - **ExecuteMsg::UpdateAccessMode { access_mode }** Sets who can trigger arbitrages.
 ///
-/// - **ExecuteMsg::WithdrawNative {
-///             send_to,
-///             denom,
-///         }** Sends all native to send_to.
+/// - **ExecuteMsg::AddExecutor { address }** Allows an address to trigger arbitrages in allowlist mode.
 ///
-/// - **ExecuteMsg::WithdrawToken {
-///             send_to,
-///             denom,
-///         }** Sends all token to send_to.
+/// - **ExecuteMsg::RemoveExecutor { address }** Removes an address from the executors.
 ///
-/// - **ExecuteMsg::SwapToUstAndTakeProfit {}** Swaps all asset to UST after that take a profit.
+/// - **ExecuteMsg::UpdateKeeperReward { keeper_reward }** Sets the reward paid to the keeper
+///   triggering an arbitrage.
+///
+/// - **ExecuteMsg::WithdrawNative { denom, send_to }** Sends all native to send_to, net of tax.
+///
+/// - **ExecuteMsg::WithdrawToken { token, send_to, amount }** Sends the token to send_to,
+///   all of it if no amount is given.
+///
+/// - **ExecuteMsg::AddWrapper { token, adapter }** Registers a wrapped asset that can be minted
+///   from or burned to its underlying.
+///
+/// - **ExecuteMsg::RemoveWrapper { token }** Removes a wrapped asset from the registry.
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn execute(
     deps: DepsMut,
@@ -102,91 +204,329 @@ pub fn execute(
         ExecuteMsg::FlashLoan {
             cluster_address,
             user_address,
-        } => try_flash_loan(deps, info, cluster_address, user_address),
+            slippage_tolerance,
+        } => try_flash_loan(
+            deps,
+            env,
+            info,
+            cluster_address,
+            user_address,
+            slippage_tolerance,
+        ),
+        ExecuteMsg::FlashLoanBatch {
+            clusters,
+            user_address,
+            slippage_tolerance,
+        } => try_flash_loan_batch(deps, env, info, clusters, user_address, slippage_tolerance),
         ExecuteMsg::_CallbackRedeem {
             cluster_address,
             user_address,
+            keeper_address,
             loan_amount,
             target,
             profit_threshold,
+            limits,
+            market_price,
+            intrinsic_price,
         } => try_callback_redeem(
             deps,
             env,
             info,
             cluster_address,
             user_address,
+            keeper_address,
             loan_amount,
             &target,
             profit_threshold,
+            limits,
+            market_price,
+            intrinsic_price,
         ),
         ExecuteMsg::_CallbackCreate {
             cluster_address,
             user_address,
+            keeper_address,
             loan_amount,
             target,
             prices,
             profit_threshold,
+            limits,
+            market_price,
+            intrinsic_price,
         } => try_callback_create(
             deps,
             env,
             info,
             cluster_address,
             user_address,
+            keeper_address,
             loan_amount,
             &target,
             &prices,
             profit_threshold,
+            limits,
+            market_price,
+            intrinsic_price,
         ),
-        ExecuteMsg::_ArbCreate {
-            cluster_address,
+        ExecuteMsg::_CallbackBatch {
             user_address,
+            keeper_address,
             loan_amount,
-            target,
+            legs,
             profit_threshold,
-        } => try_arb_create(
+        } => try_callback_batch(
             deps,
             env,
             info,
-            cluster_address,
             user_address,
+            keeper_address,
             loan_amount,
-            &target,
+            &legs,
             profit_threshold,
         ),
-        ExecuteMsg::_UserProfit {
-            user_address,
+        ExecuteMsg::AddCluster {
+            cluster_address,
             profit_threshold,
-        } => try_user_profit(deps, env, info, user_address, profit_threshold),
+            max_loan,
+            strategy,
+            pair_address,
+            cooldown,
+        } => try_add_cluster(
+            deps,
+            info,
+            cluster_address,
+            profit_threshold,
+            max_loan,
+            strategy,
+            pair_address,
+            cooldown,
+        ),
+        ExecuteMsg::UpdateCluster {
+            cluster_address,
+            enabled,
+            profit_threshold,
+            max_loan,
+            clear_max_loan,
+            strategy,
+            pair_address,
+            clear_pair_address,
+            cooldown,
+            clear_cooldown,
+        } => try_update_cluster(
+            deps,
+            info,
+            cluster_address,
+            enabled,
+            profit_threshold,
+            max_loan,
+            clear_max_loan,
+            strategy,
+            pair_address,
+            clear_pair_address,
+            cooldown,
+            clear_cooldown,
+        ),
+        ExecuteMsg::RemoveCluster { cluster_address } => {
+            try_remove_cluster(deps, info, cluster_address)
+        }
+        ExecuteMsg::UpdateProfitSplit {
+            caller_fee,
+            protocol_fee,
+            treasury_address,
+            community_fund_fee,
+            community_fund_address,
+            whale_pair_address,
+        } => try_update_profit_split(
+            deps,
+            info,
+            caller_fee,
+            protocol_fee,
+            treasury_address,
+            community_fund_fee,
+            community_fund_address,
+            whale_pair_address,
+        ),
+        ExecuteMsg::UpdateKeeperReward { keeper_reward } => {
+            try_update_keeper_reward(deps, info, keeper_reward)
+        }
+        ExecuteMsg::Pause {} => try_pause(deps, info),
+        ExecuteMsg::PauseCluster { cluster } => try_pause_cluster(deps, info, cluster),
+        ExecuteMsg::Unpause {} => try_unpause(deps, info),
+        ExecuteMsg::UnpauseCluster { cluster } => try_unpause_cluster(deps, info, cluster),
+        ExecuteMsg::UpdateGuardian { guardian } => try_update_guardian(deps, info, guardian),
+        ExecuteMsg::WithdrawNative { denom, send_to } => {
+            try_withdraw_native(deps, env, info, denom, send_to)
+        }
+        ExecuteMsg::WithdrawToken {
+            token,
+            send_to,
+            amount,
+        } => try_withdraw_token(deps, env, info, token, send_to, amount),
+        ExecuteMsg::UpdateAccessMode { access_mode } => {
+            try_update_access_mode(deps, info, access_mode)
+        }
+        ExecuteMsg::AddWrapper { token, adapter } => try_add_wrapper(deps, info, token, adapter),
+        ExecuteMsg::RemoveWrapper { token } => try_remove_wrapper(deps, info, token),
+        ExecuteMsg::AddExecutor { address } => try_add_executor(deps, info, address),
+        ExecuteMsg::RemoveExecutor { address } => try_remove_executor(deps, info, address),
         ExecuteMsg::UpdateConfig {
             vault_address,
             incentive_address,
             astroport_factory_address,
+            astroport_router_address,
+            terraswap_factory_address,
+            nebula_factory_address,
             profit_threshold,
-            owner_address,
+            slippage_tolerance,
         } => try_update_config(
             deps,
             info,
             vault_address,
             incentive_address,
             astroport_factory_address,
+            astroport_router_address,
+            terraswap_factory_address,
+            nebula_factory_address,
             profit_threshold,
-            owner_address,
-        ),
-        ExecuteMsg::_SwapToUstAndTakeProfit {
-            user_address,
-            loan_amount,
-            target,
-            profit_threshold,
-        } => try_swap_to_ust_and_take_profit(
-            deps,
-            env,
-            info,
-            user_address,
-            loan_amount,
-            &target,
-            profit_threshold,
+            slippage_tolerance,
         ),
+        ExecuteMsg::ProposeNewOwner { owner, expires } => {
+            try_propose_new_owner(deps, env, info, owner, expires)
+        }
+        ExecuteMsg::DropOwnershipProposal {} => try_drop_ownership_proposal(deps, info),
+        ExecuteMsg::ClaimOwnership {} => try_claim_ownership(deps, env, info),
+    }
+}
+
+/// ## Description
+/// Migrates the contract to this version, refusing other contracts and downgrades.
+/// Arbitrages are only allowed on registered clusters, the clusters of `msg` are registered
+/// with the default settings and the others are rejected until the owner registers them.
+/// Returns a [`ContractError`] on failure.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **_env** is an object of type [`Env`].
+///
+/// - **msg** is an object of type [`MigrateMsg`] which holds the settings missing from the
+///   stored state.
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn migrate(mut deps: DepsMut, _env: Env, msg: MigrateMsg) -> Result<Response, ContractError> {
+    let stored = get_contract_version(deps.storage)?;
+    if stored.contract != CONTRACT_NAME {
+        return Err(ContractError::InvalidMigration {});
+    }
+    if parse_version(&stored.version)? > parse_version(CONTRACT_VERSION)? {
+        return Err(ContractError::MigrationDowngrade {});
+    }
+
+    let legacy = LEGACY_STATE.load(deps.storage)?;
+    let required =
+        |address: Option<Addr>, new_address: Option<String>, field: &str| match new_address {
+            Some(address) => deps.api.addr_validate(address.as_str()),
+            None => address
+                .ok_or_else(|| StdError::generic_err(format!("{} is required to migrate", field))),
+        };
+    let state = State {
+        vault_address: legacy.vault_address,
+        incentive_address: legacy.incentive_address,
+        astroport_factory_address: legacy.astroport_factory_address,
+        astroport_router_address: required(
+            legacy.astroport_router_address,
+            msg.astroport_router_address,
+            "astroport_router_address",
+        )?,
+        terraswap_factory_address: required(
+            legacy.terraswap_factory_address,
+            msg.terraswap_factory_address,
+            "terraswap_factory_address",
+        )?,
+        aust_token_address: legacy.aust_token_address,
+        anchor_market_contract: legacy.anchor_market_contract,
+        profit_threshold: legacy.profit_threshold,
+        slippage_tolerance: msg
+            .slippage_tolerance
+            .or(legacy.slippage_tolerance)
+            .ok_or_else(|| StdError::generic_err("slippage_tolerance is required to migrate"))?,
+        base_asset: match (msg.base_asset, legacy.base_asset) {
+            (Some(AssetInfo::Token { contract_addr }), _) => ArbBaseAsset {
+                asset_info: AssetInfo::Token {
+                    contract_addr: deps.api.addr_validate(contract_addr.as_str())?.to_string(),
+                },
+            },
+            (Some(native), _) => ArbBaseAsset { asset_info: native },
+            (None, Some(base_asset)) => base_asset,
+            // the base asset used to be UST only
+            (None, None) => ArbBaseAsset {
+                asset_info: AssetInfo::NativeToken {
+                    denom: "uusd".to_string(),
+                },
+            },
+        },
+        nebula_factory_address: legacy.nebula_factory_address,
+        access_mode: legacy.access_mode.unwrap_or(AccessMode::Permissionless),
+    };
+    assert_slippage_tolerance(state.slippage_tolerance)?;
+    STATE.save(deps.storage, &state)?;
+    // aUST used to be the only wrapped asset, always minted and redeemed through Anchor
+    if !WRAPPERS.has(deps.storage, &state.aust_token_address) {
+        WRAPPERS.save(
+            deps.storage,
+            &state.aust_token_address,
+            &WrapperAdapter::Anchor {
+                market: state.anchor_market_contract.clone(),
+                denom: "uusd".to_string(),
+            },
+        )?;
     }
+
+    for cluster_address in msg.clusters.unwrap_or_default() {
+        let cluster_address = deps.api.addr_validate(cluster_address.as_str())?;
+        if CLUSTERS.has(deps.storage, &cluster_address) {
+            continue;
+        }
+        CLUSTERS.save(
+            deps.storage,
+            &cluster_address,
+            &ClusterConfig {
+                enabled: true,
+                profit_threshold: state.profit_threshold,
+                max_loan: None,
+                strategy: ClusterStrategy::Incentives,
+                pair_address: None,
+                cooldown: None,
+            },
+        )?;
+    }
+    // the incentives contract only arbitrages against UST
+    for cluster in CLUSTERS.range(deps.storage, None, None, Order::Ascending) {
+        assert_cluster_strategy(&state, &cluster?.1.strategy)?;
+    }
+
+    if let Some(owner_address) = legacy.owner_address {
+        ADMIN.set(deps.branch(), Some(owner_address))?;
+    }
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "migrate")
+        .add_attribute("from_version", stored.version)
+        .add_attribute("to_version", CONTRACT_VERSION))
+}
+
+/// Parses the numeric part of a `major.minor.patch` version.
+fn parse_version(version: &str) -> StdResult<Vec<u64>> {
+    version
+        .split('-')
+        .next()
+        .unwrap_or_default()
+        .split('.')
+        .map(|part| {
+            part.parse::<u64>()
+                .map_err(|_| StdError::generic_err(format!("Invalid version: {}", version)))
+        })
+        .collect()
 }
 
 /// ## Description
@@ -198,64 +538,183 @@ pub fn execute(
 /// - **info** is an object of type [`MessageInfo`].
 ///
 /// - **vault_address** is an object of type [`Option<String>`] which is the address of
-///     the new White whale vault contract.
+///   the new White whale vault contract.
 ///
-/// - **incentive_addres** is an object of type [`Option<String>`] which is the address of
-///     the new incentive contract.
+/// - **incentive_address** is an object of type [`Option<String>`] which is the address of
+///   the new incentive contract.
 ///
 /// - **astroport_factory_address** is an object of type [`Option<String>`] which is the address of
-///     the new astroport factory contract.
+///   the new astroport factory contract.
+///
+/// - **astroport_router_address** is an object of type [`Option<String>`] which is the address of
+///   the new astroport router contract.
+///
+/// - **terraswap_factory_address** is an object of type [`Option<String>`] which is the address of
+///   the new terraswap factory contract.
+///
+/// - **nebula_factory_address** is an object of type [`Option<String>`] which is the address of
+///   the new Nebula cluster factory contract.
 ///
 /// - **profit_threshold** is an object of type [`Option<Uint128>`] which is the new threshold of
-///     the arbitrage profit.
+///   the arbitrage profit.
 ///
-/// - **owner_address** is an object of type [`Option<String>`] which is a new owner address to update.
+/// - **slippage_tolerance** is an object of type [`Option<Decimal>`] which is the new share of
+///   the simulated amounts that may be lost to slippage.
 ///
 /// ## Executor
 /// Only the owner can execute this.
+#[allow(clippy::too_many_arguments)]
 pub fn try_update_config(
     deps: DepsMut,
     info: MessageInfo,
     vault_address: Option<String>,
-    incentive_addres: Option<String>,
+    incentive_address: Option<String>,
     astroport_factory_address: Option<String>,
+    astroport_router_address: Option<String>,
+    terraswap_factory_address: Option<String>,
+    nebula_factory_address: Option<String>,
     profit_threshold: Option<Uint128>,
-    owner_address: Option<String>,
+    slippage_tolerance: Option<Decimal>,
 ) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
     let mut state = STATE.load(deps.storage)?;
 
-    if info.sender != state.owner_address {
-        return Err(ContractError::Unauthorized {});
-    }
-
     if let Some(vault_address) = vault_address {
         state.vault_address = deps.api.addr_validate(vault_address.as_ref())?;
     }
-    if let Some(incentive_addres) = incentive_addres {
-        state.incentive_addres = deps.api.addr_validate(incentive_addres.as_ref())?;
+    if let Some(incentive_address) = incentive_address {
+        state.incentive_address = deps.api.addr_validate(incentive_address.as_ref())?;
     }
     if let Some(astroport_factory_address) = astroport_factory_address {
         state.astroport_factory_address =
             deps.api.addr_validate(astroport_factory_address.as_ref())?;
     }
+    if let Some(astroport_router_address) = astroport_router_address {
+        state.astroport_router_address =
+            deps.api.addr_validate(astroport_router_address.as_ref())?;
+    }
+    if let Some(terraswap_factory_address) = terraswap_factory_address {
+        state.terraswap_factory_address =
+            deps.api.addr_validate(terraswap_factory_address.as_ref())?;
+    }
+    if let Some(nebula_factory_address) = nebula_factory_address {
+        state.nebula_factory_address =
+            Some(deps.api.addr_validate(nebula_factory_address.as_ref())?);
+    }
     if let Some(profit_threshold) = profit_threshold {
         state.profit_threshold = profit_threshold;
     }
-    if let Some(owner_address) = owner_address {
-        state.owner_address = deps.api.addr_validate(owner_address.as_str())?;
+    if let Some(slippage_tolerance) = slippage_tolerance {
+        assert_slippage_tolerance(slippage_tolerance)?;
+        state.slippage_tolerance = slippage_tolerance;
     }
 
     STATE.save(deps.storage, &state)?;
     Ok(Response::new())
 }
 
+/// ## Description
+/// Exposes all the reply steps of the arbitrage flow.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **env** is an object of type [`Env`].
+///
+/// - **msg** is an object of type [`Reply`].
+///
+/// ## Steps
+/// - **REPLY_CREATE_BASKET** Cluster assets are bought, increases allowances and calls ArbClusterCreate.
+///
+/// - **REPLY_ARB_CREATE** CT is minted and sold, finishes the leg.
+///
+/// - **REPLY_ARB_REDEEM** CT is bought and redeemed, swaps all asset to the base asset.
+///
+/// - **REPLY_SWAP_TO_BASE** Cluster assets are sold, finishes the leg.
+///
+/// - **REPLY_DIRECT_BUY_CT** CT is bought on Astroport, increases the allowance and burns it with the cluster.
+///
+/// - **REPLY_DIRECT_MINT** CT is minted by the cluster, sells it on Astroport.
+///
+/// - **REPLY_DIRECT_SELL_CT** Minted CT is sold, finishes the leg.
+///
+/// - **REPLY_BURN_WHALE** The community fund share is swapped to WHALE, burns it through the community fund.
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn reply(
+    deps: DepsMut,
+    env: Env,
+    msg: Reply,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    match msg.id {
+        REPLY_CREATE_BASKET => reply_create_basket(deps, env),
+        REPLY_ARB_CREATE => reply_arb_create(deps, env, msg),
+        REPLY_ARB_REDEEM => reply_arb_redeem(deps, env, msg),
+        REPLY_DIRECT_BUY_CT => reply_direct_buy_ct(deps, env),
+        REPLY_DIRECT_MINT => reply_direct_mint(deps, env),
+        REPLY_DIRECT_SELL_CT => reply_direct_sell_ct(deps, env),
+        REPLY_SWAP_TO_BASE => reply_swap_to_base(deps, env),
+        REPLY_BURN_WHALE => reply_burn_whale(deps, msg),
+        id => Err(StdError::generic_err(format!("Unknown reply id: {}", id)).into()),
+    }
+}
+
 #[cfg_attr(not(feature = "library"), entry_point)]
-pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
     match msg {
+        QueryMsg::Config {} => to_binary(&query_config(deps)?),
         QueryMsg::UstVaultAddress {} => to_binary(&query_vault_address(deps)?),
         QueryMsg::EstimateArbitrage { cluster_address } => {
-            to_binary(&query_estimate_arbitrage(deps, cluster_address)?)
+            to_binary(&query_estimate_arbitrage(deps, env, cluster_address)?)
         }
+        QueryMsg::SimulateArbitrage {
+            cluster_address,
+            loan_amount,
+        } => to_binary(&query_simulate_arbitrage(
+            deps,
+            env,
+            cluster_address,
+            loan_amount,
+        )?),
+        QueryMsg::ListOpportunities { start_after, limit } => {
+            to_binary(&query_list_opportunities(deps, env, start_after, limit)?)
+        }
+        QueryMsg::History { start_after, limit } => {
+            to_binary(&query_history(deps, None, None, start_after, limit)?)
+        }
+        QueryMsg::HistoryByCluster {
+            cluster_address,
+            start_after,
+            limit,
+        } => to_binary(&query_history(
+            deps,
+            Some(cluster_address),
+            None,
+            start_after,
+            limit,
+        )?),
+        QueryMsg::HistoryByUser {
+            user_address,
+            start_after,
+            limit,
+        } => to_binary(&query_history(
+            deps,
+            None,
+            Some(user_address),
+            start_after,
+            limit,
+        )?),
+        QueryMsg::Stats { cluster } => to_binary(&query_stats(deps, cluster)?),
+        QueryMsg::ProtocolRevenue {} => to_binary(&query_protocol_revenue(deps)?),
+        QueryMsg::Executors { start_after, limit } => {
+            to_binary(&query_executors(deps, start_after, limit)?)
+        }
+        QueryMsg::Clusters { start_after, limit } => {
+            to_binary(&query_clusters(deps, start_after, limit)?)
+        }
+        QueryMsg::Wrappers { start_after, limit } => {
+            to_binary(&query_wrappers(deps, start_after, limit)?)
+        }
+        QueryMsg::Balances {} => to_binary(&query_balances(deps, env)?),
     }
 }
 
@@ -265,3 +724,223 @@ fn query_vault_address(deps: Deps) -> StdResult<UstVaultAddressResponse> {
         vault_address: state.vault_address,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info};
+    use cosmwasm_std::Storage;
+
+    use crate::state::LegacyState;
+    use crate::testing::{mock_cluster, mock_dependencies as mock_terra_dependencies, mock_vault};
+
+    fn legacy_state() -> LegacyState {
+        LegacyState {
+            vault_address: Addr::unchecked("vault"),
+            incentive_address: Addr::unchecked("incentives"),
+            astroport_factory_address: Addr::unchecked("astroport_factory"),
+            astroport_router_address: None,
+            terraswap_factory_address: None,
+            aust_token_address: Addr::unchecked("aust"),
+            anchor_market_contract: Addr::unchecked("anchor_market"),
+            profit_threshold: Uint128::from(1_000u128),
+            slippage_tolerance: None,
+            base_asset: None,
+            nebula_factory_address: None,
+            access_mode: None,
+            owner_address: Some(Addr::unchecked("owner")),
+        }
+    }
+
+    fn migrate_msg() -> MigrateMsg {
+        MigrateMsg {
+            astroport_router_address: Some("astroport_router".to_string()),
+            terraswap_factory_address: Some("terraswap_factory".to_string()),
+            slippage_tolerance: Some(Decimal::percent(1)),
+            base_asset: None,
+            clusters: Some(vec!["cluster".to_string()]),
+        }
+    }
+
+    fn save_legacy(storage: &mut dyn Storage, contract: &str, version: &str) {
+        set_contract_version(storage, contract, version).unwrap();
+        LEGACY_STATE.save(storage, &legacy_state()).unwrap();
+    }
+
+    #[test]
+    fn migrates_legacy_state() {
+        let mut deps = mock_dependencies(&[]);
+        save_legacy(&mut deps.storage, CONTRACT_NAME, "0.1.0");
+
+        migrate(deps.as_mut(), mock_env(), migrate_msg()).unwrap();
+
+        let state = STATE.load(&deps.storage).unwrap();
+        assert_eq!(
+            state,
+            State {
+                vault_address: Addr::unchecked("vault"),
+                incentive_address: Addr::unchecked("incentives"),
+                astroport_factory_address: Addr::unchecked("astroport_factory"),
+                astroport_router_address: Addr::unchecked("astroport_router"),
+                terraswap_factory_address: Addr::unchecked("terraswap_factory"),
+                aust_token_address: Addr::unchecked("aust"),
+                anchor_market_contract: Addr::unchecked("anchor_market"),
+                profit_threshold: Uint128::from(1_000u128),
+                slippage_tolerance: Decimal::percent(1),
+                base_asset: ArbBaseAsset {
+                    asset_info: AssetInfo::NativeToken {
+                        denom: "uusd".to_string(),
+                    },
+                },
+                nebula_factory_address: None,
+                access_mode: AccessMode::Permissionless,
+            }
+        );
+        assert_eq!(
+            ADMIN.get(deps.as_ref()).unwrap(),
+            Some(Addr::unchecked("owner"))
+        );
+        assert_eq!(
+            get_contract_version(&deps.storage).unwrap().version,
+            CONTRACT_VERSION
+        );
+
+        let cluster = CLUSTERS
+            .load(&deps.storage, &Addr::unchecked("cluster"))
+            .unwrap();
+        assert!(cluster.enabled);
+        assert_eq!(cluster.profit_threshold, Uint128::from(1_000u128));
+        assert_eq!(
+            WRAPPERS
+                .load(&deps.storage, &Addr::unchecked("aust"))
+                .unwrap(),
+            WrapperAdapter::Anchor {
+                market: Addr::unchecked("anchor_market"),
+                denom: "uusd".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn migrated_contract_arbitrages_its_clusters() {
+        let mut deps = mock_terra_dependencies(&[]);
+        mock_vault(&mut deps.querier, Decimal::permille(3));
+        mock_cluster(
+            &mut deps.querier,
+            "cluster",
+            1_100_000_000_000,
+            1_000_000_000_000,
+            1_000_000_000_000,
+            1_000_000_000_000,
+        );
+        save_legacy(&mut deps.storage, CONTRACT_NAME, "0.1.0");
+        migrate(deps.as_mut(), mock_env(), migrate_msg()).unwrap();
+
+        let flash_loan = ExecuteMsg::FlashLoan {
+            cluster_address: "cluster".to_string(),
+            user_address: None,
+            slippage_tolerance: None,
+        };
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("keeper", &[]),
+            flash_loan.clone(),
+        )
+        .unwrap();
+
+        // the legacy owner administers the migrated contract
+        let disable = ExecuteMsg::UpdateCluster {
+            cluster_address: "cluster".to_string(),
+            enabled: Some(false),
+            profit_threshold: None,
+            max_loan: None,
+            clear_max_loan: None,
+            strategy: None,
+            pair_address: None,
+            clear_pair_address: None,
+            cooldown: None,
+            clear_cooldown: None,
+        };
+        assert!(matches!(
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info("keeper", &[]),
+                disable.clone()
+            ),
+            Err(ContractError::Admin(_))
+        ));
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), disable).unwrap();
+        assert!(matches!(
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info("keeper", &[]),
+                flash_loan
+            ),
+            Err(ContractError::ClusterDisabled {})
+        ));
+    }
+
+    #[test]
+    fn migration_requires_new_settings() {
+        let mut deps = mock_dependencies(&[]);
+        save_legacy(&mut deps.storage, CONTRACT_NAME, "0.1.0");
+
+        let msg = MigrateMsg {
+            astroport_router_address: None,
+            ..migrate_msg()
+        };
+        assert!(migrate(deps.as_mut(), mock_env(), msg).is_err());
+    }
+
+    #[test]
+    fn migration_keeps_incentives_clusters_on_ust() {
+        let mut deps = mock_dependencies(&[]);
+        save_legacy(&mut deps.storage, CONTRACT_NAME, "0.1.0");
+
+        let msg = MigrateMsg {
+            base_asset: Some(AssetInfo::NativeToken {
+                denom: "uluna".to_string(),
+            }),
+            ..migrate_msg()
+        };
+        assert!(matches!(
+            migrate(deps.as_mut(), mock_env(), msg),
+            Err(ContractError::IncentivesRequireUst {})
+        ));
+    }
+
+    #[test]
+    fn migration_rejects_downgrades() {
+        let mut deps = mock_dependencies(&[]);
+        save_legacy(&mut deps.storage, CONTRACT_NAME, "10.0.0");
+
+        assert!(matches!(
+            migrate(deps.as_mut(), mock_env(), migrate_msg()),
+            Err(ContractError::MigrationDowngrade {})
+        ));
+    }
+
+    #[test]
+    fn migration_rejects_other_contracts() {
+        let mut deps = mock_dependencies(&[]);
+        save_legacy(&mut deps.storage, "crates.io:other", "0.1.0");
+
+        assert!(matches!(
+            migrate(deps.as_mut(), mock_env(), migrate_msg()),
+            Err(ContractError::InvalidMigration {})
+        ));
+    }
+
+    #[test]
+    fn versions_compare_numerically() {
+        assert!(parse_version("0.10.0").unwrap() > parse_version("0.9.3").unwrap());
+        assert_eq!(
+            parse_version("1.2.3-beta").unwrap(),
+            parse_version("1.2.3").unwrap()
+        );
+        assert!(parse_version("1.x").is_err());
+    }
+}
@@ -1,4 +1,5 @@
 use cosmwasm_std::StdError;
+use cw_controllers::AdminError;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -6,10 +7,85 @@ pub enum ContractError {
     #[error("{0}")]
     Std(#[from] StdError),
 
+    #[error("{0}")]
+    Admin(#[from] AdminError),
+
     #[error("Unauthorized")]
     Unauthorized {},
     // Add any other custom errors you like here.
     // Look at https://docs.rs/thiserror/1.0.21/thiserror/ for details.
     #[error("InsufficientProfit")]
     InsufficientProfit {},
+
+    #[error("NoArbitrageOpportunity")]
+    NoArbitrageOpportunity {},
+
+    #[error("InvalidSlippageTolerance")]
+    InvalidSlippageTolerance {},
+
+    #[error("MaxSlippageExceeded")]
+    MaxSlippageExceeded {},
+
+    #[error("NothingMinted")]
+    NothingMinted {},
+
+    #[error("NothingRedeemed")]
+    NothingRedeemed {},
+
+    #[error("UnredeemedClusterToken")]
+    UnredeemedClusterToken {},
+
+    #[error("NothingToWithdraw")]
+    NothingToWithdraw {},
+
+    #[error("InvalidMigration")]
+    InvalidMigration {},
+
+    #[error("MigrationDowngrade")]
+    MigrationDowngrade {},
+
+    #[error("Paused")]
+    Paused {},
+
+    #[error("ClusterNotRegistered")]
+    ClusterNotRegistered {},
+
+    #[error("ClusterAlreadyRegistered")]
+    ClusterAlreadyRegistered {},
+
+    #[error("ClusterPaused")]
+    ClusterPaused {},
+
+    #[error("ClusterDisabled")]
+    ClusterDisabled {},
+
+    #[error("ClusterInactive")]
+    ClusterInactive {},
+
+    #[error("ClusterCooldown")]
+    ClusterCooldown {},
+
+    #[error("IncentivesRequireUst")]
+    IncentivesRequireUst {},
+
+    #[error("WrapperNotRegistered")]
+    WrapperNotRegistered {},
+
+    #[error("WrapperAlreadyRegistered")]
+    WrapperAlreadyRegistered {},
+
+    #[error("InvalidWrapper")]
+    InvalidWrapper {},
+
+    #[error("OwnershipProposalNotFound")]
+    OwnershipProposalNotFound {},
+
+    #[error("OwnershipProposalExpired")]
+    OwnershipProposalExpired {},
+
+    #[error("InvalidProfitSplit")]
+    InvalidProfitSplit {},
+
+    #[error("ProfitSplitNotSet")]
+    ProfitSplitNotSet {},
 }
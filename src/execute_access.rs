@@ -0,0 +1,668 @@
+use cosmwasm_std::{Addr, Deps, DepsMut, Empty, Env, MessageInfo, Response};
+use cw0::Expiration;
+use terra_cosmwasm::TerraMsgWrapper;
+
+use crate::error::ContractError;
+use crate::msg::AccessMode;
+use crate::state::{
+    OwnershipProposal, State, ADMIN, CLUSTERS, EXECUTORS, GUARDIAN, OWNERSHIP_PROPOSAL, PAUSED,
+    PAUSED_CLUSTERS, STATE,
+};
+
+/// ## Description
+/// Proposes a new owner, who has to claim the ownership before the proposal expires.
+/// Returns a [`ContractError`] on failure.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **env** is an object of type [`Env`].
+///
+/// - **info** is an object of type [`MessageInfo`].
+///
+/// - **owner** is an object of type [`String`] which is the proposed owner address.
+///
+/// - **expires** is an object of type [`Expiration`] which is when the proposal can no longer be claimed.
+///
+/// ## Executor
+/// Only the owner can execute this.
+pub fn try_propose_new_owner(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    owner: String,
+    expires: Expiration,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+    if expires.is_expired(&env.block) {
+        return Err(ContractError::OwnershipProposalExpired {});
+    }
+
+    let owner = deps.api.addr_validate(owner.as_str())?;
+    OWNERSHIP_PROPOSAL.save(
+        deps.storage,
+        &OwnershipProposal {
+            owner: owner.clone(),
+            expires,
+        },
+    )?;
+    Ok(Response::new()
+        .add_attribute("action", "propose_new_owner")
+        .add_attribute("owner", owner.to_string()))
+}
+
+/// ## Description
+/// Removes the pending ownership proposal. Returns a [`ContractError`] on failure.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **info** is an object of type [`MessageInfo`].
+///
+/// ## Executor
+/// Only the owner can execute this.
+pub fn try_drop_ownership_proposal(
+    deps: DepsMut,
+    info: MessageInfo,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+    if OWNERSHIP_PROPOSAL.may_load(deps.storage)?.is_none() {
+        return Err(ContractError::OwnershipProposalNotFound {});
+    }
+
+    OWNERSHIP_PROPOSAL.remove(deps.storage);
+    Ok(Response::new().add_attribute("action", "drop_ownership_proposal"))
+}
+
+/// ## Description
+/// Makes the proposed owner the new owner. Returns a [`ContractError`] on failure.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **env** is an object of type [`Env`].
+///
+/// - **info** is an object of type [`MessageInfo`].
+///
+/// ## Executor
+/// Only the proposed owner can execute this, before the proposal expires.
+pub fn try_claim_ownership(
+    mut deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    let proposal = OWNERSHIP_PROPOSAL
+        .may_load(deps.storage)?
+        .ok_or(ContractError::OwnershipProposalNotFound {})?;
+    if info.sender != proposal.owner {
+        return Err(ContractError::Unauthorized {});
+    }
+    if proposal.expires.is_expired(&env.block) {
+        return Err(ContractError::OwnershipProposalExpired {});
+    }
+
+    ADMIN.set(deps.branch(), Some(proposal.owner.clone()))?;
+    OWNERSHIP_PROPOSAL.remove(deps.storage);
+    Ok(Response::new()
+        .add_attribute("action", "claim_ownership")
+        .add_attribute("owner", proposal.owner.to_string()))
+}
+
+/// ## Description
+/// Stops every arbitrage until the owner unpauses. Returns a [`ContractError`] on failure.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **info** is an object of type [`MessageInfo`].
+///
+/// ## Executor
+/// Only the owner or the guardian can execute this.
+pub fn try_pause(
+    deps: DepsMut,
+    info: MessageInfo,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    assert_owner_or_guardian(deps.as_ref(), &info.sender)?;
+
+    PAUSED.save(deps.storage, &true)?;
+    Ok(Response::new()
+        .add_attribute("action", "pause")
+        .add_attribute("sender", info.sender.to_string()))
+}
+
+/// ## Description
+/// Resumes the arbitrages. Returns a [`ContractError`] on failure.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **info** is an object of type [`MessageInfo`].
+///
+/// ## Executor
+/// Only the owner can execute this.
+pub fn try_unpause(
+    deps: DepsMut,
+    info: MessageInfo,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    PAUSED.save(deps.storage, &false)?;
+    Ok(Response::new().add_attribute("action", "unpause"))
+}
+
+/// ## Description
+/// Pauses a registered cluster, apart from its registry settings. The owner resumes it with
+/// `UnpauseCluster`. Returns a [`ContractError`] on failure.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **info** is an object of type [`MessageInfo`].
+///
+/// - **cluster** is an object of type [`String`] which is the cluster to pause.
+///
+/// ## Executor
+/// Only the owner or the guardian can execute this.
+pub fn try_pause_cluster(
+    deps: DepsMut,
+    info: MessageInfo,
+    cluster: String,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    assert_owner_or_guardian(deps.as_ref(), &info.sender)?;
+
+    let cluster_address = deps.api.addr_validate(cluster.as_str())?;
+    if !CLUSTERS.has(deps.storage, &cluster_address) {
+        return Err(ContractError::ClusterNotRegistered {});
+    }
+    PAUSED_CLUSTERS.save(deps.storage, &cluster_address, &Empty {})?;
+
+    Ok(Response::new()
+        .add_attribute("action", "pause_cluster")
+        .add_attribute("cluster", cluster_address.to_string())
+        .add_attribute("sender", info.sender.to_string()))
+}
+
+/// ## Description
+/// Resumes the arbitrages of a paused cluster, leaving a cluster disabled in the registry
+/// disabled. Returns a [`ContractError`] on failure.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **info** is an object of type [`MessageInfo`].
+///
+/// - **cluster** is an object of type [`String`] which is the cluster to unpause.
+///
+/// ## Executor
+/// Only the owner can execute this.
+pub fn try_unpause_cluster(
+    deps: DepsMut,
+    info: MessageInfo,
+    cluster: String,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    let cluster_address = deps.api.addr_validate(cluster.as_str())?;
+    PAUSED_CLUSTERS.remove(deps.storage, &cluster_address);
+
+    Ok(Response::new()
+        .add_attribute("action", "unpause_cluster")
+        .add_attribute("cluster", cluster_address.to_string()))
+}
+
+/// ## Description
+/// Sets the address allowed to pause the contract. Returns a [`ContractError`] on failure.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **info** is an object of type [`MessageInfo`].
+///
+/// - **guardian** is an object of type [`Option<String>`] which removes the guardian if none.
+///
+/// ## Executor
+/// Only the owner can execute this.
+pub fn try_update_guardian(
+    deps: DepsMut,
+    info: MessageInfo,
+    guardian: Option<String>,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    match guardian {
+        Some(guardian) => {
+            GUARDIAN.save(deps.storage, &deps.api.addr_validate(guardian.as_str())?)?
+        }
+        None => GUARDIAN.remove(deps.storage),
+    }
+    Ok(Response::new().add_attribute("action", "update_guardian"))
+}
+
+/// ## Description
+/// Sets who can trigger arbitrages. Returns a [`ContractError`] on failure.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **info** is an object of type [`MessageInfo`].
+///
+/// - **access_mode** is an object of type [`AccessMode`].
+///
+/// ## Executor
+/// Only the owner can execute this.
+pub fn try_update_access_mode(
+    deps: DepsMut,
+    info: MessageInfo,
+    access_mode: AccessMode,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+    let mut state = STATE.load(deps.storage)?;
+
+    state.access_mode = access_mode;
+    STATE.save(deps.storage, &state)?;
+    Ok(Response::new().add_attribute("action", "update_access_mode"))
+}
+
+/// ## Description
+/// Allows an address to trigger arbitrages in allowlist mode. Returns a [`ContractError`] on failure.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **info** is an object of type [`MessageInfo`].
+///
+/// - **address** is an object of type [`String`] which is the executor to add.
+///
+/// ## Executor
+/// Only the owner can execute this.
+pub fn try_add_executor(
+    deps: DepsMut,
+    info: MessageInfo,
+    address: String,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    let address = deps.api.addr_validate(address.as_str())?;
+    EXECUTORS.save(deps.storage, &address, &Empty {})?;
+    Ok(Response::new()
+        .add_attribute("action", "add_executor")
+        .add_attribute("executor", address.to_string()))
+}
+
+/// ## Description
+/// Removes an address from the executors. Returns a [`ContractError`] on failure.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **info** is an object of type [`MessageInfo`].
+///
+/// - **address** is an object of type [`String`] which is the executor to remove.
+///
+/// ## Executor
+/// Only the owner can execute this.
+pub fn try_remove_executor(
+    deps: DepsMut,
+    info: MessageInfo,
+    address: String,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    let address = deps.api.addr_validate(address.as_str())?;
+    EXECUTORS.remove(deps.storage, &address);
+    Ok(Response::new()
+        .add_attribute("action", "remove_executor")
+        .add_attribute("executor", address.to_string()))
+}
+
+/// ## Description
+/// Checks that an address can trigger arbitrages under the access mode.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **state** is a reference to an object of type [`State`].
+///
+/// - **sender** is a reference to an object of type [`Addr`].
+pub fn assert_executor(deps: Deps, state: &State, sender: &Addr) -> Result<(), ContractError> {
+    match state.access_mode {
+        AccessMode::Permissionless => Ok(()),
+        AccessMode::Allowlist => {
+            if EXECUTORS.has(deps.storage, sender) {
+                Ok(())
+            } else {
+                Err(ContractError::Unauthorized {})
+            }
+        }
+    }
+}
+
+/// ## Description
+/// Checks that neither the contract nor the cluster is paused and that the cluster can still be
+/// arbitraged.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **cluster_address** is a reference to an object of type [`Addr`].
+pub fn assert_not_paused(deps: Deps, cluster_address: &Addr) -> Result<(), ContractError> {
+    if PAUSED.may_load(deps.storage)?.unwrap_or(false) {
+        return Err(ContractError::Paused {});
+    }
+    let cluster = CLUSTERS
+        .may_load(deps.storage, cluster_address)?
+        .ok_or(ContractError::ClusterNotRegistered {})?;
+    if PAUSED_CLUSTERS.has(deps.storage, cluster_address) {
+        return Err(ContractError::ClusterPaused {});
+    }
+    if !cluster.enabled {
+        return Err(ContractError::ClusterDisabled {});
+    }
+    Ok(())
+}
+
+/// Checks that the sender is the owner or the guardian.
+fn assert_owner_or_guardian(deps: Deps, sender: &Addr) -> Result<(), ContractError> {
+    if ADMIN.is_admin(deps, sender)? || GUARDIAN.may_load(deps.storage)?.as_ref() == Some(sender) {
+        Ok(())
+    } else {
+        Err(ContractError::Unauthorized {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::execute;
+    use crate::msg::{ClusterStrategy, ExecuteMsg};
+    use crate::testing::{
+        flash_loan_callback, mock_cluster, mock_dependencies, mock_instantiate, mock_vault,
+        WasmMockQuerier, OWNER, VAULT,
+    };
+    use cosmwasm_std::testing::{mock_env, mock_info, MockApi, MockStorage};
+    use cosmwasm_std::{Decimal, OwnedDeps};
+
+    /// A profitable registered cluster.
+    fn setup() -> OwnedDeps<MockStorage, MockApi, WasmMockQuerier> {
+        let mut deps = mock_dependencies(&[]);
+        mock_vault(&mut deps.querier, Decimal::permille(3));
+        mock_cluster(
+            &mut deps.querier,
+            "cluster",
+            1_100_000_000_000,
+            1_000_000_000_000,
+            1_000_000_000_000,
+            1_000_000_000_000,
+        );
+        mock_instantiate(deps.as_mut());
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            ExecuteMsg::AddCluster {
+                cluster_address: "cluster".to_string(),
+                profit_threshold: None,
+                max_loan: None,
+                strategy: ClusterStrategy::Incentives,
+                pair_address: None,
+                cooldown: None,
+            },
+        )
+        .unwrap();
+        deps
+    }
+
+    fn run(
+        deps: DepsMut,
+        sender: &str,
+        msg: ExecuteMsg,
+    ) -> Result<Response<TerraMsgWrapper>, ContractError> {
+        execute(deps, mock_env(), mock_info(sender, &[]), msg)
+    }
+
+    fn flash_loan(deps: DepsMut, sender: &str) -> Result<Response<TerraMsgWrapper>, ContractError> {
+        run(
+            deps,
+            sender,
+            ExecuteMsg::FlashLoan {
+                cluster_address: "cluster".to_string(),
+                user_address: None,
+                slippage_tolerance: None,
+            },
+        )
+    }
+
+    #[test]
+    fn allowlist_restricts_flash_loans_to_executors() {
+        let mut deps = setup();
+        let allowlist = ExecuteMsg::UpdateAccessMode {
+            access_mode: AccessMode::Allowlist,
+        };
+        let add_keeper = ExecuteMsg::AddExecutor {
+            address: "keeper".to_string(),
+        };
+
+        assert!(matches!(
+            run(deps.as_mut(), "keeper", allowlist.clone()),
+            Err(ContractError::Admin(_))
+        ));
+        run(deps.as_mut(), OWNER, allowlist).unwrap();
+        assert_eq!(
+            STATE.load(&deps.storage).unwrap().access_mode,
+            AccessMode::Allowlist
+        );
+        assert!(matches!(
+            flash_loan(deps.as_mut(), "keeper"),
+            Err(ContractError::Unauthorized {})
+        ));
+
+        assert!(matches!(
+            run(deps.as_mut(), "keeper", add_keeper.clone()),
+            Err(ContractError::Admin(_))
+        ));
+        run(deps.as_mut(), OWNER, add_keeper).unwrap();
+        flash_loan(deps.as_mut(), "keeper").unwrap();
+        assert!(matches!(
+            flash_loan(deps.as_mut(), "other"),
+            Err(ContractError::Unauthorized {})
+        ));
+
+        let remove_keeper = ExecuteMsg::RemoveExecutor {
+            address: "keeper".to_string(),
+        };
+        assert!(matches!(
+            run(deps.as_mut(), "keeper", remove_keeper.clone()),
+            Err(ContractError::Admin(_))
+        ));
+        run(deps.as_mut(), OWNER, remove_keeper).unwrap();
+        assert!(!EXECUTORS.has(&deps.storage, &Addr::unchecked("keeper")));
+        assert!(matches!(
+            flash_loan(deps.as_mut(), "keeper"),
+            Err(ContractError::Unauthorized {})
+        ));
+
+        run(
+            deps.as_mut(),
+            OWNER,
+            ExecuteMsg::UpdateAccessMode {
+                access_mode: AccessMode::Permissionless,
+            },
+        )
+        .unwrap();
+        flash_loan(deps.as_mut(), "other").unwrap();
+    }
+    #[test]
+    fn ownership_is_transferred_in_two_steps() {
+        let mut deps = setup();
+        let height = mock_env().block.height;
+        let propose = |expires| ExecuteMsg::ProposeNewOwner {
+            owner: "new_owner".to_string(),
+            expires,
+        };
+
+        assert!(matches!(
+            run(deps.as_mut(), "new_owner", propose(Expiration::Never {})),
+            Err(ContractError::Admin(_))
+        ));
+        assert!(matches!(
+            run(deps.as_mut(), OWNER, propose(Expiration::AtHeight(height))),
+            Err(ContractError::OwnershipProposalExpired {})
+        ));
+        assert!(matches!(
+            run(deps.as_mut(), "new_owner", ExecuteMsg::ClaimOwnership {}),
+            Err(ContractError::OwnershipProposalNotFound {})
+        ));
+
+        // a dropped proposal can't be claimed
+        run(deps.as_mut(), OWNER, propose(Expiration::Never {})).unwrap();
+        assert!(matches!(
+            run(
+                deps.as_mut(),
+                "new_owner",
+                ExecuteMsg::DropOwnershipProposal {}
+            ),
+            Err(ContractError::Admin(_))
+        ));
+        run(deps.as_mut(), OWNER, ExecuteMsg::DropOwnershipProposal {}).unwrap();
+        assert!(matches!(
+            run(deps.as_mut(), OWNER, ExecuteMsg::DropOwnershipProposal {}),
+            Err(ContractError::OwnershipProposalNotFound {})
+        ));
+        assert!(matches!(
+            run(deps.as_mut(), "new_owner", ExecuteMsg::ClaimOwnership {}),
+            Err(ContractError::OwnershipProposalNotFound {})
+        ));
+
+        // only the proposed owner claims, before the proposal expires
+        run(
+            deps.as_mut(),
+            OWNER,
+            propose(Expiration::AtHeight(height + 1)),
+        )
+        .unwrap();
+        assert!(matches!(
+            run(deps.as_mut(), "other", ExecuteMsg::ClaimOwnership {}),
+            Err(ContractError::Unauthorized {})
+        ));
+        let mut env = mock_env();
+        env.block.height += 1;
+        assert!(matches!(
+            execute(
+                deps.as_mut(),
+                env,
+                mock_info("new_owner", &[]),
+                ExecuteMsg::ClaimOwnership {}
+            ),
+            Err(ContractError::OwnershipProposalExpired {})
+        ));
+
+        run(deps.as_mut(), "new_owner", ExecuteMsg::ClaimOwnership {}).unwrap();
+        assert_eq!(
+            ADMIN.get(deps.as_ref()).unwrap(),
+            Some(Addr::unchecked("new_owner"))
+        );
+        assert!(OWNERSHIP_PROPOSAL
+            .may_load(&deps.storage)
+            .unwrap()
+            .is_none());
+        let add_executor = ExecuteMsg::AddExecutor {
+            address: "keeper".to_string(),
+        };
+        assert!(matches!(
+            run(deps.as_mut(), OWNER, add_executor.clone()),
+            Err(ContractError::Admin(_))
+        ));
+        run(deps.as_mut(), "new_owner", add_executor).unwrap();
+    }
+    #[test]
+    fn pause_stops_every_arbitrage() {
+        let mut deps = setup();
+        let guardian = ExecuteMsg::UpdateGuardian {
+            guardian: Some("guardian".to_string()),
+        };
+        assert!(matches!(
+            run(deps.as_mut(), "guardian", guardian.clone()),
+            Err(ContractError::Admin(_))
+        ));
+        run(deps.as_mut(), OWNER, guardian).unwrap();
+
+        // an arbitrage started before the pause can't go on
+        let callback = flash_loan_callback(&flash_loan(deps.as_mut(), "keeper").unwrap());
+        assert!(matches!(
+            run(deps.as_mut(), "keeper", ExecuteMsg::Pause {}),
+            Err(ContractError::Unauthorized {})
+        ));
+        run(deps.as_mut(), "guardian", ExecuteMsg::Pause {}).unwrap();
+        assert!(matches!(
+            flash_loan(deps.as_mut(), "keeper"),
+            Err(ContractError::Paused {})
+        ));
+        assert!(matches!(
+            run(deps.as_mut(), VAULT, callback),
+            Err(ContractError::Paused {})
+        ));
+
+        // only the owner unpauses
+        assert!(matches!(
+            run(deps.as_mut(), "guardian", ExecuteMsg::Unpause {}),
+            Err(ContractError::Admin(_))
+        ));
+        run(deps.as_mut(), OWNER, ExecuteMsg::Unpause {}).unwrap();
+        flash_loan(deps.as_mut(), "keeper").unwrap();
+
+        // a removed guardian can no longer pause
+        run(
+            deps.as_mut(),
+            OWNER,
+            ExecuteMsg::UpdateGuardian { guardian: None },
+        )
+        .unwrap();
+        assert!(matches!(
+            run(deps.as_mut(), "guardian", ExecuteMsg::Pause {}),
+            Err(ContractError::Unauthorized {})
+        ));
+        run(deps.as_mut(), OWNER, ExecuteMsg::Pause {}).unwrap();
+        assert!(PAUSED.load(&deps.storage).unwrap());
+    }
+
+    #[test]
+    fn paused_cluster_is_not_arbitraged() {
+        let mut deps = setup();
+        run(
+            deps.as_mut(),
+            OWNER,
+            ExecuteMsg::UpdateGuardian {
+                guardian: Some("guardian".to_string()),
+            },
+        )
+        .unwrap();
+        let pause = |cluster: &str| ExecuteMsg::PauseCluster {
+            cluster: cluster.to_string(),
+        };
+        let unpause = ExecuteMsg::UnpauseCluster {
+            cluster: "cluster".to_string(),
+        };
+
+        assert!(matches!(
+            run(deps.as_mut(), "guardian", pause("other_cluster")),
+            Err(ContractError::ClusterNotRegistered {})
+        ));
+        assert!(matches!(
+            run(deps.as_mut(), "keeper", pause("cluster")),
+            Err(ContractError::Unauthorized {})
+        ));
+        run(deps.as_mut(), "guardian", pause("cluster")).unwrap();
+        assert!(matches!(
+            flash_loan(deps.as_mut(), "keeper"),
+            Err(ContractError::ClusterPaused {})
+        ));
+
+        assert!(matches!(
+            run(deps.as_mut(), "guardian", unpause.clone()),
+            Err(ContractError::Admin(_))
+        ));
+        run(deps.as_mut(), OWNER, unpause).unwrap();
+        assert!(!PAUSED_CLUSTERS.has(&deps.storage, &Addr::unchecked("cluster")));
+        flash_loan(deps.as_mut(), "keeper").unwrap();
+    }
+}
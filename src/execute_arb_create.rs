@@ -1,15 +1,26 @@
 use cosmwasm_std::{
-    coin, to_binary, Addr, CosmosMsg, Decimal, DepsMut, Env, MessageInfo, Response, Uint128,
-    WasmMsg,
+    attr, coin, to_binary, Addr, CosmosMsg, Decimal, DepsMut, Env, MessageInfo, Reply, Response,
+    StdError, StdResult, SubMsg, Uint128, WasmMsg,
 };
 use cw20::Cw20ExecuteMsg;
 use terra_cosmwasm::TerraMsgWrapper;
 
+use crate::contract::{
+    REPLY_ARB_CREATE, REPLY_CREATE_BASKET, REPLY_DIRECT_MINT, REPLY_DIRECT_SELL_CT,
+};
 use crate::error::ContractError;
-use crate::execute_flash_loan::repay_and_take_profit;
-use crate::msg::{ExecuteMsg, IncentivesMsg};
-use crate::state::STATE;
-use crate::utils::{create_astroport_swap_msg, create_aust_swap_msg, create_terraswap_swap_msg};
+use crate::execute_access::assert_not_paused;
+use crate::execute_cluster::cluster_strategy;
+use crate::execute_flash_loan::{finish_leg, query_leg_delta, start_arbitrage};
+use crate::msg::{
+    ArbitrageDirection, ArbitrageLeg, ClusterExecuteMsg, ClusterStrategy, IncentivesMsg,
+    SlippageLimits,
+};
+use crate::state::{ArbitrageFlow, LegResult, State, ARBITRAGE_FLOW, STATE};
+use crate::utils::{
+    belief_price, create_asset_swap_msgs, create_astroport_swap_msg, find_contract_event_amount,
+    query_best_venue, query_cluster_pair, reply_on_last,
+};
 
 use astroport::asset::{Asset as AstroportAsset, AssetInfo as AstroportAssetInfo};
 
@@ -29,20 +40,87 @@ pub fn try_callback_create(
     info: MessageInfo,
     cluster_address: Addr,
     user_address: Addr,
+    keeper_address: Addr,
     loan_amount: Uint128,
     target: &[AstroportAsset],
     prices: &[String],
     profit_threshold: Uint128,
+    limits: SlippageLimits,
+    market_price: Decimal,
+    intrinsic_price: Decimal,
 ) -> Result<Response<TerraMsgWrapper>, ContractError> {
     let state = STATE.load(deps.storage)?;
 
     if info.sender != state.vault_address {
         return Err(ContractError::Unauthorized {});
     }
+    assert_not_paused(deps.as_ref(), &cluster_address)?;
+
+    start_arbitrage(
+        deps,
+        env,
+        &state,
+        ArbitrageFlow {
+            user_address,
+            keeper_address,
+            loan_amount,
+            profit_threshold,
+            legs: vec![ArbitrageLeg {
+                cluster_address,
+                direction: ArbitrageDirection::Create,
+                loan_amount,
+                target: target.to_vec(),
+                prices: prices.to_vec(),
+                limits,
+                market_price,
+                intrinsic_price,
+            }],
+            results: vec![LegResult::default()],
+            current_leg: 0,
+            initial_base_balance: Uint128::zero(),
+            base_snapshot: Uint128::zero(),
+            asset_snapshots: vec![],
+        },
+    )
+}
 
-    let value_weights = target
+/// ## Description
+/// Swaps the loaned base asset of the current leg to the cluster assets based on the target weight ratio.
+/// Replies with [`REPLY_CREATE_BASKET`] once all swaps are done.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **env** is an object of type [`Env`].
+///
+/// - **state** is a reference to an object of type [`State`].
+///
+/// - **flow** is an object of type [`ArbitrageFlow`] which is the arbitrage in progress.
+pub fn start_create_leg(
+    deps: DepsMut,
+    env: Env,
+    state: &State,
+    mut flow: ArbitrageFlow,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    // the direct strategy mints CT to this contract, only the minted CT is sold
+    if cluster_strategy(deps.as_ref(), &flow.leg().cluster_address)? == ClusterStrategy::Direct {
+        let (cluster_token, _) =
+            query_cluster_pair(deps.as_ref(), state, &flow.leg().cluster_address)?;
+        let cluster_token = AstroportAssetInfo::Token {
+            contract_addr: cluster_token,
+        };
+        let amount = cluster_token.query_pool(&deps.querier, env.contract.address.clone())?;
+        flow.asset_snapshots.push(AstroportAsset {
+            info: cluster_token,
+            amount,
+        });
+    }
+
+    let leg = flow.leg();
+    let value_weights = leg
+        .target
         .iter()
-        .zip(prices.iter())
+        .zip(leg.prices.iter())
         .map(|(asset, price)| {
             (
                 asset.info.clone(),
@@ -54,98 +132,114 @@ pub fn try_callback_create(
         .iter()
         .fold(Uint128::zero(), |total, (_, amount)| total + amount);
 
-    // pro-rata: calculate UST that need to swap to assets based on target weight ratio
+    // pro-rata: calculate base asset that need to swap to assets based on target weight ratio
+    let base_asset_info = state.base_asset_info();
     let mut messages = vec![];
-    for (asset_info, value_weight) in value_weights {
+    let mut attributes = vec![];
+    for (i, (asset_info, value_weight)) in value_weights.into_iter().enumerate() {
         if value_weight.is_zero() {
             continue;
         }
 
-        let asset_amount = loan_amount * value_weight / total_value_weight;
+        let asset_amount = leg.loan_amount * value_weight / total_value_weight;
 
-        messages.push(match asset_info.clone() {
-            AstroportAssetInfo::NativeToken { denom } => {
-                // skip if asset if `uusd`
-                if denom == "uusd" {
-                    continue;
-                }
-                create_terraswap_swap_msg(asset_amount.u128(), "uusd".to_string(), denom)?
-            }
-            AstroportAssetInfo::Token { contract_addr } => {
-                if contract_addr == state.aust_token_address.clone() {
-                    create_aust_swap_msg(
-                        state.anchor_market_contract.clone(),
-                        state.aust_token_address.clone(),
-                        asset_amount,
-                        false,
-                    )?
-                } else {
-                    create_astroport_swap_msg(
-                        &deps.querier,
-                        AstroportAsset {
-                            info: AstroportAssetInfo::NativeToken {
-                                denom: "uusd".to_string(),
-                            },
-                            amount: asset_amount,
-                        },
-                        asset_info.clone(),
-                        state.astroport_factory_address.clone(),
-                    )?
-                }
-            }
-        });
+        // skip if asset is the base asset
+        if asset_info == base_asset_info {
+            continue;
+        }
+        let offer_asset = AstroportAsset {
+            info: base_asset_info.clone(),
+            amount: asset_amount,
+        };
+        let (venue, _) = query_best_venue(
+            deps.as_ref(),
+            state,
+            &offer_asset,
+            &asset_info,
+            env.block.height,
+        )?;
+        attributes.push(attr("venue", format!("{}:{}", asset_info, venue)));
+        messages.extend(create_asset_swap_msgs(
+            deps.as_ref(),
+            state,
+            offer_asset,
+            asset_info,
+            venue,
+            leg.limits.belief_prices.get(i).cloned().flatten(),
+            Some(leg.limits.max_spread),
+        )?);
     }
 
-    messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
-        contract_addr: env.contract.address.to_string(),
-        funds: vec![],
-        msg: to_binary(&ExecuteMsg::_ArbCreate {
-            cluster_address,
-            user_address,
-            loan_amount,
-            target: target.to_vec(),
-            profit_threshold,
-        })?,
-    }));
+    if messages.is_empty() {
+        return arb_create(deps, env, state, flow);
+    }
 
-    Ok(Response::new().add_messages(messages))
+    ARBITRAGE_FLOW.save(deps.storage, &flow)?;
+    Ok(Response::new()
+        .add_submessages(reply_on_last(messages, REPLY_CREATE_BASKET))
+        .add_attributes(attributes))
 }
 
 /// ## Description
-///  Executes the create operation and uses CT to arbitrage on Astroport with all ralated assets in contract.
+/// Continues the create leg once the cluster assets are bought.
 ///
 /// ## Params
 /// - **deps** is an object of type [`DepsMut`].
 ///
 /// - **env** is an object of type [`Env`].
-#[allow(clippy::too_many_arguments)]
-pub fn try_arb_create(
+pub fn reply_create_basket(
     deps: DepsMut,
     env: Env,
-    info: MessageInfo,
-    cluster_address: Addr,
-    user_address: Addr,
-    loan_amount: Uint128,
-    target: &[AstroportAsset],
-    profit_threshold: Uint128,
 ) -> Result<Response<TerraMsgWrapper>, ContractError> {
     let state = STATE.load(deps.storage)?;
+    let flow = ARBITRAGE_FLOW.load(deps.storage)?;
+    arb_create(deps, env, &state, flow)
+}
 
-    if info.sender != env.contract.address {
-        return Err(ContractError::Unauthorized {});
-    }
-
-    let assets: Vec<AstroportAsset> = target
+/// ## Description
+/// Executes the create operation and uses CT to arbitrage on Astroport with all ralated assets in contract.
+/// Replies with [`REPLY_ARB_CREATE`] once the CT is sold, or with [`REPLY_DIRECT_MINT`] once the CT
+/// is minted for the direct strategy.
+fn arb_create(
+    deps: DepsMut,
+    env: Env,
+    state: &State,
+    mut flow: ArbitrageFlow,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    // only the assets bought by this leg go into the basket
+    let assets = flow
+        .leg()
+        .target
         .iter()
-        .map(|asset| AstroportAsset {
-            info: asset.info.clone(),
-            // get balance
-            amount: asset
-                .info
-                .query_pool(&deps.querier, env.contract.address.clone())
-                .unwrap(),
+        .map(|asset| {
+            Ok(AstroportAsset {
+                info: asset.info.clone(),
+                amount: query_leg_delta(deps.as_ref(), &env, &flow, &asset.info)?,
+            })
         })
-        .collect();
+        .collect::<StdResult<Vec<AstroportAsset>>>()?;
+    flow.result_mut().basket_amounts = assets.iter().map(|asset| asset.amount).collect();
+
+    // the venues without a spread check are bounded by the simulated basket
+    for (i, asset) in assets.iter().enumerate() {
+        let min_amount = flow
+            .leg()
+            .limits
+            .min_basket_amounts
+            .get(i)
+            .cloned()
+            .unwrap_or_default();
+        if asset.amount < min_amount {
+            return Err(ContractError::MaxSlippageExceeded {});
+        }
+    }
+
+    let strategy = cluster_strategy(deps.as_ref(), &flow.leg().cluster_address)?;
+    // the direct strategy mints from the cluster itself, which pulls the cw20 assets
+    let spender = match strategy {
+        ClusterStrategy::Incentives => state.incentive_address.clone(),
+        ClusterStrategy::Direct => flow.leg().cluster_address.clone(),
+    };
 
     let mut funds = vec![];
     let mut messages = vec![];
@@ -156,42 +250,247 @@ pub fn try_arb_create(
             }
             AstroportAssetInfo::Token { contract_addr } => {
                 // increate allowance
-                messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
+                messages.push(SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
                     contract_addr: contract_addr.to_string(),
                     msg: to_binary(&Cw20ExecuteMsg::IncreaseAllowance {
-                        spender: state.incentive_addres.to_string(),
+                        spender: spender.to_string(),
                         amount: asset.amount,
                         expires: None,
                     })
                     .unwrap(),
                     funds: vec![],
-                }));
+                })));
             }
         }
     }
 
     funds.sort_by(|c1, c2| c1.denom.cmp(&c2.denom));
 
-    // mint cluster token and sell it on Astroport.
-    messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
-        contract_addr: state.incentive_addres.to_string(),
-        msg: to_binary(&IncentivesMsg::ArbClusterCreate {
-            cluster_contract: cluster_address.to_string(),
-            assets,
-            min_ust: Some(Uint128::from(1u128)),
-        })?,
-        funds,
-    }));
-
-    // repay and take profit
-    messages.append(&mut repay_and_take_profit(
-        &deps.querier,
-        loan_amount,
-        env.contract.address,
-        state.vault_address,
-        user_address,
-        profit_threshold,
-    )?);
-
-    Ok(Response::new().add_messages(messages))
+    messages.push(match strategy {
+        // mint cluster token and sell it on Astroport.
+        ClusterStrategy::Incentives => SubMsg::reply_on_success(
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: state.incentive_address.to_string(),
+                msg: to_binary(&IncentivesMsg::ArbClusterCreate {
+                    cluster_contract: flow.leg().cluster_address.to_string(),
+                    assets,
+                    min_ust: Some(flow.leg().limits.min_base_returned),
+                })?,
+                funds,
+            }),
+            REPLY_ARB_CREATE,
+        ),
+        // mint cluster token, it is sold once minted
+        ClusterStrategy::Direct => SubMsg::reply_on_success(
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: flow.leg().cluster_address.to_string(),
+                msg: to_binary(&ClusterExecuteMsg::RebalanceCreate {
+                    asset_amounts: assets,
+                    min_tokens: Some(flow.leg().limits.min_cluster_token),
+                })?,
+                funds,
+            }),
+            REPLY_DIRECT_MINT,
+        ),
+    });
+
+    ARBITRAGE_FLOW.save(deps.storage, &flow)?;
+    Ok(Response::new().add_submessages(messages))
+}
+
+/// ## Description
+/// Records the CT minted by the cluster from the CT balance and sells it on Astroport for the
+/// direct strategy. Replies with [`REPLY_DIRECT_SELL_CT`] once the CT is sold.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **env** is an object of type [`Env`].
+pub fn reply_direct_mint(
+    deps: DepsMut,
+    env: Env,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    let state = STATE.load(deps.storage)?;
+    let mut flow = ARBITRAGE_FLOW.load(deps.storage)?;
+
+    let (cluster_token, pair_contract) =
+        query_cluster_pair(deps.as_ref(), &state, &flow.leg().cluster_address)?;
+    let cluster_token_amount = query_leg_delta(
+        deps.as_ref(),
+        &env,
+        &flow,
+        &AstroportAssetInfo::Token {
+            contract_addr: cluster_token.clone(),
+        },
+    )?;
+    if cluster_token_amount.is_zero() {
+        return Err(ContractError::NothingMinted {});
+    }
+    flow.result_mut().cluster_token_amount = cluster_token_amount;
+
+    let leg = flow.leg();
+    let message = create_astroport_swap_msg(
+        AstroportAsset {
+            info: AstroportAssetInfo::Token {
+                contract_addr: cluster_token,
+            },
+            amount: cluster_token_amount,
+        },
+        pair_contract,
+        belief_price(
+            cluster_token_amount,
+            leg.limits.min_base_returned,
+            leg.limits.max_spread,
+        ),
+        Some(leg.limits.max_spread),
+    )?;
+
+    ARBITRAGE_FLOW.save(deps.storage, &flow)?;
+    Ok(Response::new().add_submessage(SubMsg::reply_on_success(message, REPLY_DIRECT_SELL_CT)))
+}
+
+/// ## Description
+/// Finishes the create leg once the minted CT is sold for the direct strategy.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **env** is an object of type [`Env`].
+pub fn reply_direct_sell_ct(
+    deps: DepsMut,
+    env: Env,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    let flow = ARBITRAGE_FLOW.load(deps.storage)?;
+    finish_leg(deps, env, flow)
+}
+
+/// ## Description
+/// Records the minted CT and finishes the create leg.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **env** is an object of type [`Env`].
+///
+/// - **msg** is an object of type [`Reply`] which is the result of `ArbClusterCreate`.
+pub fn reply_arb_create(
+    deps: DepsMut,
+    env: Env,
+    msg: Reply,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    let mut flow = ARBITRAGE_FLOW.load(deps.storage)?;
+
+    // the incentives contract mints and sells the CT itself, the CT never reaches this contract
+    // so only the mint event of the cluster reports the amount
+    let result = msg.result.into_result().map_err(StdError::generic_err)?;
+    let cluster_token_amount = find_contract_event_amount(
+        &result.events,
+        &flow.leg().cluster_address,
+        "mint_to_sender",
+    )
+    .unwrap_or_default();
+    if cluster_token_amount.is_zero() {
+        return Err(ContractError::NothingMinted {});
+    }
+    flow.result_mut().cluster_token_amount = cluster_token_amount;
+
+    finish_leg(deps, env, flow)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::contract::{execute, reply, REPLY_ARB_CREATE};
+    use crate::error::ContractError;
+    use crate::msg::{ArbitrageDirection, ClusterStrategy, ExecuteMsg};
+    use crate::state::{history, ARBITRAGE_FLOW};
+    use crate::testing::{
+        flash_loan_callback, mock_cluster, mock_dependencies, mock_instantiate, mock_reply,
+        mock_vault, WasmMockQuerier, OWNER, VAULT,
+    };
+    use cosmwasm_std::testing::{mock_env, mock_info, MockApi, MockStorage, MOCK_CONTRACT_ADDR};
+    use cosmwasm_std::{coin, Decimal, OwnedDeps};
+    use cw_storage_plus::U64Key;
+
+    /// Starts the create arbitrage of a cluster trading above its NAV, up to the incentives reply.
+    fn setup() -> OwnedDeps<MockStorage, MockApi, WasmMockQuerier> {
+        let mut deps = mock_dependencies(&[]);
+        mock_vault(&mut deps.querier, Decimal::permille(3));
+        mock_cluster(
+            &mut deps.querier,
+            "cluster",
+            1_000_000_000_000,
+            1_000_000_000_000,
+            1_100_000_000_000,
+            1_000_000_000_000,
+        );
+        mock_instantiate(deps.as_mut());
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            ExecuteMsg::AddCluster {
+                cluster_address: "cluster".to_string(),
+                profit_threshold: None,
+                max_loan: None,
+                strategy: ClusterStrategy::Incentives,
+                pair_address: None,
+                cooldown: None,
+            },
+        )
+        .unwrap();
+
+        let response = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("keeper", &[]),
+            ExecuteMsg::FlashLoan {
+                cluster_address: "cluster".to_string(),
+                user_address: None,
+                slippage_tolerance: None,
+            },
+        )
+        .unwrap();
+        let callback = flash_loan_callback(&response);
+        let loan_amount = match &callback {
+            ExecuteMsg::_CallbackCreate { loan_amount, .. } => loan_amount.u128(),
+            _ => panic!("not a create callback"),
+        };
+        deps.querier
+            .with_balance(MOCK_CONTRACT_ADDR, &[coin(loan_amount, "uusd")]);
+        execute(deps.as_mut(), mock_env(), mock_info(VAULT, &[]), callback).unwrap();
+
+        // the incentives contract sold the minted CT with a profit
+        deps.querier
+            .with_balance(MOCK_CONTRACT_ADDR, &[coin(2 * loan_amount, "uusd")]);
+        deps
+    }
+
+    #[test]
+    fn minted_amount_is_reported_by_the_cluster() {
+        let mut deps = setup();
+
+        // only the cluster reports the minted CT
+        let other_contract =
+            mock_reply(REPLY_ARB_CREATE, "cluster_pair", &[("mint_to_sender", "5")]);
+        assert!(matches!(
+            reply(deps.as_mut(), mock_env(), other_contract),
+            Err(ContractError::NothingMinted {})
+        ));
+        let missing = mock_reply(REPLY_ARB_CREATE, "cluster", &[("action", "mint")]);
+        assert!(matches!(
+            reply(deps.as_mut(), mock_env(), missing),
+            Err(ContractError::NothingMinted {})
+        ));
+
+        let minted = mock_reply(REPLY_ARB_CREATE, "cluster", &[("mint_to_sender", "5")]);
+        let response = reply(deps.as_mut(), mock_env(), minted).unwrap();
+        assert!(response
+            .attributes
+            .iter()
+            .any(|attribute| attribute.key == "profit"));
+
+        let record = history().load(&deps.storage, U64Key::new(1)).unwrap();
+        assert_eq!(record.direction, ArbitrageDirection::Create);
+        assert!(ARBITRAGE_FLOW.may_load(&deps.storage).unwrap().is_none());
+    }
 }
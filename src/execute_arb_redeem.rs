@@ -1,15 +1,25 @@
-use astroport::querier::{query_balance, query_token_balance};
 use cosmwasm_std::{
-    coin, to_binary, Addr, CosmosMsg, DepsMut, Env, MessageInfo, Response, Uint128, WasmMsg,
+    attr, coin, to_binary, Addr, CosmosMsg, Decimal, DepsMut, Env, MessageInfo, Reply, Response,
+    StdError, SubMsg, Uint128, WasmMsg,
 };
 
+use crate::contract::{REPLY_ARB_REDEEM, REPLY_DIRECT_BUY_CT, REPLY_SWAP_TO_BASE};
 use crate::error::ContractError;
-use crate::execute_flash_loan::repay_and_take_profit;
-use crate::msg::{ExecuteMsg, IncentivesMsg};
-use crate::state::STATE;
-use crate::utils::{create_astroport_swap_msg, create_aust_swap_msg, create_terraswap_swap_msg};
+use crate::execute_access::assert_not_paused;
+use crate::execute_cluster::cluster_strategy;
+use crate::execute_flash_loan::{finish_leg, query_leg_delta, start_arbitrage};
+use crate::msg::{
+    ArbitrageDirection, ArbitrageLeg, ClusterExecuteMsg, ClusterStrategy, IncentivesMsg,
+    SlippageLimits,
+};
+use crate::state::{ArbitrageFlow, LegResult, State, ARBITRAGE_FLOW, STATE};
+use crate::utils::{
+    belief_price, create_asset_swap_msgs, create_astroport_swap_msg, find_contract_event_amount,
+    query_best_venue, query_cluster_pair, reply_on_last,
+};
 
 use astroport::asset::{Asset as AstroportAsset, AssetInfo as AstroportAssetInfo};
+use cw20::Cw20ExecuteMsg;
 use terra_cosmwasm::TerraMsgWrapper;
 
 /// ## Description
@@ -26,132 +36,505 @@ pub fn try_callback_redeem(
     info: MessageInfo,
     cluster_address: Addr,
     user_address: Addr,
+    keeper_address: Addr,
     loan_amount: Uint128,
     target: &[AstroportAsset],
     profit_threshold: Uint128,
+    limits: SlippageLimits,
+    market_price: Decimal,
+    intrinsic_price: Decimal,
 ) -> Result<Response<TerraMsgWrapper>, ContractError> {
     let state = STATE.load(deps.storage)?;
 
     if info.sender != state.vault_address {
         return Err(ContractError::Unauthorized {});
     }
+    assert_not_paused(deps.as_ref(), &cluster_address)?;
 
-    let asset = astroport::asset::Asset {
-        info: AstroportAssetInfo::NativeToken {
-            denom: "uusd".to_string(),
+    start_arbitrage(
+        deps,
+        env,
+        &state,
+        ArbitrageFlow {
+            user_address,
+            keeper_address,
+            loan_amount,
+            profit_threshold,
+            legs: vec![ArbitrageLeg {
+                cluster_address,
+                direction: ArbitrageDirection::Redeem,
+                loan_amount,
+                target: target.to_vec(),
+                prices: vec![],
+                limits,
+                market_price,
+                intrinsic_price,
+            }],
+            results: vec![LegResult::default()],
+            current_leg: 0,
+            initial_base_balance: Uint128::zero(),
+            base_snapshot: Uint128::zero(),
+            asset_snapshots: vec![],
         },
-        amount: loan_amount,
+    )
+}
+
+/// ## Description
+/// Buys CT from Astroport with the loaned base asset of the current leg and redeems it.
+/// Replies with [`REPLY_ARB_REDEEM`] once the cluster assets are received.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **state** is a reference to an object of type [`State`].
+///
+/// - **flow** is an object of type [`ArbitrageFlow`] which is the arbitrage in progress.
+pub fn start_redeem_leg(
+    deps: DepsMut,
+    env: Env,
+    state: &State,
+    flow: ArbitrageFlow,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    let leg = flow.leg();
+    let strategy = cluster_strategy(deps.as_ref(), &leg.cluster_address)?;
+    if strategy == ClusterStrategy::Direct {
+        return buy_cluster_token(deps, env, state, flow);
+    }
+
+    let asset = AstroportAsset {
+        info: state.base_asset_info(),
+        amount: leg.loan_amount,
+    };
+
+    let mut messages = vec![];
+    let funds = match &asset.info {
+        AstroportAssetInfo::NativeToken { denom } => vec![coin(leg.loan_amount.u128(), denom)],
+        AstroportAssetInfo::Token { contract_addr } => {
+            messages.push(SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: contract_addr.to_string(),
+                msg: to_binary(&Cw20ExecuteMsg::IncreaseAllowance {
+                    spender: state.incentive_address.to_string(),
+                    amount: leg.loan_amount,
+                    expires: None,
+                })?,
+                funds: vec![],
+            })));
+            vec![]
+        }
     };
 
-    let msgs = vec![
-        // Buy cluster from Astroport and redeem with pro-rata
+    // Buy cluster from Astroport and redeem with pro-rata
+    messages.push(SubMsg::reply_on_success(
         CosmosMsg::Wasm(WasmMsg::Execute {
-            contract_addr: state.incentive_addres.to_string(),
-            funds: vec![coin(loan_amount.u128(), "uusd".to_string())],
+            contract_addr: state.incentive_address.to_string(),
+            funds,
             msg: to_binary(&IncentivesMsg::ArbClusterRedeem {
-                cluster_contract: cluster_address.to_string(),
+                cluster_contract: leg.cluster_address.to_string(),
                 asset,
-                min_cluster: Some(Uint128::from(1u128)),
+                min_cluster: Some(leg.limits.min_cluster_token),
             })?,
         }),
-        // Swap all assets to UST
-        CosmosMsg::Wasm(WasmMsg::Execute {
-            contract_addr: env.contract.address.to_string(),
-            funds: vec![],
-            msg: to_binary(&ExecuteMsg::_SwapToUstAndTakeProfit {
-                user_address,
-                loan_amount,
-                target: target.to_vec(),
-                profit_threshold,
+        REPLY_ARB_REDEEM,
+    ));
+
+    ARBITRAGE_FLOW.save(deps.storage, &flow)?;
+    Ok(Response::new().add_submessages(messages))
+}
+
+/// ## Description
+/// Buys CT on Astroport with the loaned base asset of the current leg for the direct strategy.
+/// Replies with [`REPLY_DIRECT_BUY_CT`] once the CT is received.
+fn buy_cluster_token(
+    deps: DepsMut,
+    env: Env,
+    state: &State,
+    mut flow: ArbitrageFlow,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    let (cluster_token, pair_contract) =
+        query_cluster_pair(deps.as_ref(), state, &flow.leg().cluster_address)?;
+    // only the bought CT is redeemed
+    let cluster_token = AstroportAssetInfo::Token {
+        contract_addr: cluster_token,
+    };
+    let amount = cluster_token.query_pool(&deps.querier, env.contract.address.clone())?;
+    flow.asset_snapshots.push(AstroportAsset {
+        info: cluster_token,
+        amount,
+    });
+    let leg = flow.leg();
+
+    let message = create_astroport_swap_msg(
+        AstroportAsset {
+            info: state.base_asset_info(),
+            amount: leg.loan_amount,
+        },
+        pair_contract,
+        belief_price(
+            leg.loan_amount,
+            leg.limits.min_cluster_token,
+            leg.limits.max_spread,
+        ),
+        Some(leg.limits.max_spread),
+    )?;
+
+    ARBITRAGE_FLOW.save(deps.storage, &flow)?;
+    Ok(Response::new().add_submessage(SubMsg::reply_on_success(message, REPLY_DIRECT_BUY_CT)))
+}
+
+/// ## Description
+/// Records the bought CT and redeems it with the cluster's own burn message for the direct strategy.
+/// Replies with [`REPLY_ARB_REDEEM`] once the cluster assets are received.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **env** is an object of type [`Env`].
+pub fn reply_direct_buy_ct(
+    deps: DepsMut,
+    env: Env,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    let state = STATE.load(deps.storage)?;
+    let mut flow = ARBITRAGE_FLOW.load(deps.storage)?;
+
+    let (cluster_token, _) =
+        query_cluster_pair(deps.as_ref(), &state, &flow.leg().cluster_address)?;
+    let cluster_token_amount = query_leg_delta(
+        deps.as_ref(),
+        &env,
+        &flow,
+        &AstroportAssetInfo::Token {
+            contract_addr: cluster_token.clone(),
+        },
+    )?;
+    if cluster_token_amount < flow.leg().limits.min_cluster_token {
+        return Err(ContractError::MaxSlippageExceeded {});
+    }
+    flow.result_mut().cluster_token_amount = cluster_token_amount;
+    let leg = flow.leg();
+
+    // the cluster burns CT from this contract
+    let messages = vec![
+        SubMsg::new(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: cluster_token.to_string(),
+            msg: to_binary(&Cw20ExecuteMsg::IncreaseAllowance {
+                spender: leg.cluster_address.to_string(),
+                amount: cluster_token_amount,
+                expires: None,
             })?,
-        }),
+            funds: vec![],
+        })),
+        SubMsg::reply_on_success(
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: leg.cluster_address.to_string(),
+                msg: to_binary(&ClusterExecuteMsg::RebalanceRedeem {
+                    max_tokens: cluster_token_amount,
+                    asset_amounts: None,
+                })?,
+                funds: vec![],
+            }),
+            REPLY_ARB_REDEEM,
+        ),
     ];
 
-    Ok(Response::new().add_messages(msgs))
+    ARBITRAGE_FLOW.save(deps.storage, &flow)?;
+    Ok(Response::new().add_submessages(messages))
 }
 
 /// ## Description
-/// Sell related tokens with cluster to UST, after that repay and take profit.
+/// Records the redeemed CT and cluster assets, then sells related tokens with cluster to the base asset.
+/// The direct strategy must have burned all the bought CT, the incentives contract buys and burns
+/// the CT itself so only the redeem event of the cluster reports the amount.
+/// Replies with [`REPLY_SWAP_TO_BASE`] once all swaps are done.
 ///
 /// ## Params
 /// - **deps** is an object of type [`DepsMut`].
 ///
 /// - **env** is an object of type [`Env`].
 ///
-pub fn try_swap_to_ust_and_take_profit(
+/// - **msg** is an object of type [`Reply`] which is the result of `ArbClusterRedeem`.
+pub fn reply_arb_redeem(
     deps: DepsMut,
     env: Env,
-    info: MessageInfo,
-    user_address: Addr,
-    loan_amount: Uint128,
-    target: &[AstroportAsset],
-    profit_threshold: Uint128,
+    msg: Reply,
 ) -> Result<Response<TerraMsgWrapper>, ContractError> {
     let state = STATE.load(deps.storage)?;
+    let mut flow = ARBITRAGE_FLOW.load(deps.storage)?;
 
-    if info.sender != env.contract.address {
-        return Err(ContractError::Unauthorized {});
+    let result = msg.result.into_result().map_err(StdError::generic_err)?;
+    let token_cost =
+        find_contract_event_amount(&result.events, &flow.leg().cluster_address, "token_cost");
+    match cluster_strategy(deps.as_ref(), &flow.leg().cluster_address)? {
+        ClusterStrategy::Direct => {
+            if token_cost.is_some() && token_cost != Some(flow.result().cluster_token_amount) {
+                return Err(ContractError::UnredeemedClusterToken {});
+            }
+            let (cluster_token, _) =
+                query_cluster_pair(deps.as_ref(), &state, &flow.leg().cluster_address)?;
+            let unredeemed = query_leg_delta(
+                deps.as_ref(),
+                &env,
+                &flow,
+                &AstroportAssetInfo::Token {
+                    contract_addr: cluster_token,
+                },
+            )?;
+            if !unredeemed.is_zero() {
+                return Err(ContractError::UnredeemedClusterToken {});
+            }
+        }
+        ClusterStrategy::Incentives => {
+            let token_cost = token_cost.unwrap_or_default();
+            if token_cost.is_zero() {
+                return Err(ContractError::NothingRedeemed {});
+            }
+            flow.result_mut().cluster_token_amount = token_cost;
+        }
     }
 
+    let base_asset_info = state.base_asset_info();
+    let leg = flow.leg().clone();
+    let mut basket_amounts = vec![];
     let mut messages = vec![];
-    for asset in target {
-        messages.push(match asset.info.clone() {
-            AstroportAssetInfo::NativeToken { denom } => {
-                if denom == "uusd" {
-                    continue;
-                }
-                create_terraswap_swap_msg(
-                    query_balance(&deps.querier, env.contract.address.clone(), denom.clone())?
-                        .u128(),
-                    denom,
-                    "uusd".to_string(),
-                )?
-            }
-            AstroportAssetInfo::Token { contract_addr } => {
-                let amount = query_token_balance(
-                    &deps.querier,
-                    contract_addr.clone(),
-                    env.contract.address.clone(),
-                )?;
-                if contract_addr == state.aust_token_address {
-                    create_aust_swap_msg(
-                        state.anchor_market_contract.clone(),
-                        state.aust_token_address.clone(),
-                        amount,
-                        true,
-                    )?
-                } else {
-                    create_astroport_swap_msg(
-                        &deps.querier,
-                        AstroportAsset {
-                            info: AstroportAssetInfo::Token {
-                                contract_addr: contract_addr.clone(),
-                            },
-                            amount: query_token_balance(
-                                &deps.querier,
-                                contract_addr,
-                                env.contract.address.clone(),
-                            )?,
-                        },
-                        AstroportAssetInfo::NativeToken {
-                            denom: "uusd".to_string(),
-                        },
-                        state.astroport_factory_address.clone(),
-                    )?
-                }
-            }
-        });
+    let mut attributes = vec![];
+    for (i, asset) in leg.target.into_iter().enumerate() {
+        let amount = query_leg_delta(deps.as_ref(), &env, &flow, &asset.info)?;
+        basket_amounts.push(amount);
+
+        if asset.info == base_asset_info || amount.is_zero() {
+            continue;
+        }
+        let offer_asset = AstroportAsset {
+            info: asset.info.clone(),
+            amount,
+        };
+        let (venue, _) = query_best_venue(
+            deps.as_ref(),
+            &state,
+            &offer_asset,
+            &base_asset_info,
+            env.block.height,
+        )?;
+        attributes.push(attr("venue", format!("{}:{}", asset.info, venue)));
+        messages.extend(create_asset_swap_msgs(
+            deps.as_ref(),
+            &state,
+            offer_asset,
+            base_asset_info.clone(),
+            venue,
+            leg.limits.belief_prices.get(i).cloned().flatten(),
+            Some(leg.limits.max_spread),
+        )?);
+    }
+    flow.result_mut().basket_amounts = basket_amounts;
+
+    if messages.is_empty() {
+        return finish_leg(deps, env, flow);
+    }
+
+    ARBITRAGE_FLOW.save(deps.storage, &flow)?;
+    Ok(Response::new()
+        .add_submessages(reply_on_last(messages, REPLY_SWAP_TO_BASE))
+        .add_attributes(attributes))
+}
+
+/// ## Description
+/// Finishes the redeem leg once all cluster assets are sold.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **env** is an object of type [`Env`].
+pub fn reply_swap_to_base(
+    deps: DepsMut,
+    env: Env,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    let flow = ARBITRAGE_FLOW.load(deps.storage)?;
+    finish_leg(deps, env, flow)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::contract::{execute, reply, REPLY_ARB_REDEEM, REPLY_DIRECT_BUY_CT};
+    use crate::error::ContractError;
+    use crate::msg::{ClusterStrategy, ExecuteMsg, SlippageLimits};
+    use crate::state::ARBITRAGE_FLOW;
+    use crate::testing::{
+        flash_loan_callback, mock_cluster, mock_dependencies, mock_instantiate, mock_reply,
+        mock_vault, WasmMockQuerier, OWNER, VAULT,
+    };
+    use cosmwasm_std::testing::{mock_env, mock_info, MockApi, MockStorage, MOCK_CONTRACT_ADDR};
+    use cosmwasm_std::{
+        coin, from_binary, BankMsg, CosmosMsg, Decimal, OwnedDeps, Uint128, WasmMsg,
+    };
+
+    /// Starts the redeem arbitrage of a cluster trading below its NAV, up to the redeem reply.
+    /// Returns the loan and the slippage limits of the arbitrage.
+    fn setup(
+        strategy: ClusterStrategy,
+    ) -> (
+        OwnedDeps<MockStorage, MockApi, WasmMockQuerier>,
+        u128,
+        SlippageLimits,
+    ) {
+        let mut deps = mock_dependencies(&[]);
+        mock_vault(&mut deps.querier, Decimal::permille(3));
+        mock_cluster(
+            &mut deps.querier,
+            "cluster",
+            1_100_000_000_000,
+            1_000_000_000_000,
+            1_000_000_000_000,
+            1_000_000_000_000,
+        );
+        mock_instantiate(deps.as_mut());
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            ExecuteMsg::AddCluster {
+                cluster_address: "cluster".to_string(),
+                profit_threshold: None,
+                max_loan: None,
+                strategy,
+                pair_address: None,
+                cooldown: None,
+            },
+        )
+        .unwrap();
+
+        let response = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("keeper", &[]),
+            ExecuteMsg::FlashLoan {
+                cluster_address: "cluster".to_string(),
+                user_address: None,
+                slippage_tolerance: None,
+            },
+        )
+        .unwrap();
+        let callback = flash_loan_callback(&response);
+        let (loan_amount, limits) = match &callback {
+            ExecuteMsg::_CallbackRedeem {
+                loan_amount,
+                limits,
+                ..
+            } => (loan_amount.u128(), limits.clone()),
+            _ => panic!("not a redeem callback"),
+        };
+        deps.querier
+            .with_balance(MOCK_CONTRACT_ADDR, &[coin(loan_amount, "uusd")]);
+        assert!(matches!(
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info("keeper", &[]),
+                callback.clone()
+            ),
+            Err(ContractError::Unauthorized {})
+        ));
+        execute(deps.as_mut(), mock_env(), mock_info(VAULT, &[]), callback).unwrap();
+        (deps, loan_amount, limits)
     }
 
-    // repay and take profit
-    messages.append(&mut repay_and_take_profit(
-        &deps.querier,
-        loan_amount,
-        env.contract.address,
-        state.vault_address,
-        user_address,
-        profit_threshold,
-    )?);
-
-    Ok(Response::new().add_messages(messages))
+    #[test]
+    fn redeemed_amount_is_reported_by_the_cluster() {
+        let (mut deps, loan_amount, _) = setup(ClusterStrategy::Incentives);
+
+        // the redeemed UST came back with a profit
+        deps.querier
+            .with_balance(MOCK_CONTRACT_ADDR, &[coin(2 * loan_amount, "uusd")]);
+        let other_contract = mock_reply(REPLY_ARB_REDEEM, "cluster_pair", &[("token_cost", "5")]);
+        assert!(matches!(
+            reply(deps.as_mut(), mock_env(), other_contract),
+            Err(ContractError::NothingRedeemed {})
+        ));
+        let missing = mock_reply(REPLY_ARB_REDEEM, "cluster", &[("action", "burn")]);
+        assert!(matches!(
+            reply(deps.as_mut(), mock_env(), missing),
+            Err(ContractError::NothingRedeemed {})
+        ));
+
+        let redeemed = mock_reply(REPLY_ARB_REDEEM, "cluster", &[("token_cost", "5")]);
+        let response = reply(deps.as_mut(), mock_env(), redeemed).unwrap();
+        assert!(ARBITRAGE_FLOW.may_load(&deps.storage).unwrap().is_none());
+
+        // the vault gets the loan back with its fee, the user the rest
+        let repaid = loan_amount + loan_amount * 3 / 1000;
+        assert_eq!(
+            response.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: VAULT.to_string(),
+                amount: vec![coin(repaid, "uusd")],
+            })
+        );
+        assert!(response
+            .attributes
+            .iter()
+            .any(|attribute| attribute.key == "profit"
+                && attribute.value == (2 * loan_amount - repaid).to_string()));
+    }
+
+    #[test]
+    fn redeem_below_the_simulated_return_is_reverted() {
+        let (mut deps, loan_amount, _) = setup(ClusterStrategy::Incentives);
+
+        deps.querier
+            .with_balance(MOCK_CONTRACT_ADDR, &[coin(loan_amount / 2, "uusd")]);
+        let redeemed = mock_reply(REPLY_ARB_REDEEM, "cluster", &[("token_cost", "5")]);
+        assert!(matches!(
+            reply(deps.as_mut(), mock_env(), redeemed),
+            Err(ContractError::MaxSlippageExceeded {})
+        ));
+    }
+
+    #[test]
+    fn direct_redeem_burns_all_the_bought_cluster_token() {
+        let (mut deps, loan_amount, limits) = setup(ClusterStrategy::Direct);
+        let bought = limits.min_cluster_token.u128();
+
+        // the CT was bought on the pair
+        deps.querier
+            .with_balance(MOCK_CONTRACT_ADDR, &[coin(0, "uusd")]);
+        deps.querier
+            .with_token_balance("cluster_token", MOCK_CONTRACT_ADDR, bought);
+        let response = reply(
+            deps.as_mut(),
+            mock_env(),
+            mock_reply(REPLY_DIRECT_BUY_CT, "cluster_pair", &[]),
+        )
+        .unwrap();
+        let max_tokens = match &response.messages[1].msg {
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr, msg, ..
+            }) if contract_addr == "cluster" => match from_binary(msg).unwrap() {
+                crate::msg::ClusterExecuteMsg::RebalanceRedeem { max_tokens, .. } => max_tokens,
+                _ => panic!("not a redeem"),
+            },
+            _ => panic!("not a cluster message"),
+        };
+        assert_eq!(max_tokens, Uint128::new(bought));
+
+        // the cluster left the bought CT unburned
+        deps.querier
+            .with_balance(MOCK_CONTRACT_ADDR, &[coin(2 * loan_amount, "uusd")]);
+        let token_cost = bought.to_string();
+        let redeemed = mock_reply(REPLY_ARB_REDEEM, "cluster", &[("token_cost", &token_cost)]);
+        assert!(matches!(
+            reply(deps.as_mut(), mock_env(), redeemed.clone()),
+            Err(ContractError::UnredeemedClusterToken {})
+        ));
+
+        // the cluster burned everything but reported another cost
+        deps.querier
+            .with_token_balance("cluster_token", MOCK_CONTRACT_ADDR, 0);
+        let wrong_cost = mock_reply(REPLY_ARB_REDEEM, "cluster", &[("token_cost", "5")]);
+        assert!(matches!(
+            reply(deps.as_mut(), mock_env(), wrong_cost),
+            Err(ContractError::UnredeemedClusterToken {})
+        ));
+
+        reply(deps.as_mut(), mock_env(), redeemed).unwrap();
+        assert!(ARBITRAGE_FLOW.may_load(&deps.storage).unwrap().is_none());
+    }
 }
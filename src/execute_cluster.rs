@@ -0,0 +1,644 @@
+use cosmwasm_std::{Addr, Deps, DepsMut, Env, MessageInfo, Response, StdResult, Uint128};
+use terra_cosmwasm::TerraMsgWrapper;
+
+use crate::error::ContractError;
+use crate::msg::{ClusterStrategy, Cooldown};
+use crate::state::{ClusterConfig, State, ADMIN, CLUSTERS, CLUSTER_STATS, PAUSED_CLUSTERS, STATE};
+use crate::utils::get_cluster_state;
+
+use astroport::asset::AssetInfo as AstroportAssetInfo;
+
+/// ## Description
+/// Registers a cluster that can be arbitraged. Returns a [`ContractError`] on failure.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **info** is an object of type [`MessageInfo`].
+///
+/// - **cluster_address** is an object of type [`String`].
+///
+/// - **profit_threshold** is an object of type [`Option<Uint128>`] which is the threshold of the
+///   arbitrage profit on this cluster, the configured one if none.
+///
+/// - **max_loan** is an object of type [`Option<Uint128>`] which caps the flash loan.
+///
+/// - **strategy** is an object of type [`ClusterStrategy`].
+///
+/// - **pair_address** is an object of type [`Option<String>`] which overrides the CT pair
+///   found through the Astroport factory.
+///
+/// - **cooldown** is an object of type [`Option<Cooldown>`] which is the minimum delay between
+///   two arbitrages on this cluster.
+///
+/// ## Executor
+/// Only the owner can execute this.
+#[allow(clippy::too_many_arguments)]
+pub fn try_add_cluster(
+    deps: DepsMut,
+    info: MessageInfo,
+    cluster_address: String,
+    profit_threshold: Option<Uint128>,
+    max_loan: Option<Uint128>,
+    strategy: ClusterStrategy,
+    pair_address: Option<String>,
+    cooldown: Option<Cooldown>,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+    let state = STATE.load(deps.storage)?;
+
+    assert_cluster_strategy(&state, &strategy)?;
+    let cluster_address = deps.api.addr_validate(cluster_address.as_str())?;
+    if CLUSTERS.has(deps.storage, &cluster_address) {
+        return Err(ContractError::ClusterAlreadyRegistered {});
+    }
+    // only actual clusters can be registered
+    get_cluster_state(deps.as_ref(), &cluster_address)?;
+
+    let cluster = ClusterConfig {
+        enabled: true,
+        profit_threshold: profit_threshold.unwrap_or(state.profit_threshold),
+        max_loan,
+        strategy,
+        pair_address: pair_address
+            .map(|pair_address| deps.api.addr_validate(pair_address.as_str()))
+            .transpose()?,
+        cooldown,
+    };
+    CLUSTERS.save(deps.storage, &cluster_address, &cluster)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "add_cluster")
+        .add_attribute("cluster", cluster_address.to_string()))
+}
+
+/// ## Description
+/// Updates the settings of a registered cluster, the `clear_*` flags removing the optional ones.
+/// Returns a [`ContractError`] on failure.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **info** is an object of type [`MessageInfo`].
+///
+/// - **cluster_address** is an object of type [`String`].
+///
+/// - **enabled** is an object of type [`Option<bool>`].
+///
+/// - **profit_threshold** is an object of type [`Option<Uint128>`].
+///
+/// - **max_loan** is an object of type [`Option<Uint128>`].
+///
+/// - **clear_max_loan** is an object of type [`Option<bool>`] which removes the loan cap.
+///
+/// - **strategy** is an object of type [`Option<ClusterStrategy>`].
+///
+/// - **pair_address** is an object of type [`Option<String>`].
+///
+/// - **clear_pair_address** is an object of type [`Option<bool>`] which goes back to the factory pair.
+///
+/// - **cooldown** is an object of type [`Option<Cooldown>`].
+///
+/// - **clear_cooldown** is an object of type [`Option<bool>`] which removes the cooldown.
+///
+/// ## Executor
+/// Only the owner can execute this.
+#[allow(clippy::too_many_arguments)]
+pub fn try_update_cluster(
+    deps: DepsMut,
+    info: MessageInfo,
+    cluster_address: String,
+    enabled: Option<bool>,
+    profit_threshold: Option<Uint128>,
+    max_loan: Option<Uint128>,
+    clear_max_loan: Option<bool>,
+    strategy: Option<ClusterStrategy>,
+    pair_address: Option<String>,
+    clear_pair_address: Option<bool>,
+    cooldown: Option<Cooldown>,
+    clear_cooldown: Option<bool>,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    let cluster_address = deps.api.addr_validate(cluster_address.as_str())?;
+    let mut cluster = CLUSTERS
+        .may_load(deps.storage, &cluster_address)?
+        .ok_or(ContractError::ClusterNotRegistered {})?;
+
+    if let Some(enabled) = enabled {
+        cluster.enabled = enabled;
+    }
+    if let Some(profit_threshold) = profit_threshold {
+        cluster.profit_threshold = profit_threshold;
+    }
+    if let Some(max_loan) = max_loan {
+        cluster.max_loan = Some(max_loan);
+    }
+    if let Some(strategy) = strategy {
+        cluster.strategy = strategy;
+    }
+    if let Some(pair_address) = pair_address {
+        cluster.pair_address = Some(deps.api.addr_validate(pair_address.as_str())?);
+    }
+    if let Some(cooldown) = cooldown {
+        cluster.cooldown = Some(cooldown);
+    }
+    if clear_max_loan == Some(true) {
+        cluster.max_loan = None;
+    }
+    if clear_pair_address == Some(true) {
+        cluster.pair_address = None;
+    }
+    if clear_cooldown == Some(true) {
+        cluster.cooldown = None;
+    }
+    assert_cluster_strategy(&STATE.load(deps.storage)?, &cluster.strategy)?;
+
+    CLUSTERS.save(deps.storage, &cluster_address, &cluster)?;
+    Ok(Response::new()
+        .add_attribute("action", "update_cluster")
+        .add_attribute("cluster", cluster_address.to_string()))
+}
+
+/// ## Description
+/// Removes a cluster from the registry together with its pause and stats, so that registering
+/// it again starts afresh. Returns a [`ContractError`] on failure.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **info** is an object of type [`MessageInfo`].
+///
+/// - **cluster_address** is an object of type [`String`].
+///
+/// ## Executor
+/// Only the owner can execute this.
+pub fn try_remove_cluster(
+    deps: DepsMut,
+    info: MessageInfo,
+    cluster_address: String,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    let cluster_address = deps.api.addr_validate(cluster_address.as_str())?;
+    if !CLUSTERS.has(deps.storage, &cluster_address) {
+        return Err(ContractError::ClusterNotRegistered {});
+    }
+    CLUSTERS.remove(deps.storage, &cluster_address);
+    PAUSED_CLUSTERS.remove(deps.storage, &cluster_address);
+    CLUSTER_STATS.remove(deps.storage, &cluster_address);
+
+    Ok(Response::new()
+        .add_attribute("action", "remove_cluster")
+        .add_attribute("cluster", cluster_address.to_string()))
+}
+
+/// ## Description
+/// Loads a registered cluster and checks that it can be arbitraged.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **cluster_address** is a reference to an object of type [`Addr`].
+pub fn load_enabled_cluster(
+    deps: Deps,
+    cluster_address: &Addr,
+) -> Result<ClusterConfig, ContractError> {
+    let cluster = CLUSTERS
+        .may_load(deps.storage, cluster_address)?
+        .ok_or(ContractError::ClusterNotRegistered {})?;
+    if !cluster.enabled {
+        return Err(ContractError::ClusterDisabled {});
+    }
+    if !get_cluster_state(deps, cluster_address)?.active {
+        return Err(ContractError::ClusterInactive {});
+    }
+    Ok(cluster)
+}
+
+/// ## Description
+/// Checks that the cooldown of a cluster has elapsed since its last arbitrage.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **env** is a reference to an object of type [`Env`].
+///
+/// - **cluster_address** is a reference to an object of type [`Addr`].
+///
+/// - **cluster** is a reference to an object of type [`ClusterConfig`].
+pub fn assert_cluster_cooldown(
+    deps: Deps,
+    env: &Env,
+    cluster_address: &Addr,
+    cluster: &ClusterConfig,
+) -> Result<(), ContractError> {
+    let cooldown = match &cluster.cooldown {
+        Some(cooldown) => cooldown,
+        None => return Ok(()),
+    };
+    // clusters never arbitraged have no cooldown to wait for
+    let stats = match CLUSTER_STATS.may_load(deps.storage, cluster_address)? {
+        Some(stats) => stats,
+        None => return Ok(()),
+    };
+
+    let cooling = match cooldown {
+        Cooldown::Blocks(blocks) => env.block.height < stats.last_height.saturating_add(*blocks),
+        Cooldown::Seconds(seconds) => env.block.time < stats.last_time.plus_seconds(*seconds),
+    };
+    if cooling {
+        return Err(ContractError::ClusterCooldown {});
+    }
+    Ok(())
+}
+
+/// ## Description
+/// Checks that a cluster strategy can arbitrage the base asset. The Nebula incentives contract
+/// only arbitrages against UST.
+///
+/// ## Params
+/// - **state** is a reference to an object of type [`State`].
+///
+/// - **strategy** is a reference to an object of type [`ClusterStrategy`].
+pub fn assert_cluster_strategy(
+    state: &State,
+    strategy: &ClusterStrategy,
+) -> Result<(), ContractError> {
+    let ust = AstroportAssetInfo::NativeToken {
+        denom: "uusd".to_string(),
+    };
+    if *strategy == ClusterStrategy::Incentives && state.base_asset_info() != ust {
+        return Err(ContractError::IncentivesRequireUst {});
+    }
+    Ok(())
+}
+
+/// ## Description
+/// Returns the strategy of a cluster, the incentives contract if it is not registered.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **cluster_address** is a reference to an object of type [`Addr`].
+pub fn cluster_strategy(deps: Deps, cluster_address: &Addr) -> StdResult<ClusterStrategy> {
+    Ok(CLUSTERS
+        .may_load(deps.storage, cluster_address)?
+        .map(|cluster| cluster.strategy)
+        .unwrap_or(ClusterStrategy::Incentives))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::execute;
+    use crate::error::ContractError;
+    use crate::msg::ExecuteMsg;
+    use crate::state::ArbitrageStats;
+    use crate::testing::{
+        mock_cluster, mock_dependencies, mock_instantiate, mock_vault, WasmMockQuerier, OWNER,
+    };
+    use cosmwasm_std::testing::{mock_env, mock_info, MockApi, MockStorage};
+    use cosmwasm_std::{Decimal, OwnedDeps};
+    use terra_cosmwasm::TerraMsgWrapper;
+
+    /// A profitable cluster arbitraged at the height of [`mock_env`].
+    fn setup(cooldown: Option<Cooldown>) -> OwnedDeps<MockStorage, MockApi, WasmMockQuerier> {
+        let mut deps = mock_dependencies(&[]);
+        mock_vault(&mut deps.querier, Decimal::permille(3));
+        mock_cluster(
+            &mut deps.querier,
+            "cluster",
+            1_100_000_000_000,
+            1_000_000_000_000,
+            1_000_000_000_000,
+            1_000_000_000_000,
+        );
+        mock_instantiate(deps.as_mut());
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            ExecuteMsg::AddCluster {
+                cluster_address: "cluster".to_string(),
+                profit_threshold: None,
+                max_loan: None,
+                strategy: ClusterStrategy::Incentives,
+                pair_address: None,
+                cooldown,
+            },
+        )
+        .unwrap();
+
+        let env = mock_env();
+        CLUSTER_STATS
+            .save(
+                deps.as_mut().storage,
+                &Addr::unchecked("cluster"),
+                &ArbitrageStats {
+                    redeem_count: 1,
+                    last_height: env.block.height,
+                    last_time: env.block.time,
+                    ..ArbitrageStats::default()
+                },
+            )
+            .unwrap();
+        deps
+    }
+
+    fn flash_loan(deps: DepsMut, env: Env) -> Result<Response<TerraMsgWrapper>, ContractError> {
+        execute(
+            deps,
+            env,
+            mock_info("keeper", &[]),
+            ExecuteMsg::FlashLoan {
+                cluster_address: "cluster".to_string(),
+                user_address: None,
+                slippage_tolerance: None,
+            },
+        )
+    }
+
+    #[test]
+    fn incentives_strategy_needs_a_ust_base_asset() {
+        let mut deps = setup(None);
+        let mut state = STATE.load(&deps.storage).unwrap();
+        state.base_asset.asset_info = terraswap::asset::AssetInfo::NativeToken {
+            denom: "uluna".to_string(),
+        };
+        STATE.save(&mut deps.storage, &state).unwrap();
+
+        let add = |strategy| ExecuteMsg::AddCluster {
+            cluster_address: "cluster2".to_string(),
+            profit_threshold: None,
+            max_loan: None,
+            strategy,
+            pair_address: None,
+            cooldown: None,
+        };
+        assert!(matches!(
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info(OWNER, &[]),
+                add(ClusterStrategy::Incentives)
+            ),
+            Err(ContractError::IncentivesRequireUst {})
+        ));
+        // the cluster registered while the base asset was UST can no longer be arbitraged
+        assert!(matches!(
+            flash_loan(deps.as_mut(), mock_env()),
+            Err(ContractError::IncentivesRequireUst {})
+        ));
+
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            ExecuteMsg::UpdateCluster {
+                cluster_address: "cluster".to_string(),
+                enabled: None,
+                profit_threshold: None,
+                max_loan: None,
+                clear_max_loan: None,
+                strategy: Some(ClusterStrategy::Direct),
+                pair_address: None,
+                clear_pair_address: None,
+                cooldown: None,
+                clear_cooldown: None,
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            CLUSTERS
+                .load(&deps.storage, &Addr::unchecked("cluster"))
+                .unwrap()
+                .strategy,
+            ClusterStrategy::Direct
+        );
+    }
+
+    #[test]
+    fn block_cooldown_rejects_a_second_call() {
+        let mut deps = setup(Some(Cooldown::Blocks(10)));
+
+        let mut env = mock_env();
+        env.block.height += 9;
+        let result = flash_loan(deps.as_mut(), env.clone());
+        assert!(matches!(result, Err(ContractError::ClusterCooldown {})));
+
+        env.block.height += 1;
+        flash_loan(deps.as_mut(), env).unwrap();
+    }
+
+    #[test]
+    fn time_cooldown_rejects_a_second_call() {
+        let mut deps = setup(Some(Cooldown::Seconds(60)));
+
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(59);
+        let result = flash_loan(deps.as_mut(), env.clone());
+        assert!(matches!(result, Err(ContractError::ClusterCooldown {})));
+
+        env.block.time = env.block.time.plus_seconds(1);
+        flash_loan(deps.as_mut(), env).unwrap();
+    }
+
+    #[test]
+    fn huge_cooldown_does_not_overflow() {
+        let mut deps = setup(Some(Cooldown::Blocks(u64::MAX)));
+
+        let result = flash_loan(deps.as_mut(), mock_env());
+        assert!(matches!(result, Err(ContractError::ClusterCooldown {})));
+    }
+
+    #[test]
+    fn cooldown_can_be_cleared() {
+        let mut deps = setup(Some(Cooldown::Blocks(10)));
+
+        let update = |clear_cooldown| ExecuteMsg::UpdateCluster {
+            cluster_address: "cluster".to_string(),
+            enabled: None,
+            profit_threshold: None,
+            max_loan: None,
+            clear_max_loan: None,
+            strategy: None,
+            pair_address: None,
+            clear_pair_address: None,
+            cooldown: Some(Cooldown::Blocks(20)),
+            clear_cooldown,
+        };
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            update(None),
+        )
+        .unwrap();
+        let cluster = CLUSTERS
+            .load(deps.as_ref().storage, &Addr::unchecked("cluster"))
+            .unwrap();
+        assert_eq!(cluster.cooldown, Some(Cooldown::Blocks(20)));
+
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            update(Some(true)),
+        )
+        .unwrap();
+        let cluster = CLUSTERS
+            .load(deps.as_ref().storage, &Addr::unchecked("cluster"))
+            .unwrap();
+        assert_eq!(cluster.cooldown, None);
+        flash_loan(deps.as_mut(), mock_env()).unwrap();
+    }
+
+    #[test]
+    fn registry_adds_updates_and_removes_clusters() {
+        let mut deps = setup(Some(Cooldown::Blocks(10)));
+        let cluster_address = Addr::unchecked("cluster");
+        let owner_execute =
+            |deps: DepsMut, msg: ExecuteMsg| execute(deps, mock_env(), mock_info(OWNER, &[]), msg);
+        let add = |cluster_address: &str| ExecuteMsg::AddCluster {
+            cluster_address: cluster_address.to_string(),
+            profit_threshold: None,
+            max_loan: None,
+            strategy: ClusterStrategy::Incentives,
+            pair_address: None,
+            cooldown: None,
+        };
+
+        let result = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("keeper", &[]),
+            add("cluster"),
+        );
+        assert!(matches!(result, Err(ContractError::Admin(_))));
+        let result = owner_execute(deps.as_mut(), add("cluster"));
+        assert!(matches!(
+            result,
+            Err(ContractError::ClusterAlreadyRegistered {})
+        ));
+        // only clusters answering their state can be registered
+        let result = owner_execute(deps.as_mut(), add("not_a_cluster"));
+        assert!(matches!(result, Err(ContractError::Std(_))));
+
+        let update = |clear: Option<bool>| ExecuteMsg::UpdateCluster {
+            cluster_address: "cluster".to_string(),
+            enabled: Some(false),
+            profit_threshold: Some(Uint128::new(1_000)),
+            max_loan: Some(Uint128::new(5_000)),
+            clear_max_loan: clear,
+            strategy: Some(ClusterStrategy::Direct),
+            pair_address: Some("other_pair".to_string()),
+            clear_pair_address: clear,
+            cooldown: None,
+            clear_cooldown: None,
+        };
+        owner_execute(deps.as_mut(), update(None)).unwrap();
+        assert_eq!(
+            CLUSTERS
+                .load(deps.as_ref().storage, &cluster_address)
+                .unwrap(),
+            ClusterConfig {
+                enabled: false,
+                profit_threshold: Uint128::new(1_000),
+                max_loan: Some(Uint128::new(5_000)),
+                strategy: ClusterStrategy::Direct,
+                pair_address: Some(Addr::unchecked("other_pair")),
+                cooldown: Some(Cooldown::Blocks(10)),
+            }
+        );
+        owner_execute(deps.as_mut(), update(Some(true))).unwrap();
+        let cluster = CLUSTERS
+            .load(deps.as_ref().storage, &cluster_address)
+            .unwrap();
+        assert_eq!(cluster.max_loan, None);
+        assert_eq!(cluster.pair_address, None);
+
+        // removing the cluster forgets its pause and its cooldown
+        owner_execute(
+            deps.as_mut(),
+            ExecuteMsg::PauseCluster {
+                cluster: "cluster".to_string(),
+            },
+        )
+        .unwrap();
+        owner_execute(
+            deps.as_mut(),
+            ExecuteMsg::RemoveCluster {
+                cluster_address: "cluster".to_string(),
+            },
+        )
+        .unwrap();
+        assert!(!CLUSTERS.has(deps.as_ref().storage, &cluster_address));
+        assert!(!PAUSED_CLUSTERS.has(deps.as_ref().storage, &cluster_address));
+        assert!(!CLUSTER_STATS.has(deps.as_ref().storage, &cluster_address));
+        let result = owner_execute(
+            deps.as_mut(),
+            ExecuteMsg::RemoveCluster {
+                cluster_address: "cluster".to_string(),
+            },
+        );
+        assert!(matches!(
+            result,
+            Err(ContractError::ClusterNotRegistered {})
+        ));
+
+        owner_execute(deps.as_mut(), add("cluster")).unwrap();
+        flash_loan(deps.as_mut(), mock_env()).unwrap();
+    }
+
+    #[test]
+    fn flash_loan_needs_an_enabled_registered_cluster() {
+        let mut deps = setup(None);
+        let disable = |enabled| ExecuteMsg::UpdateCluster {
+            cluster_address: "cluster".to_string(),
+            enabled: Some(enabled),
+            profit_threshold: None,
+            max_loan: None,
+            clear_max_loan: None,
+            strategy: None,
+            pair_address: None,
+            clear_pair_address: None,
+            cooldown: None,
+            clear_cooldown: None,
+        };
+
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            disable(false),
+        )
+        .unwrap();
+        let result = flash_loan(deps.as_mut(), mock_env());
+        assert!(matches!(result, Err(ContractError::ClusterDisabled {})));
+
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            disable(true),
+        )
+        .unwrap();
+        flash_loan(deps.as_mut(), mock_env()).unwrap();
+
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            ExecuteMsg::RemoveCluster {
+                cluster_address: "cluster".to_string(),
+            },
+        )
+        .unwrap();
+        let result = flash_loan(deps.as_mut(), mock_env());
+        assert!(matches!(
+            result,
+            Err(ContractError::ClusterNotRegistered {})
+        ));
+    }
+}
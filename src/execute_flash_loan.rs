@@ -1,16 +1,31 @@
 use cosmwasm_std::{
-    attr, to_binary, Addr, BankMsg, CosmosMsg, DepsMut, Env, MessageInfo, QuerierWrapper, Response,
-    StdResult, Uint128, WasmMsg,
+    attr, to_binary, Addr, CosmosMsg, Decimal, Deps, DepsMut, Env, MessageInfo, Response,
+    StdResult, Storage, Uint128, WasmMsg,
 };
+use cw_storage_plus::U64Key;
 use terra_cosmwasm::TerraMsgWrapper;
 
 use crate::error::ContractError;
-use crate::msg::ExecuteMsg;
-use crate::query::estimate_arbitrage;
-use crate::state::STATE;
+use crate::execute_access::{assert_executor, assert_not_paused};
+use crate::execute_arb_create::start_create_leg;
+use crate::execute_arb_redeem::start_redeem_leg;
+use crate::execute_cluster::{
+    assert_cluster_cooldown, assert_cluster_strategy, load_enabled_cluster,
+};
+use crate::execute_profit::distribute_profit;
+use crate::msg::{ArbitrageDirection, ArbitrageLeg, ExecuteMsg};
+use crate::query::plan_arbitrage;
+use crate::simulation::slippage_limits;
+use crate::state::{
+    history, ArbitrageFlow, ArbitrageRecord, LegResult, State, ARBITRAGE_FLOW, CLUSTER_STATS,
+    GLOBAL_STATS, HISTORY_COUNT, STATE,
+};
+use crate::utils::{
+    assert_slippage_tolerance, compute_tax, create_transfer_msg, query_flash_loan_fee,
+};
 
-use astroport::querier::query_balance;
-use terraswap::asset::{Asset, AssetInfo};
+use astroport::asset::{Asset as AstroportAsset, AssetInfo as AstroportAssetInfo};
+use terraswap::asset::Asset;
 
 use white_whale::ust_vault::msg::ExecuteMsg as WhiteWhaleExecuteMsg;
 use white_whale::ust_vault::msg::FlashLoanPayload;
@@ -24,46 +39,72 @@ use white_whale::ust_vault::msg::FlashLoanPayload;
 /// - **env** is an object of type [`Env`].
 ///
 /// - **cluster_adddress** is an object type [`String`]. which is the cluster that want to do arbitrage
+///
+/// - **slippage_tolerance** is an object of type [`Option<Decimal>`] which overrides the configured one.
 pub fn try_flash_loan(
     deps: DepsMut,
+    env: Env,
     info: MessageInfo,
     cluster_address: String,
     user_address: Option<String>,
+    slippage_tolerance: Option<Decimal>,
 ) -> Result<Response<TerraMsgWrapper>, ContractError> {
     let state = STATE.load(deps.storage)?;
+    assert_executor(deps.as_ref(), &state, &info.sender)?;
+    let slippage_tolerance = slippage_tolerance.unwrap_or(state.slippage_tolerance);
+    assert_slippage_tolerance(slippage_tolerance)?;
     let validated_cluster_address = deps.api.addr_validate(cluster_address.as_str())?;
+    assert_not_paused(deps.as_ref(), &validated_cluster_address)?;
+    let cluster = load_enabled_cluster(deps.as_ref(), &validated_cluster_address)?;
+    assert_cluster_strategy(&state, &cluster.strategy)?;
+    assert_cluster_cooldown(deps.as_ref(), &env, &validated_cluster_address, &cluster)?;
+    let keeper_address = info.sender;
     let user_address = match user_address {
         Some(addr) => deps.api.addr_validate(addr.as_str())?,
-        None => info.sender,
+        None => keeper_address.clone(),
     };
 
-    let estimate = estimate_arbitrage(deps.as_ref(), cluster_address, &state)?;
+    let (estimate, simulation) = plan_arbitrage(deps.as_ref(), &env, cluster_address, &state)?;
+    // don't borrow for an arbitrage that would revert on the profit check
+    if estimate.arbitrage_cost.is_zero() {
+        return Err(ContractError::NoArbitrageOpportunity {});
+    }
+    if estimate.expected_profit < cluster.profit_threshold {
+        return Err(ContractError::InsufficientProfit {});
+    }
+    let limits = slippage_limits(&simulation, slippage_tolerance);
 
-    let callback = if estimate.market_price < estimate.intrinsic_price {
+    let callback = if estimate.direction == ArbitrageDirection::Redeem {
         // buy CT from Astroport and redeem
         ExecuteMsg::_CallbackRedeem {
             user_address,
+            keeper_address,
             loan_amount: estimate.arbitrage_cost,
             cluster_address: validated_cluster_address,
             target: estimate.target,
-            profit_threshold: state.profit_threshold,
+            profit_threshold: cluster.profit_threshold,
+            limits,
+            market_price: estimate.market_price,
+            intrinsic_price: estimate.intrinsic_price,
         }
     } else {
         // mint CT and sell on Astroport
         ExecuteMsg::_CallbackCreate {
             user_address,
+            keeper_address,
             loan_amount: estimate.arbitrage_cost,
             cluster_address: validated_cluster_address,
             target: estimate.target,
             prices: estimate.prices,
-            profit_threshold: state.profit_threshold,
+            profit_threshold: cluster.profit_threshold,
+            limits,
+            market_price: estimate.market_price,
+            intrinsic_price: estimate.intrinsic_price,
         }
     };
 
     let requested_asset = Asset {
-        info: AssetInfo::NativeToken {
-            denom: "uusd".to_string(),
-        },
+        info: state.base_asset.asset_info.clone(),
         amount: estimate.arbitrage_cost,
     };
 
@@ -87,86 +128,643 @@ pub fn try_flash_loan(
 }
 
 /// ## Description
-/// Selects strategy and estimate flash loan amount from cluster info and astroport pool info.
+/// Estimates every cluster, drops the ones that are paused, disabled, cooling down or whose
+/// expected profit is below their threshold and executes the remaining ones under a single
+/// flash loan, whose profit has to clear the highest threshold of its clusters.
 ///
 /// ## Params
-/// - **querier** is a reference to an object of type [`QuerierWrapper`].
+/// - **deps** is an object of type [`DepsMut`].
 ///
-/// - **loan_amount** is an object of type [`Uint128`].
+/// - **env** is an object of type [`Env`].
 ///
-/// - **contract_address** is an object of type [`Addr`].
+/// - **info** is an object of type [`MessageInfo`].
 ///
-/// - **vault_address** is an object of type [`Addr`].
-pub fn repay_and_take_profit(
-    querier: &QuerierWrapper,
-    loan_amount: Uint128,
-    contract_address: Addr,
-    vault_address: Addr,
-    user_address: Addr,
-    profit_threshold: Uint128,
-) -> StdResult<Vec<CosmosMsg<TerraMsgWrapper>>> {
-    let mut messages = vec![];
+/// - **clusters** is an object of type [`Vec<String>`] which are the clusters that want to do arbitrage.
+///
+/// - **user_address** is an object of type [`Option<String>`] which is the address to send profit to.
+///
+/// - **slippage_tolerance** is an object of type [`Option<Decimal>`] which overrides the configured one.
+pub fn try_flash_loan_batch(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    clusters: Vec<String>,
+    user_address: Option<String>,
+    slippage_tolerance: Option<Decimal>,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    let state = STATE.load(deps.storage)?;
+    assert_executor(deps.as_ref(), &state, &info.sender)?;
+    let slippage_tolerance = slippage_tolerance.unwrap_or(state.slippage_tolerance);
+    assert_slippage_tolerance(slippage_tolerance)?;
+    let keeper_address = info.sender;
+    let user_address = match user_address {
+        Some(addr) => deps.api.addr_validate(addr.as_str())?,
+        None => keeper_address.clone(),
+    };
 
-    // calculates return amount
-    let return_asset = Asset {
-        info: AssetInfo::NativeToken {
-            denom: "uusd".to_string(),
-        },
-        // TODO: fix this
-        amount: loan_amount.checked_div(Uint128::from(999u128))? + loan_amount,
+    let mut legs = vec![];
+    let mut profit_threshold = Uint128::zero();
+    let mut skipped = vec![];
+    let mut attributes = vec![attr("action", "flash_loan_batch")];
+    for cluster_address in clusters {
+        // a cluster that can't be arbitraged now doesn't revert the others
+        let planned = plan_batch_leg(
+            deps.as_ref(),
+            &env,
+            &state,
+            cluster_address.clone(),
+            slippage_tolerance,
+        );
+        let (leg, cluster_threshold) = match planned {
+            Ok(Some(planned)) => planned,
+            Ok(None) | Err(_) => {
+                skipped.push(cluster_address);
+                continue;
+            }
+        };
+
+        // the batch is repaid once, so its profit must clear the strictest leg threshold
+        profit_threshold = profit_threshold.max(cluster_threshold);
+        attributes.push(attr("cluster", leg.cluster_address.to_string()));
+        attributes.push(attr("loan_amount", leg.loan_amount.to_string()));
+        legs.push(leg);
+    }
+    if !skipped.is_empty() {
+        attributes.push(attr("skipped", skipped.join(",")));
+    }
+
+    if legs.is_empty() {
+        return Err(ContractError::NoArbitrageOpportunity {});
+    }
+
+    let loan_amount = legs
+        .iter()
+        .fold(Uint128::zero(), |total, leg| total + leg.loan_amount);
+    attributes.push(attr("total_loan_amount", loan_amount.to_string()));
+
+    let callback = ExecuteMsg::_CallbackBatch {
+        user_address,
+        keeper_address,
+        loan_amount,
+        legs,
+        profit_threshold,
     };
 
-    messages.push(CosmosMsg::Bank(BankMsg::Send {
-        to_address: vault_address.to_string(),
-        amount: vec![return_asset.deduct_tax(querier)?],
-    }));
+    let requested_asset = Asset {
+        info: state.base_asset.asset_info.clone(),
+        amount: loan_amount,
+    };
 
-    // take profit
-    messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
-        contract_addr: contract_address.to_string(),
-        msg: to_binary(&ExecuteMsg::_UserProfit {
-            user_address,
-            profit_threshold,
-        })?,
-        funds: vec![],
-    }));
+    Ok(Response::new()
+        .add_message(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: state.vault_address.to_string(),
+            msg: to_binary(&WhiteWhaleExecuteMsg::FlashLoan {
+                payload: FlashLoanPayload {
+                    requested_asset,
+                    callback: to_binary(&callback)?,
+                },
+            })?,
+            funds: vec![],
+        }))
+        .add_attributes(attributes))
+}
 
-    Ok(messages)
+/// ## Description
+/// Plans the batch leg of a cluster with its profit threshold. Returns [`None`] when the
+/// expected profit of the cluster is below its threshold, or a [`ContractError`] when it is
+/// paused, disabled, cooling down or can't be estimated.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **env** is a reference to an object of type [`Env`].
+///
+/// - **state** is a reference to an object of type [`State`].
+///
+/// - **cluster_address** is an object of type [`String`].
+///
+/// - **slippage_tolerance** is an object of type [`Decimal`].
+fn plan_batch_leg(
+    deps: Deps,
+    env: &Env,
+    state: &State,
+    cluster_address: String,
+    slippage_tolerance: Decimal,
+) -> Result<Option<(ArbitrageLeg, Uint128)>, ContractError> {
+    let validated_cluster_address = deps.api.addr_validate(cluster_address.as_str())?;
+    assert_not_paused(deps, &validated_cluster_address)?;
+    let cluster = load_enabled_cluster(deps, &validated_cluster_address)?;
+    assert_cluster_strategy(state, &cluster.strategy)?;
+    assert_cluster_cooldown(deps, env, &validated_cluster_address, &cluster)?;
+    let (estimate, simulation) = plan_arbitrage(deps, env, cluster_address, state)?;
+    // an unprofitable leg would spend fees and taxes against the others
+    if estimate.arbitrage_cost.is_zero() || estimate.expected_profit < cluster.profit_threshold {
+        return Ok(None);
+    }
+
+    let leg = ArbitrageLeg {
+        cluster_address: validated_cluster_address,
+        direction: estimate.direction,
+        loan_amount: estimate.arbitrage_cost,
+        target: estimate.target,
+        prices: estimate.prices,
+        limits: slippage_limits(&simulation, slippage_tolerance),
+        market_price: estimate.market_price,
+        intrinsic_price: estimate.intrinsic_price,
+    };
+    Ok(Some((leg, cluster.profit_threshold)))
 }
 
 /// ## Description
-/// Sends all of profit to user
+/// Executes every leg of a batch with the loaned funds, then repays the flash loan once
+/// and checks the profit of the whole batch against the threshold.
 ///
 /// ## Params
 /// - **deps** is an object of type [`DepsMut`].
 ///
 /// - **env** is an object of type [`Env`].
-pub fn try_user_profit(
+///
+/// - **info** is an object of type [`MessageInfo`].
+///
+/// - **legs** is a reference to a slice of [`ArbitrageLeg`] which are the arbitrages to execute.
+#[allow(clippy::too_many_arguments)]
+pub fn try_callback_batch(
     deps: DepsMut,
     env: Env,
     info: MessageInfo,
     user_address: Addr,
+    keeper_address: Addr,
+    loan_amount: Uint128,
+    legs: &[ArbitrageLeg],
     profit_threshold: Uint128,
 ) -> Result<Response<TerraMsgWrapper>, ContractError> {
-    if info.sender != env.contract.address {
+    let state = STATE.load(deps.storage)?;
+
+    if info.sender != state.vault_address {
         return Err(ContractError::Unauthorized {});
     }
+    for leg in legs {
+        assert_not_paused(deps.as_ref(), &leg.cluster_address)?;
+    }
+
+    start_arbitrage(
+        deps,
+        env,
+        &state,
+        ArbitrageFlow {
+            user_address,
+            keeper_address,
+            loan_amount,
+            profit_threshold,
+            legs: legs.to_vec(),
+            results: vec![LegResult::default(); legs.len()],
+            current_leg: 0,
+            initial_base_balance: Uint128::zero(),
+            base_snapshot: Uint128::zero(),
+            asset_snapshots: vec![],
+        },
+    )
+}
+
+/// ## Description
+/// Stores the arbitrage flow and starts its first leg. The following steps are
+/// driven by the reply handlers.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **env** is an object of type [`Env`].
+///
+/// - **state** is a reference to an object of type [`State`].
+///
+/// - **flow** is an object of type [`ArbitrageFlow`] which is the arbitrage to execute.
+pub fn start_arbitrage(
+    deps: DepsMut,
+    env: Env,
+    state: &State,
+    mut flow: ArbitrageFlow,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    if flow.legs.is_empty() {
+        return Err(ContractError::NoArbitrageOpportunity {});
+    }
+
+    // the funds held before the loan are never counted as profit
+    flow.initial_base_balance = state
+        .base_asset_info()
+        .query_pool(&deps.querier, env.contract.address.clone())?;
 
-    let amount = query_balance(&deps.querier, env.contract.address, "uusd".to_string())?;
-    if amount < profit_threshold {
+    start_leg(deps, env, state, flow)
+}
+
+/// ## Description
+/// Snapshots the base asset and leg asset balances and sends the first step of the current leg.
+fn start_leg(
+    deps: DepsMut,
+    env: Env,
+    state: &State,
+    mut flow: ArbitrageFlow,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    let base_asset_info = state.base_asset_info();
+    flow.base_snapshot = base_asset_info.query_pool(&deps.querier, env.contract.address.clone())?;
+
+    let mut asset_snapshots: Vec<AstroportAsset> = vec![];
+    for asset in &flow.leg().target {
+        if asset_snapshots
+            .iter()
+            .any(|snapshot| snapshot.info.equal(&asset.info))
+        {
+            continue;
+        }
+        let amount = if asset.info.equal(&base_asset_info) {
+            // the loan of the leg is spent by the leg itself
+            flow.base_snapshot.saturating_sub(flow.leg().loan_amount)
+        } else {
+            asset
+                .info
+                .query_pool(&deps.querier, env.contract.address.clone())?
+        };
+        asset_snapshots.push(AstroportAsset {
+            info: asset.info.clone(),
+            amount,
+        });
+    }
+    flow.asset_snapshots = asset_snapshots;
+
+    match flow.leg().direction {
+        ArbitrageDirection::Redeem => start_redeem_leg(deps, env, state, flow),
+        ArbitrageDirection::Create => start_create_leg(deps, env, state, flow),
+    }
+}
+
+/// ## Description
+/// Records the base asset returned by the current leg, then starts the next leg or,
+/// after the last one, repays the flash loan and takes the profit.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **env** is an object of type [`Env`].
+///
+/// - **flow** is an object of type [`ArbitrageFlow`] which is the arbitrage in progress.
+pub fn finish_leg(
+    deps: DepsMut,
+    env: Env,
+    mut flow: ArbitrageFlow,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    let state = STATE.load(deps.storage)?;
+
+    let balance = state
+        .base_asset_info()
+        .query_pool(&deps.querier, env.contract.address.clone())?;
+    let base_returned = (balance + flow.leg().loan_amount).saturating_sub(flow.base_snapshot);
+    flow.result_mut().base_returned = base_returned;
+    if base_returned < flow.leg().limits.min_base_returned {
+        return Err(ContractError::MaxSlippageExceeded {});
+    }
+
+    let attributes = vec![
+        attr("cluster", flow.leg().cluster_address.to_string()),
+        attr("base_returned", base_returned.to_string()),
+    ];
+
+    flow.current_leg += 1;
+    let response = if (flow.current_leg as usize) < flow.legs.len() {
+        start_leg(deps, env, &state, flow)?
+    } else {
+        ARBITRAGE_FLOW.remove(deps.storage);
+        repay_and_take_profit(deps, &env, balance, &state, &flow)?
+    };
+
+    Ok(response.add_attributes(attributes))
+}
+
+/// ## Description
+/// Repays the flash loan and shares the remaining base asset between the user and the protocol.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **env** is a reference to an object of type [`Env`].
+///
+/// - **balance** is an object of type [`Uint128`] which is the base asset balance of the contract,
+///   the funds held before the flash loan are left untouched.
+///
+/// - **state** is a reference to an object of type [`State`].
+///
+/// - **flow** is a reference to an object of type [`ArbitrageFlow`] which is the finished arbitrage.
+pub fn repay_and_take_profit(
+    deps: DepsMut,
+    env: &Env,
+    balance: Uint128,
+    state: &State,
+    flow: &ArbitrageFlow,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    let base_asset_info = state.base_asset_info();
+
+    // calculates return amount, the vault must receive the loan plus its fee
+    // so the tax is paid on top of it
+    let flash_loan_fee = query_flash_loan_fee(&deps.querier, &state.vault_address)?;
+    let return_amount = flow.loan_amount + flash_loan_fee.compute(flow.loan_amount);
+    let return_tax = compute_tax(deps.as_ref(), &base_asset_info, return_amount)?;
+
+    let held_before = flow.initial_base_balance.saturating_sub(flow.loan_amount);
+    let amount = balance
+        .checked_sub(held_before + return_amount + return_tax)
+        .map_err(|_| ContractError::InsufficientProfit {})?;
+    if amount < flow.profit_threshold {
         return Err(ContractError::InsufficientProfit {});
     }
 
+    let repay = create_transfer_msg(
+        AstroportAsset {
+            info: base_asset_info,
+            amount: return_amount,
+        },
+        &state.vault_address,
+    )?;
+
+    record_history(deps.storage, env, flow, amount)?;
+
+    // take profit
+    let response = distribute_profit(
+        deps,
+        state,
+        amount,
+        &flow.user_address,
+        &flow.keeper_address,
+    )?;
+
     Ok(Response::new()
-        .add_message(CosmosMsg::Bank(BankMsg::Send {
-            to_address: user_address.to_string(),
-            amount: vec![Asset {
-                info: AssetInfo::NativeToken {
-                    denom: "uusd".to_string(),
+        .add_message(repay)
+        .add_submessages(response.messages)
+        .add_attribute(
+            "flash_loan_fee",
+            (return_amount - flow.loan_amount).to_string(),
+        )
+        .add_attribute("profit", amount.to_string())
+        .add_attributes(response.attributes))
+}
+
+/// ## Description
+/// Returns how much of an asset the leg being executed added to the contract balance.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **env** is a reference to an object of type [`Env`].
+///
+/// - **flow** is a reference to an object of type [`ArbitrageFlow`] which is the arbitrage in progress.
+///
+/// - **info** is a reference to an object of type [`AstroportAssetInfo`].
+pub fn query_leg_delta(
+    deps: Deps,
+    env: &Env,
+    flow: &ArbitrageFlow,
+    info: &AstroportAssetInfo,
+) -> StdResult<Uint128> {
+    let balance = info.query_pool(&deps.querier, env.contract.address.clone())?;
+    Ok(balance.saturating_sub(flow.asset_snapshot(info)))
+}
+
+/// ## Description
+/// Stores a history record for every leg of a finished arbitrage and adds it to the global
+/// and cluster stats. The net profit is shared between the legs pro-rata of their loan.
+fn record_history(
+    storage: &mut dyn Storage,
+    env: &Env,
+    flow: &ArbitrageFlow,
+    net_profit: Uint128,
+) -> StdResult<()> {
+    let mut id = HISTORY_COUNT.may_load(storage)?.unwrap_or_default();
+    let mut global_stats = GLOBAL_STATS.may_load(storage)?.unwrap_or_default();
+    for (leg, result) in flow.legs.iter().zip(flow.results.iter()) {
+        id += 1;
+        let record = ArbitrageRecord {
+            id,
+            height: env.block.height,
+            time: env.block.time,
+            cluster_address: leg.cluster_address.clone(),
+            direction: leg.direction.clone(),
+            loan_amount: leg.loan_amount,
+            market_price: leg.market_price,
+            intrinsic_price: leg.intrinsic_price,
+            gross_profit: result.base_returned.saturating_sub(leg.loan_amount),
+            net_profit: net_profit.multiply_ratio(leg.loan_amount, flow.loan_amount),
+            user_address: flow.user_address.clone(),
+        };
+        history().save(storage, U64Key::new(id), &record)?;
+
+        global_stats.add(&record);
+        let mut cluster_stats = CLUSTER_STATS
+            .may_load(storage, &record.cluster_address)?
+            .unwrap_or_default();
+        cluster_stats.add(&record);
+        CLUSTER_STATS.save(storage, &record.cluster_address, &cluster_stats)?;
+    }
+    GLOBAL_STATS.save(storage, &global_stats)?;
+    HISTORY_COUNT.save(storage, &id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::{execute, query, reply, REPLY_ARB_REDEEM};
+    use crate::msg::{ClusterStrategy, HistoryResponse, QueryMsg, StatsResponse};
+    use crate::query::query_estimate_arbitrage;
+    use crate::testing::{
+        flash_loan_callback, mock_cluster, mock_dependencies, mock_instantiate, mock_reply,
+        mock_vault, OWNER, VAULT,
+    };
+    use cosmwasm_std::testing::{mock_env, mock_info, MOCK_CONTRACT_ADDR};
+    use cosmwasm_std::{coin, from_binary};
+
+    fn add_cluster(deps: DepsMut, cluster_address: &str, profit_threshold: u128) {
+        execute(
+            deps,
+            mock_env(),
+            mock_info(OWNER, &[]),
+            ExecuteMsg::AddCluster {
+                cluster_address: cluster_address.to_string(),
+                profit_threshold: Some(Uint128::new(profit_threshold)),
+                max_loan: None,
+                strategy: ClusterStrategy::Incentives,
+                pair_address: None,
+                cooldown: None,
+            },
+        )
+        .unwrap();
+    }
+
+    fn batch_legs(response: &Response<TerraMsgWrapper>) -> Vec<ArbitrageLeg> {
+        match flash_loan_callback(response) {
+            ExecuteMsg::_CallbackBatch { legs, .. } => legs,
+            _ => panic!("not a batch callback"),
+        }
+    }
+
+    fn flash_loan_batch(
+        deps: DepsMut,
+        clusters: &[&str],
+        slippage_tolerance: Option<Decimal>,
+    ) -> Result<Response<TerraMsgWrapper>, ContractError> {
+        execute(
+            deps,
+            mock_env(),
+            mock_info("keeper", &[]),
+            ExecuteMsg::FlashLoanBatch {
+                clusters: clusters.iter().map(|cluster| cluster.to_string()).collect(),
+                user_address: None,
+                slippage_tolerance,
+            },
+        )
+    }
+
+    #[test]
+    fn batch_drops_legs_below_their_threshold() {
+        let mut deps = mock_dependencies(&[]);
+        mock_vault(&mut deps.querier, Decimal::permille(3));
+        deps.querier.with_tax(Decimal::permille(1), 1_000_000);
+        // CT trades at 1 UST and redeems for 1.1 UST
+        mock_cluster(
+            &mut deps.querier,
+            "cluster1",
+            1_100_000_000_000,
+            1_000_000_000_000,
+            1_000_000_000_000,
+            1_000_000_000_000,
+        );
+        // CT redeems for 1.01 UST, some profit but far below the cluster threshold
+        mock_cluster(
+            &mut deps.querier,
+            "cluster2",
+            1_010_000_000_000,
+            1_000_000_000_000,
+            1_000_000_000_000,
+            1_000_000_000_000,
+        );
+        mock_instantiate(deps.as_mut());
+        add_cluster(deps.as_mut(), "cluster1", 0);
+        add_cluster(deps.as_mut(), "cluster2", 1_000_000_000);
+
+        let estimate =
+            query_estimate_arbitrage(deps.as_ref(), mock_env(), "cluster2".to_string()).unwrap();
+        assert!(!estimate.expected_profit.is_zero());
+        assert!(estimate.expected_profit < Uint128::new(1_000_000_000));
+
+        let response = flash_loan_batch(
+            deps.as_mut(),
+            &["cluster1", "cluster2"],
+            Some(Decimal::percent(2)),
+        )
+        .unwrap();
+        let legs = batch_legs(&response);
+        assert_eq!(legs.len(), 1);
+        assert_eq!(legs[0].cluster_address, Addr::unchecked("cluster1"));
+        assert_eq!(legs[0].direction, ArbitrageDirection::Redeem);
+        assert_eq!(legs[0].limits.max_spread, Decimal::percent(2));
+        assert!(response.attributes.contains(&attr("skipped", "cluster2")));
+        assert!(response
+            .attributes
+            .contains(&attr("total_loan_amount", legs[0].loan_amount.to_string())));
+
+        // without a leg left nothing is borrowed
+        let result = flash_loan_batch(deps.as_mut(), &["cluster2"], None);
+        assert!(matches!(
+            result,
+            Err(ContractError::NoArbitrageOpportunity {})
+        ));
+
+        // the override is checked like the configured tolerance
+        let result = flash_loan_batch(deps.as_mut(), &["cluster1"], Some(Decimal::one()));
+        assert!(matches!(
+            result,
+            Err(ContractError::InvalidSlippageTolerance {})
+        ));
+    }
+    #[test]
+    fn finished_arbitrages_are_recorded() {
+        let mut deps = mock_dependencies(&[]);
+        mock_vault(&mut deps.querier, Decimal::permille(3));
+        mock_cluster(
+            &mut deps.querier,
+            "cluster",
+            1_100_000_000_000,
+            1_000_000_000_000,
+            1_000_000_000_000,
+            1_000_000_000_000,
+        );
+        mock_instantiate(deps.as_mut());
+        add_cluster(deps.as_mut(), "cluster", 0);
+
+        let response = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("keeper", &[]),
+            ExecuteMsg::FlashLoan {
+                cluster_address: "cluster".to_string(),
+                user_address: Some("user".to_string()),
+                slippage_tolerance: None,
+            },
+        )
+        .unwrap();
+        let callback = flash_loan_callback(&response);
+        let loan_amount = match &callback {
+            ExecuteMsg::_CallbackRedeem { loan_amount, .. } => loan_amount.u128(),
+            _ => panic!("not a redeem callback"),
+        };
+        deps.querier
+            .with_balance(MOCK_CONTRACT_ADDR, &[coin(loan_amount, "uusd")]);
+        execute(deps.as_mut(), mock_env(), mock_info(VAULT, &[]), callback).unwrap();
+
+        // a reverted arbitrage leaves no record
+        deps.querier
+            .with_balance(MOCK_CONTRACT_ADDR, &[coin(2 * loan_amount, "uusd")]);
+        let missing = mock_reply(REPLY_ARB_REDEEM, "cluster", &[]);
+        assert!(reply(deps.as_mut(), mock_env(), missing).is_err());
+        assert!(HISTORY_COUNT.may_load(&deps.storage).unwrap().is_none());
+
+        let redeemed = mock_reply(REPLY_ARB_REDEEM, "cluster", &[("token_cost", "5")]);
+        reply(deps.as_mut(), mock_env(), redeemed).unwrap();
+        let net_profit = Uint128::new(loan_amount - loan_amount * 3 / 1000);
+
+        let history: HistoryResponse = from_binary(
+            &query(
+                deps.as_ref(),
+                mock_env(),
+                QueryMsg::HistoryByUser {
+                    user_address: "user".to_string(),
+                    start_after: None,
+                    limit: None,
                 },
-                amount,
-            }
-            .deduct_tax(&deps.querier)?],
-        }))
-        .add_attribute("profit", amount.to_string()))
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(history.records.len(), 1);
+        let record = &history.records[0];
+        assert_eq!(record.id, 1);
+        assert_eq!(record.cluster_address, Addr::unchecked("cluster"));
+        assert_eq!(record.direction, ArbitrageDirection::Redeem);
+        assert_eq!(record.loan_amount.u128(), loan_amount);
+        assert_eq!(record.gross_profit.u128(), loan_amount);
+        assert_eq!(record.net_profit, net_profit);
+
+        let stats: StatsResponse = from_binary(
+            &query(
+                deps.as_ref(),
+                mock_env(),
+                QueryMsg::Stats {
+                    cluster: Some("cluster".to_string()),
+                },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(stats.stats.redeem_count, 1);
+        assert_eq!(stats.stats.create_count, 0);
+        assert_eq!(stats.stats.loan_volume.u128(), loan_amount);
+        assert_eq!(stats.stats.total_profit, net_profit);
+        assert_eq!(stats.stats.last_height, mock_env().block.height);
+        assert_eq!(GLOBAL_STATS.load(&deps.storage).unwrap(), stats.stats);
+    }
 }
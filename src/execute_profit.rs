@@ -0,0 +1,570 @@
+use cosmwasm_std::{
+    attr, to_binary, Addr, CosmosMsg, Decimal, Deps, DepsMut, MessageInfo, Reply, Response,
+    StdError, StdResult, SubMsg, Uint128, WasmMsg,
+};
+use terra_cosmwasm::TerraMsgWrapper;
+
+use crate::contract::REPLY_BURN_WHALE;
+use crate::error::ContractError;
+use crate::msg::{KeeperBounty, KeeperReward};
+use crate::state::{ProfitSplit, State, ADMIN, KEEPER_REWARD, PROFIT_SPLIT, PROTOCOL_REVENUE};
+use crate::utils::{create_astroport_swap_msg, create_transfer_msg, find_event_amount};
+
+use astroport::asset::{Asset as AstroportAsset, AssetInfo as AstroportAssetInfo};
+use astroport::querier::simulate;
+
+use white_whale::community_fund::msg::{
+    ConfigResponse as CommunityFundConfigResponse, ExecuteMsg as CommunityFundExecuteMsg,
+    QueryMsg as CommunityFundQueryMsg,
+};
+use white_whale::fee::Fee;
+
+/// ## Description
+/// Sets how the arbitrage profit is shared. Returns a [`ContractError`] on failure.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **info** is an object of type [`MessageInfo`].
+///
+/// - **caller_fee** is an object of type [`Fee`] which is the share sent to the caller.
+///
+/// - **protocol_fee** is an object of type [`Fee`] which is the share sent to the treasury.
+///
+/// - **treasury_address** is an object of type [`String`].
+///
+/// - **community_fund_fee** is an object of type [`Fee`] which is the share sent to the community fund.
+///
+/// - **community_fund_address** is an object of type [`String`].
+///
+/// - **whale_pair_address** is an object of type [`Option<String>`] which is the Astroport pair
+///   the community fund share is swapped to WHALE with before being burned.
+///
+/// ## Executor
+/// Only the owner can execute this.
+#[allow(clippy::too_many_arguments)]
+pub fn try_update_profit_split(
+    deps: DepsMut,
+    info: MessageInfo,
+    caller_fee: Fee,
+    protocol_fee: Fee,
+    treasury_address: String,
+    community_fund_fee: Fee,
+    community_fund_address: String,
+    whale_pair_address: Option<String>,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    if caller_fee.share + protocol_fee.share + community_fund_fee.share != Decimal::one() {
+        return Err(ContractError::InvalidProfitSplit {});
+    }
+
+    let profit_split = ProfitSplit {
+        caller_fee,
+        protocol_fee,
+        treasury_address: deps.api.addr_validate(treasury_address.as_str())?,
+        community_fund_fee,
+        community_fund_address: deps.api.addr_validate(community_fund_address.as_str())?,
+        whale_pair_address: whale_pair_address
+            .map(|pair| deps.api.addr_validate(pair.as_str()))
+            .transpose()?,
+    };
+    PROFIT_SPLIT.save(deps.storage, &profit_split)?;
+
+    Ok(Response::new().add_attribute("action", "update_profit_split"))
+}
+
+/// ## Description
+/// Sets the reward paid to the keeper triggering an arbitrage. Returns a [`ContractError`] on failure.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **info** is an object of type [`MessageInfo`].
+///
+/// - **keeper_reward** is an object of type [`Option<KeeperReward>`] which removes the reward if none.
+///   A reward funded by the protocol needs the profit split to be set.
+///
+/// ## Executor
+/// Only the owner can execute this.
+pub fn try_update_keeper_reward(
+    deps: DepsMut,
+    info: MessageInfo,
+    keeper_reward: Option<KeeperReward>,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    // without a split the protocol share is empty and the keeper would never be paid
+    if matches!(&keeper_reward, Some(keeper_reward) if keeper_reward.funded_by_protocol)
+        && PROFIT_SPLIT.may_load(deps.storage)?.is_none()
+    {
+        return Err(ContractError::ProfitSplitNotSet {});
+    }
+
+    match keeper_reward {
+        Some(keeper_reward) => KEEPER_REWARD.save(deps.storage, &keeper_reward)?,
+        None => KEEPER_REWARD.remove(deps.storage),
+    }
+
+    Ok(Response::new().add_attribute("action", "update_keeper_reward"))
+}
+
+/// ## Description
+/// Shares the arbitrage profit between the caller, the keeper, the treasury and the community
+/// fund. Everything goes to the caller when nothing is configured, otherwise the treasury takes
+/// the rounding remainder. Each transfer pays its own tax.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **state** is a reference to an object of type [`State`].
+///
+/// - **profit** is an object of type [`Uint128`] which is the profit in the base asset.
+///
+/// - **caller** is a reference to an object of type [`Addr`] which is the address to send the caller share to.
+///
+/// - **keeper** is a reference to an object of type [`Addr`] which is the address that triggered the arbitrage.
+pub fn distribute_profit(
+    deps: DepsMut,
+    state: &State,
+    profit: Uint128,
+    caller: &Addr,
+    keeper: &Addr,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    let base_asset_info = state.base_asset_info();
+    let profit_split = PROFIT_SPLIT.may_load(deps.storage)?;
+
+    let (mut caller_amount, mut protocol_amount, community_fund_amount) = match &profit_split {
+        Some(profit_split) => {
+            let caller_amount = profit_split.caller_fee.compute(profit);
+            let community_fund_amount = profit_split.community_fund_fee.compute(profit);
+            // the treasury takes the rounding remainder
+            let protocol_amount = profit - caller_amount - community_fund_amount;
+            (caller_amount, protocol_amount, community_fund_amount)
+        }
+        None => (profit, Uint128::zero(), Uint128::zero()),
+    };
+
+    // the keeper reward is taken from the share funding it, capped by that share
+    let mut keeper_amount = Uint128::zero();
+    if let Some(keeper_reward) = KEEPER_REWARD.may_load(deps.storage)? {
+        let bounty = match keeper_reward.bounty {
+            KeeperBounty::Fixed { amount } => amount,
+            KeeperBounty::Share { fee } => fee.compute(profit),
+        };
+        if keeper_reward.funded_by_protocol {
+            keeper_amount = std::cmp::min(bounty, protocol_amount);
+            protocol_amount -= keeper_amount;
+        } else {
+            keeper_amount = std::cmp::min(bounty, caller_amount);
+            caller_amount -= keeper_amount;
+        }
+    }
+
+    let mut response = Response::new();
+    let mut attributes = vec![
+        attr("caller_profit", caller_amount.to_string()),
+        attr("keeper_reward", keeper_amount.to_string()),
+    ];
+    // a keeper paying itself is paid with a single transfer
+    if keeper == caller {
+        caller_amount += keeper_amount;
+        keeper_amount = Uint128::zero();
+    }
+    for (amount, recipient) in [(caller_amount, caller), (keeper_amount, keeper)] {
+        let (msg, _) = transfer_net_of_tax(deps.as_ref(), &base_asset_info, amount, recipient)?;
+        if let Some(msg) = msg {
+            response = response.add_message(msg);
+        }
+    }
+
+    if let Some(profit_split) = profit_split {
+        let (msg, treasury_amount) = transfer_net_of_tax(
+            deps.as_ref(),
+            &base_asset_info,
+            protocol_amount,
+            &profit_split.treasury_address,
+        )?;
+        if let Some(msg) = msg {
+            response = response.add_message(msg);
+        }
+        attributes.push(attr("protocol_profit", protocol_amount.to_string()));
+
+        let mut paid_community_fund_amount = Uint128::zero();
+        if !community_fund_amount.is_zero() {
+            let asset = AstroportAsset {
+                info: base_asset_info.clone(),
+                amount: community_fund_amount,
+            };
+            let asset = AstroportAsset {
+                amount: community_fund_amount - asset.compute_tax(&deps.querier)?,
+                ..asset
+            };
+            paid_community_fund_amount = asset.amount;
+            match &profit_split.whale_pair_address {
+                // the swapped amount is only known once the swap has run
+                Some(whale_pair_address) => {
+                    let return_amount =
+                        simulate(&deps.querier, whale_pair_address.clone(), &asset)?.return_amount;
+                    let belief_price = if return_amount.is_zero() {
+                        None
+                    } else {
+                        Some(Decimal::from_ratio(asset.amount, return_amount))
+                    };
+                    response = response.add_submessage(SubMsg::reply_on_success(
+                        create_astroport_swap_msg(
+                            asset,
+                            whale_pair_address.clone(),
+                            belief_price,
+                            Some(state.slippage_tolerance),
+                        )?,
+                        REPLY_BURN_WHALE,
+                    ));
+                }
+                None => {
+                    response = response.add_message(create_transfer_msg(
+                        asset,
+                        &profit_split.community_fund_address,
+                    )?);
+                }
+            }
+        }
+        attributes.push(attr(
+            "community_fund_profit",
+            community_fund_amount.to_string(),
+        ));
+
+        // the revenue records what was actually paid, net of tax
+        let mut revenue = PROTOCOL_REVENUE.may_load(deps.storage)?.unwrap_or_default();
+        revenue.treasury_amount += treasury_amount;
+        revenue.community_fund_amount += paid_community_fund_amount;
+        PROTOCOL_REVENUE.save(deps.storage, &revenue)?;
+    }
+
+    Ok(response.add_attributes(attributes))
+}
+
+/// ## Description
+/// Sends the WHALE bought with the community fund share to the community fund and burns it.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **msg** is an object of type [`Reply`] which is the reply of the WHALE swap.
+pub fn reply_burn_whale(
+    deps: DepsMut,
+    msg: Reply,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    let profit_split = PROFIT_SPLIT.load(deps.storage)?;
+    let result = msg.result.into_result().map_err(StdError::generic_err)?;
+    let amount = find_event_amount(&result.events, "return_amount");
+
+    let config: CommunityFundConfigResponse = deps.querier.query_wasm_smart(
+        profit_split.community_fund_address.clone(),
+        &CommunityFundQueryMsg::Config {},
+    )?;
+    let whale = AstroportAsset {
+        info: AstroportAssetInfo::Token {
+            contract_addr: config.token_addr,
+        },
+        amount,
+    };
+
+    let mut revenue = PROTOCOL_REVENUE.may_load(deps.storage)?.unwrap_or_default();
+    revenue.whale_burned += amount;
+    PROTOCOL_REVENUE.save(deps.storage, &revenue)?;
+
+    Ok(Response::new()
+        .add_message(create_transfer_msg(
+            whale,
+            &profit_split.community_fund_address,
+        )?)
+        .add_message(CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: profit_split.community_fund_address.to_string(),
+            msg: to_binary(&CommunityFundExecuteMsg::Burn { amount })?,
+            funds: vec![],
+        }))
+        .add_attribute("whale_burned", amount.to_string()))
+}
+
+/// Returns the transfer of `amount` with its tax deducted, none when nothing is left to send,
+/// and the amount received.
+fn transfer_net_of_tax(
+    deps: Deps,
+    asset_info: &AstroportAssetInfo,
+    amount: Uint128,
+    recipient: &Addr,
+) -> StdResult<(Option<CosmosMsg<TerraMsgWrapper>>, Uint128)> {
+    if amount.is_zero() {
+        return Ok((None, Uint128::zero()));
+    }
+    let asset = AstroportAsset {
+        info: asset_info.clone(),
+        amount,
+    };
+    let asset = AstroportAsset {
+        amount: amount - asset.compute_tax(&deps.querier)?,
+        ..asset
+    };
+    let amount = asset.amount;
+    Ok((Some(create_transfer_msg(asset, recipient)?), amount))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::execute;
+    use crate::msg::ExecuteMsg;
+    use crate::state::STATE;
+    use crate::testing::{mock_dependencies, mock_instantiate, WasmMockQuerier, OWNER};
+    use cosmwasm_std::testing::{mock_env, mock_info, MockApi, MockStorage};
+    use cosmwasm_std::{coin, BankMsg, OwnedDeps};
+
+    fn share(percent: u64) -> Fee {
+        Fee {
+            share: Decimal::percent(percent),
+        }
+    }
+
+    fn update_keeper_reward(
+        deps: DepsMut,
+        bounty: KeeperBounty,
+        funded_by_protocol: bool,
+    ) -> Result<Response<TerraMsgWrapper>, ContractError> {
+        execute(
+            deps,
+            mock_env(),
+            mock_info(OWNER, &[]),
+            ExecuteMsg::UpdateKeeperReward {
+                keeper_reward: Some(KeeperReward {
+                    bounty,
+                    funded_by_protocol,
+                }),
+            },
+        )
+    }
+
+    /// Pays 50% of the profit to the caller, 30% to the treasury and 20% to the community fund.
+    fn setup() -> OwnedDeps<MockStorage, MockApi, WasmMockQuerier> {
+        let mut deps = mock_dependencies(&[]);
+        mock_instantiate(deps.as_mut());
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            ExecuteMsg::UpdateProfitSplit {
+                caller_fee: share(50),
+                protocol_fee: share(30),
+                treasury_address: "treasury".to_string(),
+                community_fund_fee: share(20),
+                community_fund_address: "community_fund".to_string(),
+                whale_pair_address: None,
+            },
+        )
+        .unwrap();
+        deps
+    }
+
+    fn distribute(deps: DepsMut, profit: u128) -> Response<TerraMsgWrapper> {
+        let state = STATE.load(deps.storage).unwrap();
+        distribute_profit(
+            deps,
+            &state,
+            Uint128::new(profit),
+            &Addr::unchecked("user"),
+            &Addr::unchecked("keeper"),
+        )
+        .unwrap()
+    }
+
+    fn attribute(response: &Response<TerraMsgWrapper>, key: &str) -> String {
+        response
+            .attributes
+            .iter()
+            .find(|attribute| attribute.key == key)
+            .unwrap()
+            .value
+            .clone()
+    }
+
+    fn sent_to(response: &Response<TerraMsgWrapper>, recipient: &str) -> u128 {
+        response
+            .messages
+            .iter()
+            .filter_map(|msg| match &msg.msg {
+                CosmosMsg::Bank(BankMsg::Send { to_address, amount })
+                    if to_address == recipient =>
+                {
+                    Some(amount[0].amount.u128())
+                }
+                _ => None,
+            })
+            .sum()
+    }
+
+    #[test]
+    fn fixed_bounty_is_taken_from_the_caller_share() {
+        let mut deps = setup();
+        update_keeper_reward(
+            deps.as_mut(),
+            KeeperBounty::Fixed {
+                amount: Uint128::new(100),
+            },
+            false,
+        )
+        .unwrap();
+
+        let response = distribute(deps.as_mut(), 1_000);
+        assert_eq!(attribute(&response, "caller_profit"), "400");
+        assert_eq!(attribute(&response, "keeper_reward"), "100");
+        assert_eq!(sent_to(&response, "user"), 400);
+        assert_eq!(sent_to(&response, "keeper"), 100);
+        assert_eq!(sent_to(&response, "treasury"), 300);
+        assert_eq!(sent_to(&response, "community_fund"), 200);
+    }
+
+    #[test]
+    fn share_bounty_is_taken_from_the_protocol_share() {
+        let mut deps = setup();
+        update_keeper_reward(deps.as_mut(), KeeperBounty::Share { fee: share(10) }, true).unwrap();
+
+        let response = distribute(deps.as_mut(), 1_000);
+        assert_eq!(attribute(&response, "keeper_reward"), "100");
+        assert_eq!(attribute(&response, "protocol_profit"), "200");
+        assert_eq!(sent_to(&response, "user"), 500);
+        assert_eq!(sent_to(&response, "keeper"), 100);
+        assert_eq!(sent_to(&response, "treasury"), 200);
+
+        let revenue = PROTOCOL_REVENUE.load(deps.as_ref().storage).unwrap();
+        assert_eq!(revenue.treasury_amount, Uint128::new(200));
+        assert_eq!(revenue.community_fund_amount, Uint128::new(200));
+    }
+
+    #[test]
+    fn bounty_is_capped_by_its_funding_share() {
+        let mut deps = setup();
+        update_keeper_reward(
+            deps.as_mut(),
+            KeeperBounty::Fixed {
+                amount: Uint128::new(10_000),
+            },
+            true,
+        )
+        .unwrap();
+
+        let response = distribute(deps.as_mut(), 1_000);
+        assert_eq!(attribute(&response, "keeper_reward"), "300");
+        assert_eq!(attribute(&response, "protocol_profit"), "0");
+        assert_eq!(sent_to(&response, "user"), 500);
+        assert_eq!(sent_to(&response, "keeper"), 300);
+        assert_eq!(sent_to(&response, "treasury"), 0);
+    }
+
+    #[test]
+    fn keeper_paying_itself_gets_a_single_transfer() {
+        let mut deps = setup();
+        update_keeper_reward(deps.as_mut(), KeeperBounty::Share { fee: share(10) }, false).unwrap();
+        let state = STATE.load(deps.as_ref().storage).unwrap();
+
+        let keeper = Addr::unchecked("keeper");
+        let response =
+            distribute_profit(deps.as_mut(), &state, Uint128::new(1_000), &keeper, &keeper)
+                .unwrap();
+        assert_eq!(
+            response.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "keeper".to_string(),
+                amount: vec![coin(500, "uusd")],
+            })
+        );
+        assert_eq!(sent_to(&response, "keeper"), 500);
+    }
+
+    #[test]
+    fn protocol_funded_bounty_needs_a_profit_split() {
+        let mut deps = mock_dependencies(&[]);
+        mock_instantiate(deps.as_mut());
+
+        let result = update_keeper_reward(
+            deps.as_mut(),
+            KeeperBounty::Fixed {
+                amount: Uint128::new(100),
+            },
+            true,
+        );
+        assert!(matches!(result, Err(ContractError::ProfitSplitNotSet {})));
+        assert!(KEEPER_REWARD
+            .may_load(deps.as_ref().storage)
+            .unwrap()
+            .is_none());
+
+        // without a split everything goes to the caller, who funds the keeper
+        update_keeper_reward(
+            deps.as_mut(),
+            KeeperBounty::Fixed {
+                amount: Uint128::new(100),
+            },
+            false,
+        )
+        .unwrap();
+        let response = distribute(deps.as_mut(), 1_000);
+        assert_eq!(sent_to(&response, "user"), 900);
+        assert_eq!(sent_to(&response, "keeper"), 100);
+
+        let result = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("keeper", &[]),
+            ExecuteMsg::UpdateKeeperReward {
+                keeper_reward: None,
+            },
+        );
+        assert!(matches!(result, Err(ContractError::Admin(_))));
+    }
+    #[test]
+    fn profit_split_must_share_the_whole_profit() {
+        let mut deps = setup();
+        let update = |caller: u64, sender: &str| {
+            (
+                mock_info(sender, &[]),
+                ExecuteMsg::UpdateProfitSplit {
+                    caller_fee: share(caller),
+                    protocol_fee: share(30),
+                    treasury_address: "new_treasury".to_string(),
+                    community_fund_fee: share(20),
+                    community_fund_address: "community_fund".to_string(),
+                    whale_pair_address: Some("whale_pair".to_string()),
+                },
+            )
+        };
+
+        for caller in [40, 60] {
+            let (info, msg) = update(caller, OWNER);
+            assert!(matches!(
+                execute(deps.as_mut(), mock_env(), info, msg),
+                Err(ContractError::InvalidProfitSplit {})
+            ));
+        }
+        let (info, msg) = update(50, "anyone");
+        assert!(matches!(
+            execute(deps.as_mut(), mock_env(), info, msg),
+            Err(ContractError::Admin(_))
+        ));
+        assert_eq!(
+            PROFIT_SPLIT.load(&deps.storage).unwrap().treasury_address,
+            Addr::unchecked("treasury")
+        );
+
+        let (info, msg) = update(50, OWNER);
+        execute(deps.as_mut(), mock_env(), info, msg).unwrap();
+        let profit_split = PROFIT_SPLIT.load(&deps.storage).unwrap();
+        assert_eq!(
+            profit_split.treasury_address,
+            Addr::unchecked("new_treasury")
+        );
+        assert_eq!(
+            profit_split.whale_pair_address,
+            Some(Addr::unchecked("whale_pair"))
+        );
+    }
+}
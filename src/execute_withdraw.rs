@@ -0,0 +1,208 @@
+use cosmwasm_std::{BankMsg, CosmosMsg, DepsMut, Env, MessageInfo, Response, Uint128};
+use terra_cosmwasm::TerraMsgWrapper;
+
+use crate::error::ContractError;
+use crate::state::ADMIN;
+use crate::utils::create_transfer_msg;
+
+use astroport::asset::{Asset as AstroportAsset, AssetInfo as AstroportAssetInfo};
+use white_whale::tax::deduct_tax;
+
+/// ## Description
+/// Sends the whole contract balance of a native token, net of tax. Returns a [`ContractError`] on failure.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **env** is an object of type [`Env`].
+///
+/// - **info** is an object of type [`MessageInfo`].
+///
+/// - **denom** is an object of type [`String`] which is the native token to withdraw.
+///
+/// - **send_to** is an object of type [`String`] which is the recipient address.
+///
+/// ## Executor
+/// Only the owner can execute this.
+pub fn try_withdraw_native(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    denom: String,
+    send_to: String,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    let send_to = deps.api.addr_validate(send_to.as_str())?;
+    let balance = deps.querier.query_balance(env.contract.address, denom)?;
+    if balance.amount.is_zero() {
+        return Err(ContractError::NothingToWithdraw {});
+    }
+    let coin = deduct_tax(deps.as_ref(), balance)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "withdraw_native")
+        .add_attribute("denom", coin.denom.clone())
+        .add_attribute("amount", coin.amount.to_string())
+        .add_attribute("send_to", send_to.to_string())
+        .add_message(CosmosMsg::Bank(BankMsg::Send {
+            to_address: send_to.to_string(),
+            amount: vec![coin],
+        })))
+}
+
+/// ## Description
+/// Sends a cw20 token held by the contract. Returns a [`ContractError`] on failure.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **env** is an object of type [`Env`].
+///
+/// - **info** is an object of type [`MessageInfo`].
+///
+/// - **token** is an object of type [`String`] which is the cw20 token to withdraw.
+///
+/// - **send_to** is an object of type [`String`] which is the recipient address.
+///
+/// - **amount** is an object of type [`Option<Uint128>`] which is the whole balance if none.
+///
+/// ## Executor
+/// Only the owner can execute this.
+pub fn try_withdraw_token(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    token: String,
+    send_to: String,
+    amount: Option<Uint128>,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    let send_to = deps.api.addr_validate(send_to.as_str())?;
+    let info = AstroportAssetInfo::Token {
+        contract_addr: deps.api.addr_validate(token.as_str())?,
+    };
+    let amount = match amount {
+        Some(amount) => amount,
+        None => info.query_pool(&deps.querier, env.contract.address)?,
+    };
+    if amount.is_zero() {
+        return Err(ContractError::NothingToWithdraw {});
+    }
+
+    Ok(Response::new()
+        .add_attribute("action", "withdraw_token")
+        .add_attribute("token", info.to_string())
+        .add_attribute("amount", amount.to_string())
+        .add_attribute("send_to", send_to.to_string())
+        .add_message(create_transfer_msg(
+            AstroportAsset { info, amount },
+            &send_to,
+        )?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::execute;
+    use crate::msg::ExecuteMsg;
+    use crate::testing::{mock_dependencies, mock_instantiate, OWNER};
+    use cosmwasm_std::testing::{mock_env, mock_info};
+    use cosmwasm_std::{coin, to_binary, Decimal, WasmMsg};
+    use cw20::Cw20ExecuteMsg;
+
+    fn withdraw_native(
+        deps: DepsMut,
+        sender: &str,
+    ) -> Result<Response<TerraMsgWrapper>, ContractError> {
+        execute(
+            deps,
+            mock_env(),
+            mock_info(sender, &[]),
+            ExecuteMsg::WithdrawNative {
+                denom: "uusd".to_string(),
+                send_to: "treasury".to_string(),
+            },
+        )
+    }
+
+    fn withdraw_token(
+        deps: DepsMut,
+        sender: &str,
+        amount: Option<Uint128>,
+    ) -> Result<Response<TerraMsgWrapper>, ContractError> {
+        execute(
+            deps,
+            mock_env(),
+            mock_info(sender, &[]),
+            ExecuteMsg::WithdrawToken {
+                token: "token".to_string(),
+                send_to: "treasury".to_string(),
+                amount,
+            },
+        )
+    }
+
+    #[test]
+    fn native_withdrawal_sends_the_balance_net_of_tax() {
+        let mut deps = mock_dependencies(&[coin(1_001_000, "uusd")]);
+        deps.querier.with_tax(Decimal::permille(1), 1_000_000);
+        mock_instantiate(deps.as_mut());
+
+        let result = withdraw_native(deps.as_mut(), "keeper");
+        assert!(matches!(result, Err(ContractError::Admin(_))));
+
+        // 0.1% of the amount sent is left for the tax, rounded in favour of the tax
+        let response = withdraw_native(deps.as_mut(), OWNER).unwrap();
+        assert_eq!(
+            response.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "treasury".to_string(),
+                amount: vec![coin(999_999, "uusd")],
+            })
+        );
+
+        let result = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            ExecuteMsg::WithdrawNative {
+                denom: "uluna".to_string(),
+                send_to: "treasury".to_string(),
+            },
+        );
+        assert!(matches!(result, Err(ContractError::NothingToWithdraw {})));
+    }
+
+    #[test]
+    fn token_withdrawal_sends_the_amount_or_the_balance() {
+        let mut deps = mock_dependencies(&[]);
+        let contract_address = mock_env().contract.address;
+        deps.querier
+            .with_token_balance("token", contract_address.as_str(), 500);
+        mock_instantiate(deps.as_mut());
+        let transfer = |amount: u128| {
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: "token".to_string(),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: "treasury".to_string(),
+                    amount: Uint128::new(amount),
+                })
+                .unwrap(),
+                funds: vec![],
+            })
+        };
+
+        let result = withdraw_token(deps.as_mut(), "keeper", None);
+        assert!(matches!(result, Err(ContractError::Admin(_))));
+
+        let response = withdraw_token(deps.as_mut(), OWNER, None).unwrap();
+        assert_eq!(response.messages[0].msg, transfer(500));
+        let response = withdraw_token(deps.as_mut(), OWNER, Some(Uint128::new(200))).unwrap();
+        assert_eq!(response.messages[0].msg, transfer(200));
+
+        let result = withdraw_token(deps.as_mut(), OWNER, Some(Uint128::zero()));
+        assert!(matches!(result, Err(ContractError::NothingToWithdraw {})));
+    }
+}
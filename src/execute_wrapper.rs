@@ -0,0 +1,232 @@
+use cosmwasm_std::{DepsMut, MessageInfo, Response};
+use terra_cosmwasm::TerraMsgWrapper;
+
+use crate::error::ContractError;
+use crate::msg::WrapperAdapter;
+use crate::state::{ADMIN, WRAPPERS};
+use crate::wrapper::assert_lp_wrapper;
+
+/// ## Description
+/// Registers a wrapped asset, so that it can be minted from or burned to its underlying
+/// instead of swapped when it is cheaper. Returns a [`ContractError`] on failure.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **info** is an object of type [`MessageInfo`].
+///
+/// - **token** is an object of type [`String`] which is the wrapper token address.
+///
+/// - **adapter** is an object of type [`WrapperAdapter`].
+///
+/// ## Executor
+/// Only the owner can execute this.
+pub fn try_add_wrapper(
+    deps: DepsMut,
+    info: MessageInfo,
+    token: String,
+    adapter: WrapperAdapter,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    let token = deps.api.addr_validate(token.as_str())?;
+    if WRAPPERS.has(deps.storage, &token) {
+        return Err(ContractError::WrapperAlreadyRegistered {});
+    }
+
+    let adapter = match adapter {
+        WrapperAdapter::Anchor { market, denom } => WrapperAdapter::Anchor {
+            market: deps.api.addr_validate(market.as_str())?,
+            denom,
+        },
+        WrapperAdapter::BassetHub { hub, denom } => WrapperAdapter::BassetHub {
+            hub: deps.api.addr_validate(hub.as_str())?,
+            denom,
+        },
+        WrapperAdapter::AstroportLp { pair, underlying } => {
+            let pair = deps.api.addr_validate(pair.as_str())?;
+            // the token must be the LP of the pair, which must hold the underlying
+            if !assert_lp_wrapper(deps.as_ref(), &token, &pair, &underlying)? {
+                return Err(ContractError::InvalidWrapper {});
+            }
+            WrapperAdapter::AstroportLp { pair, underlying }
+        }
+    };
+    WRAPPERS.save(deps.storage, &token, &adapter)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "add_wrapper")
+        .add_attribute("token", token.to_string())
+        .add_attribute("adapter", adapter.to_string()))
+}
+
+/// ## Description
+/// Removes a wrapped asset from the registry, it is then only swapped.
+/// Returns a [`ContractError`] on failure.
+///
+/// ## Params
+/// - **deps** is an object of type [`DepsMut`].
+///
+/// - **info** is an object of type [`MessageInfo`].
+///
+/// - **token** is an object of type [`String`] which is the wrapper token address.
+///
+/// ## Executor
+/// Only the owner can execute this.
+pub fn try_remove_wrapper(
+    deps: DepsMut,
+    info: MessageInfo,
+    token: String,
+) -> Result<Response<TerraMsgWrapper>, ContractError> {
+    ADMIN.assert_admin(deps.as_ref(), &info.sender)?;
+
+    let token = deps.api.addr_validate(token.as_str())?;
+    if !WRAPPERS.has(deps.storage, &token) {
+        return Err(ContractError::WrapperNotRegistered {});
+    }
+    WRAPPERS.remove(deps.storage, &token);
+
+    Ok(Response::new()
+        .add_attribute("action", "remove_wrapper")
+        .add_attribute("token", token.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::execute;
+    use crate::msg::ExecuteMsg;
+    use crate::testing::{mock_cluster, mock_dependencies, mock_instantiate, OWNER};
+    use astroport::asset::AssetInfo as AstroportAssetInfo;
+    use cosmwasm_std::testing::{mock_env, mock_info};
+    use cosmwasm_std::Addr;
+
+    fn ust() -> AstroportAssetInfo {
+        AstroportAssetInfo::NativeToken {
+            denom: "uusd".to_string(),
+        }
+    }
+
+    #[test]
+    fn wrappers_are_added_and_removed() {
+        let mut deps = mock_dependencies(&[]);
+        mock_cluster(
+            &mut deps.querier,
+            "cluster",
+            1_000_000,
+            1_000_000,
+            1_000_000,
+            1_000_000,
+        );
+        mock_instantiate(deps.as_mut());
+        let add = |token: &str, adapter| ExecuteMsg::AddWrapper {
+            token: token.to_string(),
+            adapter,
+        };
+        let lp = |underlying| WrapperAdapter::AstroportLp {
+            pair: Addr::unchecked("cluster_pair"),
+            underlying,
+        };
+        let bluna = WrapperAdapter::BassetHub {
+            hub: Addr::unchecked("hub"),
+            denom: "uluna".to_string(),
+        };
+
+        assert!(matches!(
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info("anyone", &[]),
+                add("bluna", bluna.clone())
+            ),
+            Err(ContractError::Admin(_))
+        ));
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            add("bluna", bluna.clone()),
+        )
+        .unwrap();
+        assert_eq!(
+            WRAPPERS
+                .load(&deps.storage, &Addr::unchecked("bluna"))
+                .unwrap(),
+            bluna
+        );
+        // aUST is registered at instantiation
+        assert!(matches!(
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info(OWNER, &[]),
+                add(
+                    "aust",
+                    WrapperAdapter::Anchor {
+                        market: Addr::unchecked("anchor_market"),
+                        denom: "uusd".to_string(),
+                    }
+                )
+            ),
+            Err(ContractError::WrapperAlreadyRegistered {})
+        ));
+
+        // an LP wrapper must be the LP token of a pair holding its underlying
+        for (token, underlying) in [
+            ("other_lp", ust()),
+            (
+                "cluster_pair_lp",
+                AstroportAssetInfo::NativeToken {
+                    denom: "uluna".to_string(),
+                },
+            ),
+        ] {
+            assert!(matches!(
+                execute(
+                    deps.as_mut(),
+                    mock_env(),
+                    mock_info(OWNER, &[]),
+                    add(token, lp(underlying))
+                ),
+                Err(ContractError::InvalidWrapper {})
+            ));
+        }
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            add("cluster_pair_lp", lp(ust())),
+        )
+        .unwrap();
+
+        let remove = |token: &str| ExecuteMsg::RemoveWrapper {
+            token: token.to_string(),
+        };
+        assert!(matches!(
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info("anyone", &[]),
+                remove("bluna")
+            ),
+            Err(ContractError::Admin(_))
+        ));
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            remove("bluna"),
+        )
+        .unwrap();
+        assert!(!WRAPPERS.has(&deps.storage, &Addr::unchecked("bluna")));
+        assert!(matches!(
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info(OWNER, &[]),
+                remove("bluna")
+            ),
+            Err(ContractError::WrapperNotRegistered {})
+        ));
+    }
+}
@@ -4,9 +4,20 @@ pub mod state;
 pub mod utils;
 
 mod error;
+mod execute_access;
 mod execute_arb_create;
 mod execute_arb_redeem;
+mod execute_cluster;
 mod execute_flash_loan;
+mod execute_profit;
+mod execute_withdraw;
+mod execute_wrapper;
 mod query;
+mod simulation;
+mod stableswap;
+mod wrapper;
+
+#[cfg(test)]
+mod testing;
 
 pub use crate::error::ContractError;
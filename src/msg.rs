@@ -1,8 +1,15 @@
-use cosmwasm_std::{Addr, Decimal, Uint128};
+use cosmwasm_std::{Addr, Attribute, Decimal, Uint128};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
-use astroport::asset::Asset as AstroportAsset;
+use astroport::asset::{Asset as AstroportAsset, AssetInfo as AstroportAssetInfo};
+use cw0::Expiration;
+use terraswap::asset::AssetInfo;
+use white_whale::deposit_info::ArbBaseAsset;
+use white_whale::fee::Fee;
+
+use crate::state::{ArbitrageRecord, ArbitrageStats, OwnershipProposal};
 
 /// ## Description
 /// This structure stores the basic settings for creating a new contract.
@@ -12,9 +19,34 @@ pub struct InstantiateMsg {
     pub vault_address: String,
     pub incentive_address: String,
     pub astroport_factory_address: String,
+    pub astroport_router_address: String,
+    pub terraswap_factory_address: String,
     pub aust_token_address: String,
     pub anchor_market_contract: String,
     pub profit_threshold: Uint128,
+    /// Share of the simulated amounts that may be lost to slippage
+    pub slippage_tolerance: Decimal,
+    /// Asset borrowed from the vault, arbitraged against and paid out as profit
+    pub base_asset: AssetInfo,
+    /// Nebula cluster factory scanned for opportunities, the cluster registry if none
+    pub nebula_factory_address: Option<String>,
+}
+
+/// ## Description
+/// This structure describes the migration message of the contract. The settings are only
+/// needed by state stored before they existed.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct MigrateMsg {
+    pub astroport_router_address: Option<String>,
+    pub terraswap_factory_address: Option<String>,
+    /// Share of the simulated amounts that may be lost to slippage
+    pub slippage_tolerance: Option<Decimal>,
+    /// Asset borrowed from the vault, UST if none
+    pub base_asset: Option<AssetInfo>,
+    /// Clusters registered through the incentives contract with the configured profit threshold,
+    /// arbitrages are only allowed on registered clusters
+    pub clusters: Option<Vec<String>>,
 }
 
 /// ## Description
@@ -32,6 +64,17 @@ pub enum ExecuteMsg {
         cluster_address: String,
         /// Address to send profit to
         user_address: Option<String>,
+        /// Overrides the configured slippage tolerance
+        slippage_tolerance: Option<Decimal>,
+    },
+    /// Estimates every cluster and arbitrages the profitable ones under a single flash loan.
+    FlashLoanBatch {
+        /// Cluster contract addresses
+        clusters: Vec<String>,
+        /// Address to send profit to
+        user_address: Option<String>,
+        /// Overrides the configured slippage tolerance
+        slippage_tolerance: Option<Decimal>,
     },
     /// Executes arbitrage on Astroport to get CT and perform the redeem operation with flash loan amout.
     _CallbackRedeem {
@@ -39,12 +82,20 @@ pub enum ExecuteMsg {
         cluster_address: Addr,
         /// Address to send profit to
         user_address: Addr,
+        /// Address that triggered the arbitrage, paid the keeper reward
+        keeper_address: Addr,
         /// Flash loan amount
         loan_amount: Uint128,
         /// Cluster target
         target: Vec<AstroportAsset>,
         /// Threshold of arbitrage profit,
         profit_threshold: Uint128,
+        /// Slippage limits derived from the simulation
+        limits: SlippageLimits,
+        /// CT price on Astroport when the arbitrage was planned
+        market_price: Decimal,
+        /// CT price from the cluster inventory when the arbitrage was planned
+        intrinsic_price: Decimal,
     },
     /// Prepares assets for create cluster token.
     _CallbackCreate {
@@ -52,6 +103,8 @@ pub enum ExecuteMsg {
         cluster_address: Addr,
         /// Address to send profit to
         user_address: Addr,
+        /// Address that triggered the arbitrage, paid the keeper reward
+        keeper_address: Addr,
         /// Flash loan amount
         loan_amount: Uint128,
         /// Cluster asset target
@@ -60,42 +113,144 @@ pub enum ExecuteMsg {
         prices: Vec<String>,
         /// Threshold of arbitrage profit,
         profit_threshold: Uint128,
+        /// Slippage limits derived from the simulation
+        limits: SlippageLimits,
+        /// CT price on Astroport when the arbitrage was planned
+        market_price: Decimal,
+        /// CT price from the cluster inventory when the arbitrage was planned
+        intrinsic_price: Decimal,
     },
-    /// Sends all of profit to user
-    _UserProfit {
-        /// Address to send profit to
-        user_address: Addr,
-        /// Threshold of arbitrage profit,
-        profit_threshold: Uint128,
-    },
-    ///  Executes the create operation and uses CT to arbitrage on Astroport with all ralated assets in contract.
-    _ArbCreate {
-        /// Cluster contract address
-        cluster_address: Addr,
+    /// Executes every leg of a batch with the flash loan amount.
+    _CallbackBatch {
         /// Address to send profit to
         user_address: Addr,
-        /// Flash loan amount
+        /// Address that triggered the arbitrage, paid the keeper reward
+        keeper_address: Addr,
+        /// Total flash loan amount
         loan_amount: Uint128,
-        /// Cluster target
-        target: Vec<AstroportAsset>,
+        /// Arbitrage legs, one per cluster
+        legs: Vec<ArbitrageLeg>,
         /// Threshold of arbitrage profit,
         profit_threshold: Uint128,
     },
-    /// Swap token to UST from Astroport pool
-    _SwapToUstAndTakeProfit {
-        /// Address to send profit to
-        user_address: Addr,
-        /// Flash loan amount
-        loan_amount: Uint128,
-        /// Cluster target
-        target: Vec<AstroportAsset>,
-        /// Threshold of arbitrage profit,
-        profit_threshold: Uint128,
+
+    /////////////////////
+    /// GUARDIAN CALLABLE
+    /////////////////////
+    /// Stops every arbitrage, the owner or the guardian can pause.
+    Pause {},
+    /// Pauses a single cluster, the owner or the guardian can pause it.
+    PauseCluster {
+        /// Cluster contract address
+        cluster: String,
     },
 
     /////////////////////
     /// OWNER CALLABLE
     /////////////////////
+    /// Resumes the arbitrages, only the owner can unpause.
+    Unpause {},
+    /// Resumes the arbitrages of a paused cluster, only the owner can unpause it.
+    UnpauseCluster {
+        /// Cluster contract address
+        cluster: String,
+    },
+    /// Sets the address allowed to pause the contract, none removes it.
+    UpdateGuardian { guardian: Option<String> },
+    /// Registers a cluster that can be arbitraged.
+    AddCluster {
+        /// Cluster contract address
+        cluster_address: String,
+        /// Threshold of arbitrage profit, the configured one if none
+        profit_threshold: Option<Uint128>,
+        /// Maximum flash loan amount, unbounded if none
+        max_loan: Option<Uint128>,
+        /// How the cluster is minted and redeemed
+        strategy: ClusterStrategy,
+        /// Astroport CT pair used instead of the factory one
+        pair_address: Option<String>,
+        /// Minimum delay between two arbitrages
+        cooldown: Option<Cooldown>,
+    },
+    /// Updates the settings of a registered cluster.
+    UpdateCluster {
+        /// Cluster contract address
+        cluster_address: String,
+        /// Whether the cluster can be arbitraged
+        enabled: Option<bool>,
+        /// Threshold of arbitrage profit
+        profit_threshold: Option<Uint128>,
+        /// Maximum flash loan amount
+        max_loan: Option<Uint128>,
+        /// Removes the maximum flash loan amount, taking precedence over `max_loan`
+        clear_max_loan: Option<bool>,
+        /// How the cluster is minted and redeemed
+        strategy: Option<ClusterStrategy>,
+        /// Astroport CT pair used instead of the factory one
+        pair_address: Option<String>,
+        /// Goes back to the factory CT pair, taking precedence over `pair_address`
+        clear_pair_address: Option<bool>,
+        /// Minimum delay between two arbitrages
+        cooldown: Option<Cooldown>,
+        /// Removes the cooldown, taking precedence over `cooldown`
+        clear_cooldown: Option<bool>,
+    },
+    /// Removes a cluster from the registry.
+    RemoveCluster {
+        /// Cluster contract address
+        cluster_address: String,
+    },
+    /// Sets how the arbitrage profit is shared, the shares must add up to one.
+    UpdateProfitSplit {
+        /// Share of the profit sent to the caller
+        caller_fee: Fee,
+        /// Share of the profit sent to the treasury
+        protocol_fee: Fee,
+        /// Protocol treasury address
+        treasury_address: String,
+        /// Share of the profit sent to the community fund
+        community_fund_fee: Fee,
+        /// White Whale community fund contract address
+        community_fund_address: String,
+        /// Astroport pair of the base asset and WHALE, swaps and burns the community fund share
+        whale_pair_address: Option<String>,
+    },
+    /// Sets the reward paid to the keeper triggering an arbitrage, none removes it.
+    UpdateKeeperReward { keeper_reward: Option<KeeperReward> },
+    /// Sets who can trigger arbitrages.
+    UpdateAccessMode { access_mode: AccessMode },
+    /// Allows an address to trigger arbitrages in allowlist mode.
+    AddExecutor { address: String },
+    /// Removes an address from the executors.
+    RemoveExecutor { address: String },
+    /// Registers a wrapped basket asset that can be minted or burned instead of swapped.
+    AddWrapper {
+        /// Wrapper token address
+        token: String,
+        /// How the wrapper is minted and burned
+        adapter: WrapperAdapter,
+    },
+    /// Removes a wrapped asset from the registry.
+    RemoveWrapper {
+        /// Wrapper token address
+        token: String,
+    },
+    /// Sends the whole contract balance of a native token, net of tax.
+    WithdrawNative {
+        /// Native token denom
+        denom: String,
+        /// Recipient address
+        send_to: String,
+    },
+    /// Sends a cw20 token held by the contract.
+    WithdrawToken {
+        /// Token contract address
+        token: String,
+        /// Recipient address
+        send_to: String,
+        /// Amount to send, the whole balance if none
+        amount: Option<Uint128>,
+    },
     /// UpdateConfig updates contract setting.
     UpdateConfig {
         /// Whitewhale vault contract address
@@ -104,13 +259,175 @@ pub enum ExecuteMsg {
         incentive_address: Option<String>,
         /// Astroport factory contract address
         astroport_factory_address: Option<String>,
+        /// Astroport router contract address
+        astroport_router_address: Option<String>,
+        /// Terraswap factory contract address
+        terraswap_factory_address: Option<String>,
+        /// Nebula cluster factory contract address
+        nebula_factory_address: Option<String>,
         /// Threshold of arbitrage profit
         profit_threshold: Option<Uint128>,
-        /// Address to claim the contract ownership
-        owner_address: Option<String>,
+        /// Share of the simulated amounts that may be lost to slippage
+        slippage_tolerance: Option<Decimal>,
+    },
+    /// Proposes a new owner, who has to claim the ownership before it expires.
+    ProposeNewOwner {
+        /// Proposed owner address
+        owner: String,
+        /// When the proposal can no longer be claimed
+        expires: Expiration,
+    },
+    /// Removes the pending ownership proposal.
+    DropOwnershipProposal {},
+    /// Claims the ownership proposed to the sender.
+    ClaimOwnership {},
+}
+
+/// ## Description
+/// The direction of an arbitrage on a cluster.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ArbitrageDirection {
+    /// Buy CT from Astroport and redeem it
+    Redeem,
+    /// Mint CT and sell it on Astroport
+    Create,
+}
+
+/// ## Description
+/// Who can trigger arbitrages.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessMode {
+    /// Anyone
+    Permissionless,
+    /// Only the registered executors
+    Allowlist,
+}
+
+/// ## Description
+/// The minimum delay between two arbitrages on a cluster.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Cooldown {
+    Blocks(u64),
+    Seconds(u64),
+}
+
+/// ## Description
+/// The reward paid to the keeper triggering an arbitrage.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum KeeperBounty {
+    /// Fixed amount of the base asset, capped by the profit
+    Fixed { amount: Uint128 },
+    /// Share of the profit
+    Share { fee: Fee },
+}
+
+/// ## Description
+/// The keeper reward and who funds it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct KeeperReward {
+    pub bounty: KeeperBounty,
+    /// Takes the reward from the protocol share, capped by it, instead of the caller share
+    pub funded_by_protocol: bool,
+}
+
+/// ## Description
+/// How a cluster is minted and redeemed.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ClusterStrategy {
+    /// Through the Nebula incentives contract, which also swaps CT on Astroport
+    Incentives,
+    /// With the cluster's own mint and burn messages, swapping CT on Astroport from this contract
+    Direct,
+}
+
+/// ## Description
+/// How a wrapped basket asset is minted from and burned to its underlying asset.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum WrapperAdapter {
+    /// aUST deposited to and redeemed from the Anchor money market with its stable denom
+    Anchor { market: Addr, denom: String },
+    /// bLUNA bonded with the bAsset hub with its native denom. Unbonding is not instant so it
+    /// is only minted
+    BassetHub { hub: Addr, denom: String },
+    /// LP token of an Astroport xyk pair, provided and withdrawn from one side of the pair
+    AstroportLp {
+        pair: Addr,
+        underlying: AstroportAssetInfo,
     },
 }
 
+impl WrapperAdapter {
+    /// ## Description
+    /// Returns the asset the wrapper is minted from and burned to.
+    pub fn underlying(&self) -> AstroportAssetInfo {
+        match self {
+            WrapperAdapter::Anchor { denom, .. } | WrapperAdapter::BassetHub { denom, .. } => {
+                AstroportAssetInfo::NativeToken {
+                    denom: denom.clone(),
+                }
+            }
+            WrapperAdapter::AstroportLp { underlying, .. } => underlying.clone(),
+        }
+    }
+}
+
+impl fmt::Display for WrapperAdapter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WrapperAdapter::Anchor { .. } => write!(f, "anchor"),
+            WrapperAdapter::BassetHub { .. } => write!(f, "basset_hub"),
+            WrapperAdapter::AstroportLp { .. } => write!(f, "astroport_lp"),
+        }
+    }
+}
+
+/// ## Description
+/// A single cluster arbitrage executed as part of a batch.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct ArbitrageLeg {
+    /// Cluster contract address
+    pub cluster_address: Addr,
+    /// Arbitrage direction
+    pub direction: ArbitrageDirection,
+    /// Part of the flash loan used by this leg
+    pub loan_amount: Uint128,
+    /// Cluster asset target
+    pub target: Vec<AstroportAsset>,
+    /// Cluster asset price
+    pub prices: Vec<String>,
+    /// Slippage limits derived from the simulation
+    pub limits: SlippageLimits,
+    /// CT price on Astroport when the leg was planned
+    pub market_price: Decimal,
+    /// CT price from the cluster inventory when the leg was planned
+    pub intrinsic_price: Decimal,
+}
+
+/// ## Description
+/// The minimum outcomes of an arbitrage leg, derived from its simulation and a slippage tolerance.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct SlippageLimits {
+    /// Maximum spread accepted on the pool swaps
+    pub max_spread: Decimal,
+    /// Simulated price of the swap of each cluster asset, in the order of the target
+    pub belief_prices: Vec<Option<Decimal>>,
+    /// Minimum amount of each cluster asset bought for create
+    pub min_basket_amounts: Vec<Uint128>,
+    /// Minimum CT bought on Astroport for redeem or minted for create
+    pub min_cluster_token: Uint128,
+    /// Minimum base asset returned by the leg
+    pub min_base_returned: Uint128,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum IncentivesMsg {
@@ -134,12 +451,89 @@ pub enum IncentivesMsg {
     },
 }
 
+/// ## Description
+/// This structure describes the mint and burn messages of the cluster contract.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ClusterExecuteMsg {
+    /// Mints CT with the offered assets, pulling cw20 assets with allowances
+    RebalanceCreate {
+        /// assets offerred for minting
+        asset_amounts: Vec<AstroportAsset>,
+        /// minimum minted cluster tokens
+        min_tokens: Option<Uint128>,
+    },
+    /// Burns CT from the sender, with an allowance, for the cluster assets pro-rata
+    RebalanceRedeem {
+        /// maximum burned cluster tokens
+        max_tokens: Uint128,
+        /// assets to receive, pro-rata if none
+        asset_amounts: Option<Vec<AstroportAsset>>,
+    },
+}
+
 // Query Message
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum QueryMsg {
+    /// Returns the contract configuration and ownership.
+    Config {},
     UstVaultAddress {},
-    EstimateArbitrage { cluster_address: String },
+    EstimateArbitrage {
+        cluster_address: String,
+    },
+    /// Walks the arbitrage plan through pool simulations, using the optimal loan if none is given.
+    SimulateArbitrage {
+        cluster_address: String,
+        loan_amount: Option<Uint128>,
+    },
+    /// Estimates the registered clusters of a page, at most 3, and lists the profitable ones,
+    /// ranked by expected profit.
+    ListOpportunities {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// Lists the executed arbitrages, latest first.
+    History {
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// Lists the executed arbitrages of a cluster, latest first.
+    HistoryByCluster {
+        cluster_address: String,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// Lists the executed arbitrages paying a user, latest first.
+    HistoryByUser {
+        user_address: String,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// Returns the aggregates of the executed arbitrages, of a cluster or of all of them.
+    Stats {
+        cluster: Option<String>,
+    },
+    /// Returns the protocol revenue accumulated over all arbitrages.
+    ProtocolRevenue {},
+    /// Returns the access mode and lists the executors, ordered by address.
+    Executors {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// Lists the registered clusters, ordered by address.
+    Clusters {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// Lists the registered wrapped assets, ordered by token address.
+    Wrappers {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    /// Lists the contract holdings of the base asset, aUST and the assets of the clusters of the
+    /// last 10 arbitrages.
+    Balances {},
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -149,6 +543,137 @@ pub struct UstVaultAddressResponse {
     pub vault_address: Addr,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct ClusterResponse {
+    /// Cluster contract address
+    pub cluster_address: Addr,
+    /// Whether the cluster can be arbitraged
+    pub enabled: bool,
+    /// Whether the cluster is paused by the owner or the guardian
+    pub paused: bool,
+    /// Threshold of arbitrage profit
+    pub profit_threshold: Uint128,
+    /// Maximum flash loan amount, unbounded if none
+    pub max_loan: Option<Uint128>,
+    /// How the cluster is minted and redeemed
+    pub strategy: ClusterStrategy,
+    /// Astroport CT pair used instead of the factory one
+    pub pair_address: Option<Addr>,
+    /// Minimum delay between two arbitrages
+    pub cooldown: Option<Cooldown>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct ConfigResponse {
+    /// Contract owner
+    pub owner: Option<Addr>,
+    /// Ownership transfer waiting to be claimed
+    pub ownership_proposal: Option<OwnershipProposal>,
+    pub vault_address: Addr,
+    pub incentive_address: Addr,
+    pub astroport_factory_address: Addr,
+    pub astroport_router_address: Addr,
+    pub terraswap_factory_address: Addr,
+    pub aust_token_address: Addr,
+    pub anchor_market_contract: Addr,
+    pub nebula_factory_address: Option<Addr>,
+    pub profit_threshold: Uint128,
+    pub slippage_tolerance: Decimal,
+    pub base_asset: ArbBaseAsset,
+    pub access_mode: AccessMode,
+    /// Whether every arbitrage is stopped
+    pub paused: bool,
+    /// Address allowed to pause the contract
+    pub guardian: Option<Addr>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct WrapperResponse {
+    pub token: Addr,
+    pub underlying: AstroportAssetInfo,
+    pub adapter: WrapperAdapter,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct WrappersResponse {
+    pub wrappers: Vec<WrapperResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct BalancesResponse {
+    /// Non zero holdings of the contract
+    pub balances: Vec<AstroportAsset>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct ExecutorsResponse {
+    pub access_mode: AccessMode,
+    pub executors: Vec<Addr>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct ClustersResponse {
+    pub clusters: Vec<ClusterResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct HistoryResponse {
+    /// Arbitrage records, latest first
+    pub records: Vec<ArbitrageRecord>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct StatsResponse {
+    /// Cluster the stats are about, all clusters if none
+    pub cluster: Option<Addr>,
+    pub stats: ArbitrageStats,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct ProtocolRevenueResponse {
+    /// Asset the revenue is accounted in
+    pub asset_info: AstroportAssetInfo,
+    /// Base asset sent to the treasury
+    pub treasury_amount: Uint128,
+    /// Base asset given to the community fund, including the part swapped to WHALE
+    pub community_fund_amount: Uint128,
+    /// WHALE burned through the community fund
+    pub whale_burned: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct OpportunityResponse {
+    /// Cluster contract address
+    pub cluster_address: Addr,
+    pub direction: ArbitrageDirection,
+    /// Flash loan amount in the base asset
+    pub loan_amount: Uint128,
+    /// Expected profit net of fees and taxes, in the base asset
+    pub expected_profit: Uint128,
+    pub market_price: Decimal,
+    pub intrinsic_price: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct ListOpportunitiesResponse {
+    /// Profitable clusters of the page, most profitable first
+    pub opportunities: Vec<OpportunityResponse>,
+    /// Last cluster scanned, to be given as `start_after` for the next page
+    pub last_cluster: Option<Addr>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub struct EstimateArbitrageResponse {
@@ -156,8 +681,16 @@ pub struct EstimateArbitrageResponse {
     pub market_price: Decimal,
     /// Intrinsic price
     pub intrinsic_price: Decimal,
+    /// Price paid per CT minted, including the imbalance penalty or bonus
+    pub create_price: Decimal,
+    /// Value received per CT redeemed, including the imbalance penalty
+    pub redeem_price: Decimal,
+    /// Profitable direction at the effective prices
+    pub direction: ArbitrageDirection,
     /// Estimate cost to arbitrage
     pub arbitrage_cost: Uint128,
+    /// Expected net profit in the base asset after commissions, slippage and taxes
+    pub expected_profit: Uint128,
     /// Current inventory / asset balances
     pub inv: Vec<Uint128>,
     /// The current asset target weights
@@ -166,6 +699,38 @@ pub struct EstimateArbitrageResponse {
     pub prices: Vec<String>,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct SimulatedSwap {
+    /// Asset sent to the swap, mint or redeem
+    pub offer_asset: AstroportAsset,
+    /// Asset returned
+    pub return_asset: AstroportAsset,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct SimulateArbitrageResponse {
+    /// Arbitrage direction
+    pub direction: ArbitrageDirection,
+    /// Flash loan amount
+    pub loan_amount: Uint128,
+    /// Base asset swapped into each asset for create, or each redeemed asset swapped to the base asset for redeem
+    pub basket_swaps: Vec<SimulatedSwap>,
+    /// CT bought on Astroport for redeem, or CT minted and sold on Astroport for create
+    pub cluster_token_swap: SimulatedSwap,
+    /// Base asset recovered at the end of the arbitrage
+    pub base_recovered: Uint128,
+    /// Flash loan fee charged by the vault
+    pub flash_loan_fee: Uint128,
+    /// Terra tax paid on the loan, the repayment and the native funds sent by the legs
+    pub tax: Uint128,
+    /// Base asset left after repaying the loan, fee and taxes
+    pub net_profit: Uint128,
+    /// Base asset missing to repay the loan, fee and taxes
+    pub net_loss: Uint128,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct ClusterStateResponse {
     /// The current total supply of the cluster token
@@ -186,6 +751,52 @@ pub struct ClusterStateResponse {
     pub active: bool,
 }
 
+/// ## Description
+/// This structure describes the query messages of the cluster penalty contract.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PenaltyQueryMsg {
+    /// Mint returns the CT minted for the given assets, with the imbalance penalty or bonus.
+    Mint {
+        block_height: u64,
+        cluster_token_supply: Uint128,
+        inventory: Vec<Uint128>,
+        mint_asset_amounts: Vec<Uint128>,
+        asset_prices: Vec<String>,
+        target_weights: Vec<Uint128>,
+    },
+    /// Redeem returns the assets received and the CT burned, with the imbalance penalty.
+    Redeem {
+        block_height: u64,
+        cluster_token_supply: Uint128,
+        inventory: Vec<Uint128>,
+        max_tokens: Uint128,
+        redeem_asset_amounts: Vec<Uint128>,
+        asset_prices: Vec<String>,
+        target_weights: Vec<Uint128>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PenaltyCreateResponse {
+    /// CT minted
+    pub create_tokens: Uint128,
+    /// Imbalance penalty
+    pub penalty: Uint128,
+    pub attributes: Vec<Attribute>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PenaltyRedeemResponse {
+    /// Assets received, in the order of the target
+    pub redeem_assets: Vec<Uint128>,
+    /// Imbalance penalty
+    pub penalty: Uint128,
+    /// CT burned
+    pub token_cost: Uint128,
+    pub attributes: Vec<Attribute>,
+}
+
 /// ## Description
 /// This structure describes the available query messages for the cluster contract.
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -194,3 +805,46 @@ pub enum QueryMsgNebula {
     /// ClusterState returns the current cluster state.
     ClusterState {},
 }
+
+/// ## Description
+/// This structure describes the query messages of the Nebula cluster factory.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ClusterFactoryQueryMsg {
+    /// ClusterList returns every cluster created by the factory.
+    ClusterList {},
+}
+
+/// ## Description
+/// This structure describes the response of the cluster factory `ClusterList` query.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ClusterListResponse {
+    /// Cluster contract addresses and whether they are active
+    pub contract_infos: Vec<(String, bool)>,
+}
+
+/// ## Description
+/// This structure describes the execute messages of the bAsset hub.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BassetHubExecuteMsg {
+    /// Bond mints bLUNA for the sent LUNA.
+    Bond {},
+}
+
+/// ## Description
+/// This structure describes the query messages of the bAsset hub.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BassetHubQueryMsg {
+    /// State returns the hub exchange rates.
+    State {},
+}
+
+/// ## Description
+/// This structure describes the response of the bAsset hub `State` query.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BassetHubStateResponse {
+    /// LUNA value of one bLUNA
+    pub bluna_exchange_rate: Decimal,
+}
@@ -1,17 +1,57 @@
-use cosmwasm_std::{Decimal, Deps, StdResult, Uint128};
+use cosmwasm_std::{Addr, Deps, Env, Order, StdResult, Uint128};
+use cw_storage_plus::Bound;
 
-use crate::msg::EstimateArbitrageResponse;
-use crate::state::{State, STATE};
-use crate::utils::get_cluster_state;
+use crate::msg::{
+    BalancesResponse, ClusterFactoryQueryMsg, ClusterListResponse, ClusterResponse,
+    ClustersResponse, ConfigResponse, EstimateArbitrageResponse, ExecutorsResponse,
+    HistoryResponse, ListOpportunitiesResponse, OpportunityResponse, ProtocolRevenueResponse,
+    SimulateArbitrageResponse, StatsResponse, WrapperResponse, WrappersResponse,
+};
+use crate::simulation::{optimize_loan, simulate_arbitrage, ArbitrageContext};
+use crate::state::{
+    history, State, ADMIN, CLUSTERS, CLUSTER_STATS, EXECUTORS, GLOBAL_STATS, GUARDIAN,
+    OWNERSHIP_PROPOSAL, PAUSED, PAUSED_CLUSTERS, PROTOCOL_REVENUE, STATE, WRAPPERS,
+};
+use crate::utils::{get_cluster_state, query_flash_loan_fee};
 
-use astroport::asset::AssetInfo as AstroportAssetInfo;
-use astroport::querier::query_pair_info;
+use astroport::asset::{Asset as AstroportAsset, AssetInfo as AstroportAssetInfo};
 
-use std::str::FromStr;
+// settings for pagination
+const MAX_LIMIT: u32 = 30;
+const DEFAULT_LIMIT: u32 = 10;
+// every scanned cluster runs the loan search, about 250 queries for a single asset cluster
+// and more per asset, keep the pages within the query gas limit
+const MAX_OPPORTUNITY_LIMIT: u32 = 3;
+const DEFAULT_OPPORTUNITY_LIMIT: u32 = 3;
+// arbitrages whose clusters are looked at for balances, bounding the query gas
+const BALANCES_HISTORY_WINDOW: usize = 10;
 
-const MULTIPLIER: Uint128 = Uint128::new(10_000u128);
-// MULTIPLIER_3 = MULTIPLIER * MULTIPLIER * MULTIPLIER
-const MULTIPLIER_3: Uint128 = Uint128::new(1_000_000_000_000u128);
+/// ## Description
+/// Query the contract configuration, its owner and the pending ownership proposal.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+pub fn query_config(deps: Deps) -> StdResult<ConfigResponse> {
+    let state = STATE.load(deps.storage)?;
+    Ok(ConfigResponse {
+        owner: ADMIN.get(deps)?,
+        ownership_proposal: OWNERSHIP_PROPOSAL.may_load(deps.storage)?,
+        vault_address: state.vault_address,
+        incentive_address: state.incentive_address,
+        astroport_factory_address: state.astroport_factory_address,
+        astroport_router_address: state.astroport_router_address,
+        terraswap_factory_address: state.terraswap_factory_address,
+        aust_token_address: state.aust_token_address,
+        anchor_market_contract: state.anchor_market_contract,
+        nebula_factory_address: state.nebula_factory_address,
+        profit_threshold: state.profit_threshold,
+        slippage_tolerance: state.slippage_tolerance,
+        base_asset: state.base_asset,
+        access_mode: state.access_mode,
+        paused: PAUSED.may_load(deps.storage)?.unwrap_or(false),
+        guardian: GUARDIAN.may_load(deps.storage)?,
+    })
+}
 
 /// ## Description
 /// Query estimate arbitrage amount.
@@ -19,88 +59,881 @@ const MULTIPLIER_3: Uint128 = Uint128::new(1_000_000_000_000u128);
 /// ## Params
 /// - **deps** is an object of type [`DepsMut`].
 ///
+/// - **env** is an object of type [`Env`].
+///
 /// - **cluster_address_raw** is an object of type [`String`].
 pub fn query_estimate_arbitrage(
     deps: Deps,
+    env: Env,
     cluster_address_raw: String,
 ) -> StdResult<EstimateArbitrageResponse> {
     let state = STATE.load(deps.storage)?;
-    estimate_arbitrage(deps, cluster_address_raw, &state)
+    estimate_arbitrage(deps, &env, cluster_address_raw, &state)
 }
 
 /// ## Description
-/// Calculates arbitrage information
+/// Query a simulation of the arbitrage with a per-leg breakdown.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **env** is an object of type [`Env`].
+///
+/// - **cluster_address_raw** is an object of type [`String`].
+///
+/// - **loan_amount** is an object of type [`Option<Uint128>`] which defaults to the estimated loan.
+pub fn query_simulate_arbitrage(
+    deps: Deps,
+    env: Env,
+    cluster_address_raw: String,
+    loan_amount: Option<Uint128>,
+) -> StdResult<SimulateArbitrageResponse> {
+    let state = STATE.load(deps.storage)?;
+    let cluster_address = deps.api.addr_validate(cluster_address_raw.as_str())?;
+    let cluster_state = get_cluster_state(deps, &cluster_address)?;
+    let context = ArbitrageContext::new(deps, &env, &state, cluster_state)?;
+    let direction = context.direction()?;
+
+    let loan_amount = match loan_amount {
+        Some(loan_amount) => loan_amount,
+        None => optimize_loan(deps, &state, &context, &direction)?.0,
+    };
+    let flash_loan_fee = query_flash_loan_fee(&deps.querier, &state.vault_address)?;
+
+    simulate_arbitrage(
+        deps,
+        &state,
+        &context,
+        &direction,
+        loan_amount,
+        &flash_loan_fee,
+    )
+}
+
+/// ## Description
+/// Query the arbitrage opportunities of a page of clusters, taken from the Nebula cluster
+/// factory when it is configured, otherwise from the registry. Clusters that are not registered,
+/// as they cannot be flash loaned, inactive, disabled or paused clusters, clusters that fail to
+/// estimate and clusters below their profit threshold are left out, the others are ranked by
+/// expected profit.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **env** is an object of type [`Env`].
+///
+/// - **start_after** is an object of type [`Option<String>`] which is the last cluster of the previous page.
+///
+/// - **limit** is an object of type [`Option<u32>`] which is the number of clusters scanned.
+pub fn query_list_opportunities(
+    deps: Deps,
+    env: Env,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<ListOpportunitiesResponse> {
+    let state = STATE.load(deps.storage)?;
+    let limit = limit
+        .unwrap_or(DEFAULT_OPPORTUNITY_LIMIT)
+        .min(MAX_OPPORTUNITY_LIMIT) as usize;
+    let start_after = start_after
+        .map(|start_after| deps.api.addr_validate(start_after.as_str()))
+        .transpose()?;
+
+    // clusters of the page and whether they can be arbitraged
+    let clusters: Vec<(Addr, bool)> = match &state.nebula_factory_address {
+        Some(factory) => {
+            let response: ClusterListResponse = deps
+                .querier
+                .query_wasm_smart(factory, &ClusterFactoryQueryMsg::ClusterList {})?;
+            let mut clusters = response
+                .contract_infos
+                .into_iter()
+                .map(|(address, active)| Ok((deps.api.addr_validate(address.as_str())?, active)))
+                .collect::<StdResult<Vec<(Addr, bool)>>>()?;
+            clusters.sort_by(|a, b| a.0.cmp(&b.0));
+            clusters
+                .into_iter()
+                .filter(|(address, _)| !matches!(&start_after, Some(start) if address <= start))
+                .take(limit)
+                .collect()
+        }
+        None => CLUSTERS
+            .range(
+                deps.storage,
+                start_after
+                    .as_ref()
+                    .map(|start_after| Bound::exclusive(start_after.as_str())),
+                None,
+                Order::Ascending,
+            )
+            .take(limit)
+            .map(|item| {
+                let (key, cluster) = item?;
+                Ok((
+                    Addr::unchecked(String::from_utf8_lossy(&key)),
+                    cluster.enabled,
+                ))
+            })
+            .collect::<StdResult<Vec<(Addr, bool)>>>()?,
+    };
+    let last_cluster = clusters.last().map(|(address, _)| address.clone());
+
+    let mut opportunities = vec![];
+    for (cluster_address, active) in clusters {
+        let profit_threshold = match CLUSTERS.may_load(deps.storage, &cluster_address)? {
+            Some(cluster)
+                if active
+                    && cluster.enabled
+                    && !PAUSED_CLUSTERS.has(deps.storage, &cluster_address) =>
+            {
+                cluster.profit_threshold
+            }
+            _ => continue,
+        };
+
+        // a cluster that cannot be priced is not an opportunity
+        let (estimate, context) =
+            match estimate_with_context(deps, &env, cluster_address.to_string(), &state) {
+                Ok(estimate) => estimate,
+                Err(_) => continue,
+            };
+        if !context.cluster_state.active
+            || estimate.expected_profit.is_zero()
+            || estimate.expected_profit < profit_threshold
+        {
+            continue;
+        }
+
+        opportunities.push(OpportunityResponse {
+            cluster_address,
+            direction: estimate.direction,
+            loan_amount: estimate.arbitrage_cost,
+            expected_profit: estimate.expected_profit,
+            market_price: estimate.market_price,
+            intrinsic_price: estimate.intrinsic_price,
+        });
+    }
+    opportunities.sort_by_key(|opportunity| std::cmp::Reverse(opportunity.expected_profit));
+
+    Ok(ListOpportunitiesResponse {
+        opportunities,
+        last_cluster,
+    })
+}
+
+/// ## Description
+/// Query the executed arbitrages, latest first. With a cluster or a user, only their records
+/// are listed.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **cluster_address** is an object of type [`Option<String>`].
+///
+/// - **user_address** is an object of type [`Option<String>`].
+///
+/// - **start_after** is an object of type [`Option<u64>`] which is the last record id of the previous page.
+///
+/// - **limit** is an object of type [`Option<u32>`] which is the page size.
+pub fn query_history(
+    deps: Deps,
+    cluster_address: Option<String>,
+    user_address: Option<String>,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<HistoryResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let end = start_after.map(Bound::exclusive_int);
+
+    let history = history();
+    let prefix = match (cluster_address, user_address) {
+        (Some(cluster_address), _) => Some(
+            history.idx.cluster.prefix(
+                deps.api
+                    .addr_validate(cluster_address.as_str())?
+                    .as_bytes()
+                    .to_vec(),
+            ),
+        ),
+        (None, Some(user_address)) => Some(
+            history.idx.user.prefix(
+                deps.api
+                    .addr_validate(user_address.as_str())?
+                    .as_bytes()
+                    .to_vec(),
+            ),
+        ),
+        (None, None) => None,
+    };
+    let items = match &prefix {
+        Some(prefix) => prefix.range(deps.storage, None, end, Order::Descending),
+        None => history.range(deps.storage, None, end, Order::Descending),
+    };
+    let records = items
+        .take(limit)
+        .map(|item| Ok(item?.1))
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(HistoryResponse { records })
+}
+
+/// ## Description
+/// Query the aggregates of the executed arbitrages.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **cluster** is an object of type [`Option<String>`] which restricts the stats to a cluster.
+pub fn query_stats(deps: Deps, cluster: Option<String>) -> StdResult<StatsResponse> {
+    let cluster = cluster
+        .map(|cluster| deps.api.addr_validate(cluster.as_str()))
+        .transpose()?;
+    let stats = match &cluster {
+        Some(cluster) => CLUSTER_STATS.may_load(deps.storage, cluster)?,
+        None => GLOBAL_STATS.may_load(deps.storage)?,
+    }
+    .unwrap_or_default();
+    Ok(StatsResponse { cluster, stats })
+}
+
+/// ## Description
+/// Query the protocol revenue accumulated over all arbitrages.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+pub fn query_protocol_revenue(deps: Deps) -> StdResult<ProtocolRevenueResponse> {
+    let state = STATE.load(deps.storage)?;
+    let revenue = PROTOCOL_REVENUE.may_load(deps.storage)?.unwrap_or_default();
+    Ok(ProtocolRevenueResponse {
+        asset_info: state.base_asset_info(),
+        treasury_amount: revenue.treasury_amount,
+        community_fund_amount: revenue.community_fund_amount,
+        whale_burned: revenue.whale_burned,
+    })
+}
+
+/// ## Description
+/// Query the access mode and the executors, ordered by address.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **start_after** is an object of type [`Option<String>`] which is the last executor of the previous page.
+///
+/// - **limit** is an object of type [`Option<u32>`] which is the page size.
+pub fn query_executors(
+    deps: Deps,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<ExecutorsResponse> {
+    let state = STATE.load(deps.storage)?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = match start_after {
+        Some(start_after) => Some(Bound::exclusive(
+            deps.api.addr_validate(start_after.as_str())?.as_str(),
+        )),
+        None => None,
+    };
+
+    let executors = EXECUTORS
+        .keys(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|key| Addr::unchecked(String::from_utf8_lossy(&key)))
+        .collect();
+
+    Ok(ExecutorsResponse {
+        access_mode: state.access_mode,
+        executors,
+    })
+}
+
+/// ## Description
+/// Query the registered clusters, ordered by address.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **start_after** is an object of type [`Option<String>`] which is the last cluster of the previous page.
+///
+/// - **limit** is an object of type [`Option<u32>`] which is the page size.
+pub fn query_clusters(
+    deps: Deps,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<ClustersResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = match start_after {
+        Some(start_after) => Some(Bound::exclusive(
+            deps.api.addr_validate(start_after.as_str())?.as_str(),
+        )),
+        None => None,
+    };
+
+    let clusters = CLUSTERS
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            let (key, cluster) = item?;
+            let cluster_address = Addr::unchecked(String::from_utf8_lossy(&key));
+            Ok(ClusterResponse {
+                paused: PAUSED_CLUSTERS.has(deps.storage, &cluster_address),
+                cluster_address,
+                enabled: cluster.enabled,
+                profit_threshold: cluster.profit_threshold,
+                max_loan: cluster.max_loan,
+                strategy: cluster.strategy,
+                pair_address: cluster.pair_address,
+                cooldown: cluster.cooldown,
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(ClustersResponse { clusters })
+}
+
+/// ## Description
+/// Query the registered wrapped assets, ordered by token address.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **start_after** is an object of type [`Option<String>`] which is the last token of the previous page.
+///
+/// - **limit** is an object of type [`Option<u32>`] which is the page size.
+pub fn query_wrappers(
+    deps: Deps,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<WrappersResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = match start_after {
+        Some(start_after) => Some(Bound::exclusive(
+            deps.api.addr_validate(start_after.as_str())?.as_str(),
+        )),
+        None => None,
+    };
+
+    let wrappers = WRAPPERS
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .map(|item| {
+            let (key, adapter) = item?;
+            Ok(WrapperResponse {
+                token: Addr::unchecked(String::from_utf8_lossy(&key)),
+                underlying: adapter.underlying(),
+                adapter,
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(WrappersResponse { wrappers })
+}
+
+/// ## Description
+/// Query the contract holdings of the base asset, aUST and the assets of the clusters of the
+/// last [`BALANCES_HISTORY_WINDOW`] arbitrages. Clusters and assets that cannot be queried
+/// are skipped.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **env** is an object of type [`Env`].
+pub fn query_balances(deps: Deps, env: Env) -> StdResult<BalancesResponse> {
+    let state = STATE.load(deps.storage)?;
+
+    let mut clusters: Vec<Addr> = vec![];
+    for record in history()
+        .range(deps.storage, None, None, Order::Descending)
+        .take(BALANCES_HISTORY_WINDOW)
+    {
+        let (_, record) = record?;
+        if !clusters.contains(&record.cluster_address) {
+            clusters.push(record.cluster_address);
+        }
+    }
+
+    let mut asset_infos = vec![
+        state.base_asset_info(),
+        AstroportAssetInfo::Token {
+            contract_addr: state.aust_token_address.clone(),
+        },
+    ];
+    for cluster in clusters {
+        let cluster_state = match get_cluster_state(deps, &cluster) {
+            Ok(cluster_state) => cluster_state,
+            Err(_) => continue,
+        };
+        let cluster_token = AstroportAssetInfo::Token {
+            contract_addr: Addr::unchecked(cluster_state.cluster_token),
+        };
+        for info in std::iter::once(cluster_token)
+            .chain(cluster_state.target.into_iter().map(|asset| asset.info))
+        {
+            if !asset_infos.iter().any(|known| known.equal(&info)) {
+                asset_infos.push(info);
+            }
+        }
+    }
+
+    let mut balances = vec![];
+    for info in asset_infos {
+        match info.query_pool(&deps.querier, env.contract.address.clone()) {
+            Ok(amount) if !amount.is_zero() => balances.push(AstroportAsset { info, amount }),
+            _ => {}
+        }
+    }
+    Ok(BalancesResponse { balances })
+}
+
+/// ## Description
+/// Calculates arbitrage information. The loan amount is searched with pool simulations
+/// so that commissions, slippage and taxes are taken into account.
 ///
 /// ## Params
 /// - **deps** is an object of type [`DepsMut`].
 ///
+/// - **env** is a reference to an object of type [`Env`].
+///
 /// - **cluster_address_raw** is an object of type [`String`].
 ///
 /// - **state** is a reference to an object of type [`State`].
 pub fn estimate_arbitrage(
     deps: Deps,
+    env: &Env,
     cluster_address_raw: String,
     state: &State,
 ) -> StdResult<EstimateArbitrageResponse> {
-    let cluster_address = deps.api.addr_validate(cluster_address_raw.as_str())?;
-    let cluster_state = get_cluster_state(deps, &cluster_address)?;
+    let (estimate, _) = estimate_with_context(deps, env, cluster_address_raw, state)?;
+    Ok(estimate)
+}
 
-    let supply: Uint128 = cluster_state.outstanding_balance_tokens;
-    // net_asset_val = Prices dot Inventory
-    let net_asset_val: Uint128 = cluster_state
-        .inv
-        .iter()
-        .zip(cluster_state.prices.iter())
-        .map(|(i, p)| (Decimal::from_str(p.as_str()).unwrap()) * *i)
-        .sum::<Uint128>();
-
-    // query pool info
-    let pool_info = query_pair_info(
-        &deps.querier,
-        state.astroport_factory_address.clone(),
-        &[
-            AstroportAssetInfo::NativeToken {
-                denom: "uusd".to_string(),
-            },
-            AstroportAssetInfo::Token {
-                contract_addr: deps
-                    .api
-                    .addr_validate(cluster_state.cluster_token.as_str())?,
-            },
-        ],
+/// ## Description
+/// Calculates arbitrage information like [`estimate_arbitrage`] and simulates the estimated
+/// loan, so the execution can be bounded by the simulated amounts.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **env** is a reference to an object of type [`Env`].
+///
+/// - **cluster_address_raw** is an object of type [`String`].
+///
+/// - **state** is a reference to an object of type [`State`].
+pub fn plan_arbitrage(
+    deps: Deps,
+    env: &Env,
+    cluster_address_raw: String,
+    state: &State,
+) -> StdResult<(EstimateArbitrageResponse, SimulateArbitrageResponse)> {
+    let (estimate, context) = estimate_with_context(deps, env, cluster_address_raw, state)?;
+    let flash_loan_fee = query_flash_loan_fee(&deps.querier, &state.vault_address)?;
+    let simulation = simulate_arbitrage(
+        deps,
+        state,
+        &context,
+        &context.direction()?,
+        estimate.arbitrage_cost,
+        &flash_loan_fee,
     )?;
+    Ok((estimate, simulation))
+}
 
-    let assets = pool_info.query_pools(&deps.querier, pool_info.contract_addr.clone())?;
+fn estimate_with_context(
+    deps: Deps,
+    env: &Env,
+    cluster_address_raw: String,
+    state: &State,
+) -> StdResult<(EstimateArbitrageResponse, ArbitrageContext)> {
+    let cluster_address = deps.api.addr_validate(cluster_address_raw.as_str())?;
+    let cluster_state = get_cluster_state(deps, &cluster_address)?;
+    let context = ArbitrageContext::new(deps, env, state, cluster_state)?;
 
-    // get UST amount and CT amount
-    let (ust_amt, ct_amt) = match assets[0].info {
-        AstroportAssetInfo::NativeToken { .. } => (assets[0].amount, assets[1].amount),
-        AstroportAssetInfo::Token { .. } => (assets[1].amount, assets[0].amount),
-    };
-    // intrinsic_price = net_asset_val / supply
-    let intrinsic_price: Decimal = Decimal::from_ratio(net_asset_val, supply);
-    // market_price = ust_amt / ct_amt
-    let market_price = Decimal::from_ratio(ust_amt, ct_amt);
-
-    let intrinsic_sqrt = intrinsic_price.sqrt() * MULTIPLIER;
-    let ct_sqrt = Decimal::from_str(&ct_amt.to_string()).unwrap().sqrt() * MULTIPLIER;
-    let ust_sqrt = Decimal::from_str(&ust_amt.to_string()).unwrap().sqrt() * MULTIPLIER;
-    let front = intrinsic_sqrt * ct_sqrt * ust_sqrt;
-
-    let arbitrage_cost: Uint128 = if market_price < intrinsic_price {
-        // sqrt(intrinsic_price * ct_amt * ust_amt) - ust_amt
-        front / MULTIPLIER_3 - ust_amt
-    } else {
-        // sqrt(intrinsic_price * ct_amt * ust_amt) - ct_amt * intrinsic_price
-        let back = ct_amt * MULTIPLIER_3 * intrinsic_price;
-        (front - back) / MULTIPLIER_3
-    };
-    Ok(EstimateArbitrageResponse {
+    let intrinsic_price = context.intrinsic_price();
+    let market_price = context.market_price()?;
+    let direction = context.direction()?;
+    let (arbitrage_cost, expected_profit) = optimize_loan(deps, state, &context, &direction)?;
+
+    let estimate = EstimateArbitrageResponse {
         market_price,
         intrinsic_price,
+        create_price: context.create_price,
+        redeem_price: context.redeem_price,
+        direction: direction.clone(),
         arbitrage_cost,
-        inv: cluster_state.inv,
-        target: cluster_state.target,
-        prices: cluster_state.prices,
-    })
+        expected_profit,
+        inv: context.cluster_state.inv.clone(),
+        target: context.cluster_state.target.clone(),
+        prices: context.cluster_state.prices.clone(),
+    };
+    Ok((estimate, context))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::contract::execute;
+    use crate::msg::ArbitrageDirection;
+    use crate::msg::{ClusterStrategy, ExecuteMsg};
+    use crate::state::ArbitrageRecord;
+    use crate::testing::{
+        mock_cluster, mock_dependencies as mock_terra_dependencies, mock_instantiate,
+        mock_stable_cluster, mock_vault, WasmMockQuerier, OWNER,
+    };
+    use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info};
+    use cosmwasm_std::{coin, to_binary, Decimal, DepsMut};
+    use cw_storage_plus::U64Key;
+
+    fn save_record(deps: DepsMut, id: u64, cluster_address: &str, user_address: &str) {
+        let env = mock_env();
+        let record = ArbitrageRecord {
+            id,
+            height: env.block.height + id,
+            time: env.block.time,
+            cluster_address: Addr::unchecked(cluster_address),
+            direction: ArbitrageDirection::Redeem,
+            loan_amount: Uint128::new(1_000),
+            market_price: Decimal::one(),
+            intrinsic_price: Decimal::one(),
+            gross_profit: Uint128::new(10),
+            net_profit: Uint128::new(9),
+            user_address: Addr::unchecked(user_address),
+        };
+        history()
+            .save(deps.storage, U64Key::new(id), &record)
+            .unwrap();
+    }
+
+    fn history_ids(
+        deps: Deps,
+        cluster_address: Option<&str>,
+        user_address: Option<&str>,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    ) -> Vec<u64> {
+        query_history(
+            deps,
+            cluster_address.map(String::from),
+            user_address.map(String::from),
+            start_after,
+            limit,
+        )
+        .unwrap()
+        .records
+        .into_iter()
+        .map(|record| record.id)
+        .collect()
+    }
+
+    fn page_all(deps: Deps, cluster_address: Option<&str>, user_address: Option<&str>) -> Vec<u64> {
+        let mut ids = vec![];
+        loop {
+            let page = history_ids(
+                deps,
+                cluster_address,
+                user_address,
+                ids.last().copied(),
+                Some(2),
+            );
+            if page.is_empty() {
+                return ids;
+            }
+            ids.extend(page);
+        }
+    }
+
+    #[test]
+    fn history_pages_latest_first() {
+        let mut deps = mock_dependencies(&[]);
+        for id in 1..=5u64 {
+            save_record(
+                deps.as_mut(),
+                id,
+                if id % 2 == 1 { "cluster1" } else { "cluster2" },
+                if id <= 2 { "user1" } else { "user2" },
+            );
+        }
+        let deps = deps.as_ref();
+
+        // all records, start_after is exclusive
+        assert_eq!(
+            history_ids(deps, None, None, None, None),
+            vec![5, 4, 3, 2, 1]
+        );
+        assert_eq!(history_ids(deps, None, None, Some(4), Some(2)), vec![3, 2]);
+        assert_eq!(page_all(deps, None, None), vec![5, 4, 3, 2, 1]);
+
+        // by cluster
+        assert_eq!(
+            history_ids(deps, Some("cluster1"), None, None, None),
+            vec![5, 3, 1]
+        );
+        assert_eq!(
+            history_ids(deps, Some("cluster1"), None, Some(5), None),
+            vec![3, 1]
+        );
+        assert_eq!(page_all(deps, Some("cluster1"), None), vec![5, 3, 1]);
+        assert_eq!(page_all(deps, Some("cluster2"), None), vec![4, 2]);
+
+        // by user
+        assert_eq!(
+            history_ids(deps, None, Some("user2"), None, None),
+            vec![5, 4, 3]
+        );
+        assert_eq!(
+            history_ids(deps, None, Some("user2"), Some(4), None),
+            vec![3]
+        );
+        assert_eq!(page_all(deps, None, Some("user1")), vec![2, 1]);
+        assert_eq!(page_all(deps, None, Some("user2")), vec![5, 4, 3]);
+
+        // the limit bounds the page
+        assert_eq!(history_ids(deps, None, None, None, Some(1)), vec![5]);
+    }
+
+    fn add_cluster(deps: DepsMut, cluster_address: &str, profit_threshold: Option<u128>) {
+        execute(
+            deps,
+            mock_env(),
+            mock_info(OWNER, &[]),
+            ExecuteMsg::AddCluster {
+                cluster_address: cluster_address.to_string(),
+                profit_threshold: profit_threshold.map(Uint128::new),
+                max_loan: None,
+                strategy: ClusterStrategy::Incentives,
+                pair_address: None,
+                cooldown: None,
+            },
+        )
+        .unwrap();
+    }
+
+    /// Mocks a cluster whose CT trades at 1 UST and redeems for `redeem_price` UST.
+    fn mock_discounted_cluster(querier: &mut WasmMockQuerier, name: &str, redeem_price: u64) {
+        mock_cluster(
+            querier,
+            name,
+            Uint128::new(1_000_000_000_000)
+                .multiply_ratio(redeem_price, 100u64)
+                .u128(),
+            1_000_000_000_000,
+            1_000_000_000_000,
+            1_000_000_000_000,
+        );
+    }
+
+    fn opportunities(
+        deps: Deps,
+        start_after: Option<&str>,
+        limit: Option<u32>,
+    ) -> (Vec<String>, Option<Addr>) {
+        let response =
+            query_list_opportunities(deps, mock_env(), start_after.map(String::from), limit)
+                .unwrap();
+        (
+            response
+                .opportunities
+                .into_iter()
+                .map(|opportunity| opportunity.cluster_address.to_string())
+                .collect(),
+            response.last_cluster,
+        )
+    }
+
+    #[test]
+    fn opportunities_are_filtered_and_ranked() {
+        let mut deps = mock_terra_dependencies(&[]);
+        mock_vault(&mut deps.querier, Decimal::permille(3));
+        for (name, redeem_price) in [
+            ("cluster_a", 105),
+            ("cluster_b", 120),
+            ("cluster_c", 100),
+            ("cluster_d", 120),
+            ("cluster_e", 120),
+            ("cluster_f", 120),
+        ] {
+            mock_discounted_cluster(&mut deps.querier, name, redeem_price);
+        }
+        mock_instantiate(deps.as_mut());
+        for name in [
+            "cluster_a",
+            "cluster_b",
+            "cluster_c",
+            "cluster_d",
+            "cluster_e",
+        ] {
+            add_cluster(deps.as_mut(), name, None);
+        }
+        add_cluster(deps.as_mut(), "cluster_f", Some(1_000_000_000_000));
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            ExecuteMsg::UpdateCluster {
+                cluster_address: "cluster_d".to_string(),
+                enabled: Some(false),
+                profit_threshold: None,
+                max_loan: None,
+                clear_max_loan: None,
+                strategy: None,
+                pair_address: None,
+                clear_pair_address: None,
+                cooldown: None,
+                clear_cooldown: None,
+            },
+        )
+        .unwrap();
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            ExecuteMsg::PauseCluster {
+                cluster: "cluster_e".to_string(),
+            },
+        )
+        .unwrap();
+
+        // the most profitable first, without the cluster at its intrinsic price
+        let queries = deps.querier.query_count();
+        assert_eq!(
+            opportunities(deps.as_ref(), None, Some(100)),
+            (
+                vec!["cluster_b".to_string(), "cluster_a".to_string()],
+                Some(Addr::unchecked("cluster_c"))
+            )
+        );
+        // a full page of single asset clusters stays within the documented query budget
+        assert!(deps.querier.query_count() - queries <= 250 * MAX_OPPORTUNITY_LIMIT as u64);
+
+        // disabled, paused and below their threshold
+        assert_eq!(
+            opportunities(deps.as_ref(), Some("cluster_c"), None),
+            (vec![], Some(Addr::unchecked("cluster_f")))
+        );
+        assert_eq!(
+            opportunities(deps.as_ref(), Some("cluster_f"), None),
+            (vec![], None)
+        );
+    }
+
+    #[test]
+    fn factory_opportunities_need_a_registered_cluster() {
+        let mut deps = mock_terra_dependencies(&[]);
+        mock_vault(&mut deps.querier, Decimal::permille(3));
+        for name in ["cluster_a", "cluster_b", "cluster_c"] {
+            mock_discounted_cluster(&mut deps.querier, name, 120);
+        }
+        deps.querier.with_contract("nebula_factory", |_| {
+            to_binary(&ClusterListResponse {
+                contract_infos: vec![
+                    ("cluster_c".to_string(), true),
+                    ("cluster_b".to_string(), false),
+                    ("cluster_a".to_string(), true),
+                ],
+            })
+        });
+        mock_instantiate(deps.as_mut());
+        add_cluster(deps.as_mut(), "cluster_a", None);
+        add_cluster(deps.as_mut(), "cluster_b", None);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            ExecuteMsg::UpdateConfig {
+                vault_address: None,
+                incentive_address: None,
+                astroport_factory_address: None,
+                astroport_router_address: None,
+                terraswap_factory_address: None,
+                nebula_factory_address: Some("nebula_factory".to_string()),
+                profit_threshold: None,
+                slippage_tolerance: None,
+            },
+        )
+        .unwrap();
+
+        // cluster_b is inactive and cluster_c can't be flash loaned
+        assert_eq!(
+            opportunities(deps.as_ref(), None, None),
+            (
+                vec!["cluster_a".to_string()],
+                Some(Addr::unchecked("cluster_c"))
+            )
+        );
+    }
+
+    #[test]
+    fn balances_cover_the_recent_clusters() {
+        let mut deps = mock_terra_dependencies(&[coin(1_000, "uusd")]);
+        let contract_address = mock_env().contract.address;
+        for name in ["cluster_a", "old_cluster"] {
+            mock_discounted_cluster(&mut deps.querier, name, 100);
+            deps.querier.with_token_balance(
+                format!("{}_token", name).as_str(),
+                contract_address.as_str(),
+                50,
+            );
+        }
+        mock_instantiate(deps.as_mut());
+
+        // the oldest arbitrages are out of the window, a failing cluster is skipped
+        save_record(deps.as_mut(), 1, "old_cluster", "user");
+        for id in 2..=(BALANCES_HISTORY_WINDOW as u64 + 1) {
+            let cluster_address = if id % 2 == 0 {
+                "cluster_a"
+            } else {
+                "broken_cluster"
+            };
+            save_record(deps.as_mut(), id, cluster_address, "user");
+        }
+
+        let balances = query_balances(deps.as_ref(), mock_env()).unwrap().balances;
+        assert_eq!(
+            balances,
+            vec![
+                AstroportAsset {
+                    info: AstroportAssetInfo::NativeToken {
+                        denom: "uusd".to_string(),
+                    },
+                    amount: Uint128::new(1_000),
+                },
+                AstroportAsset {
+                    info: AstroportAssetInfo::Token {
+                        contract_addr: Addr::unchecked("cluster_a_token"),
+                    },
+                    amount: Uint128::new(50),
+                },
+            ]
+        );
+    }
+    #[test]
+    fn stable_pairs_are_estimated_on_their_invariant() {
+        let estimate = |amp: Option<Option<u64>>| {
+            let mut deps = mock_terra_dependencies(&[]);
+            mock_vault(&mut deps.querier, Decimal::permille(3));
+            match amp {
+                Some(amp) => mock_stable_cluster(
+                    &mut deps.querier,
+                    "cluster",
+                    1_010_000_000_000,
+                    1_000_000_000_000,
+                    1_000_000_000_000,
+                    1_000_000_000_000,
+                    amp,
+                ),
+                None => mock_discounted_cluster(&mut deps.querier, "cluster", 101),
+            }
+            mock_instantiate(deps.as_mut());
+            query_estimate_arbitrage(deps.as_ref(), mock_env(), "cluster".to_string())
+        };
+
+        let xyk = estimate(None).unwrap();
+        let stable = estimate(Some(Some(100))).unwrap();
+        assert_eq!(stable.direction, ArbitrageDirection::Redeem);
+        assert_eq!(stable.market_price, xyk.market_price);
+        // the flatter curve takes a bigger loan to bring the price back to the NAV
+        assert!(stable.arbitrage_cost > xyk.arbitrage_cost);
+        assert!(stable.expected_profit > xyk.expected_profit);
+
+        assert!(estimate(Some(None)).is_err());
+    }
 }
@@ -0,0 +1,802 @@
+use cosmwasm_std::{from_binary, Addr, Decimal, Deps, Env, Fraction, StdError, StdResult, Uint128};
+
+use crate::msg::{
+    ArbitrageDirection, ClusterStateResponse, SimulateArbitrageResponse, SimulatedSwap,
+    SlippageLimits,
+};
+use crate::stableswap::{compute_d, marginal_price, trade_to_price};
+use crate::state::{State, CLUSTERS};
+use crate::utils::{
+    compute_tax, query_best_venue, query_cluster_pair_info, query_flash_loan_fee,
+    query_penalty_mint, query_penalty_redeem,
+};
+
+use astroport::asset::{Asset as AstroportAsset, AssetInfo as AstroportAssetInfo};
+use astroport::factory::PairType;
+use astroport::pair::{
+    ConfigResponse as PairConfigResponse, QueryMsg as PairQueryMsg, StablePoolConfig,
+};
+use astroport::querier::simulate;
+
+use white_whale::fee::Fee;
+
+use std::str::FromStr;
+
+// number of golden-section iterations used to size the loan
+const SEARCH_ITERATIONS: u32 = 24;
+// 1 / golden ratio, scaled by 1e6
+const INV_GOLDEN_RATIO: u128 = 618_034;
+const INV_GOLDEN_RATIO_SCALE: u128 = 1_000_000;
+// amount of base asset swapped to price it in UST
+const BASE_PRICE_UNIT: u128 = 1_000_000;
+// amount of CT minted and redeemed through the penalty contract to price it
+const PENALTY_PROBE: u128 = 1_000_000;
+
+/// ## Description
+/// A custom struct holding everything needed to simulate an arbitrage on a cluster.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArbitrageContext {
+    /// The current state of the cluster
+    pub cluster_state: ClusterStateResponse,
+    /// Oracle prices of the cluster assets
+    pub prices: Vec<Decimal>,
+    /// Astroport CT pair contract, quoted in the base asset
+    pub pair_contract: Addr,
+    /// Cluster token asset info
+    pub cluster_token: AstroportAssetInfo,
+    /// Base asset amount in the CT pair
+    pub base_amount: Uint128,
+    /// Price of one unit of the base asset in UST
+    pub base_price: Decimal,
+    /// CT amount in the CT pair
+    pub ct_amount: Uint128,
+    /// Type of the CT pair
+    pub pair_type: PairType,
+    /// Amplification of the CT pair when it is a stable pair
+    pub amp: Option<Decimal>,
+    /// Maximum flash loan set in the cluster registry
+    pub loan_cap: Option<Uint128>,
+    /// Block height given to the penalty contract
+    pub block_height: u64,
+    /// Price paid per CT minted, including the imbalance penalty or bonus
+    pub create_price: Decimal,
+    /// Value received per CT redeemed, including the imbalance penalty
+    pub redeem_price: Decimal,
+}
+
+impl ArbitrageContext {
+    /// ## Description
+    /// Loads the cluster state, its Astroport pair and its effective create and redeem prices.
+    ///
+    /// ## Params
+    /// - **deps** is an object of type [`Deps`].
+    ///
+    /// - **env** is a reference to an object of type [`Env`].
+    ///
+    /// - **state** is a reference to an object of type [`State`].
+    ///
+    /// - **cluster_state** is an object of type [`ClusterStateResponse`].
+    pub fn new(
+        deps: Deps,
+        env: &Env,
+        state: &State,
+        cluster_state: ClusterStateResponse,
+    ) -> StdResult<Self> {
+        let cluster_token = AstroportAssetInfo::Token {
+            contract_addr: deps
+                .api
+                .addr_validate(cluster_state.cluster_token.as_str())?,
+        };
+
+        // query pool info
+        let base_asset_info = state.base_asset_info();
+        let cluster_address = deps
+            .api
+            .addr_validate(cluster_state.cluster_contract_address.as_str())?;
+        let pair_info = query_cluster_pair_info(
+            deps,
+            state,
+            &cluster_address,
+            &deps
+                .api
+                .addr_validate(cluster_state.cluster_token.as_str())?,
+        )?;
+        // registered clusters may cap the flash loan
+        let loan_cap = CLUSTERS
+            .may_load(deps.storage, &cluster_address)?
+            .and_then(|cluster| cluster.max_loan);
+        let assets = pair_info.query_pools(&deps.querier, pair_info.contract_addr.clone())?;
+
+        // stable pairs expose their amplification in the pair config
+        let amp = match pair_info.pair_type {
+            PairType::Stable {} => {
+                let config: PairConfigResponse = deps
+                    .querier
+                    .query_wasm_smart(pair_info.contract_addr.clone(), &PairQueryMsg::Config {})?;
+                let params = config
+                    .params
+                    .ok_or_else(|| StdError::generic_err("Stable pair without parameters"))?;
+                Some(from_binary::<StablePoolConfig>(&params)?.amp)
+            }
+            _ => None,
+        };
+
+        // get base amount and CT amount
+        let (base_amount, ct_amount) = if assets[0].info == base_asset_info {
+            (assets[0].amount, assets[1].amount)
+        } else {
+            (assets[1].amount, assets[0].amount)
+        };
+
+        let prices = cluster_state
+            .prices
+            .iter()
+            .map(|price| Decimal::from_str(price))
+            .collect::<StdResult<Vec<Decimal>>>()?;
+
+        // cluster prices are quoted in UST, use the oracle price of the base asset when
+        // the cluster holds it, otherwise value one base unit with a simulated swap
+        let ust = AstroportAssetInfo::NativeToken {
+            denom: "uusd".to_string(),
+        };
+        let oracle_price = cluster_state
+            .target
+            .iter()
+            .position(|asset| asset.info == base_asset_info)
+            .map(|i| prices[i]);
+        let base_price = if base_asset_info == ust {
+            Decimal::one()
+        } else if let Some(price) = oracle_price {
+            price
+        } else {
+            let unit = Uint128::from(BASE_PRICE_UNIT);
+            let return_amount = simulate_asset_swap(
+                deps,
+                state,
+                &AstroportAsset {
+                    info: base_asset_info,
+                    amount: unit,
+                },
+                &ust,
+                env.block.height,
+            )?;
+            Decimal::from_ratio(return_amount, unit)
+        };
+
+        let mut context = ArbitrageContext {
+            cluster_state,
+            prices,
+            pair_contract: pair_info.contract_addr,
+            cluster_token,
+            base_amount,
+            base_price,
+            ct_amount,
+            pair_type: pair_info.pair_type,
+            amp,
+            loan_cap,
+            block_height: env.block.height,
+            create_price: Decimal::zero(),
+            redeem_price: Decimal::zero(),
+        };
+        context.load_effective_prices(deps)?;
+        Ok(context)
+    }
+
+    /// Prices a probe of `PENALTY_PROBE` CT through the penalty contract. The minted basket
+    /// follows the target weights like the create path, the redeem is pro-rata.
+    fn load_effective_prices(&mut self, deps: Deps) -> StdResult<()> {
+        let supply = self.cluster_state.outstanding_balance_tokens;
+        let probe = std::cmp::min(Uint128::from(PENALTY_PROBE), supply);
+        if probe.is_zero() {
+            return Ok(());
+        }
+        let probe_value = self.net_asset_value().multiply_ratio(probe, supply);
+
+        let target_value: Uint128 = self
+            .cluster_state
+            .target
+            .iter()
+            .zip(self.prices.iter())
+            .map(|(asset, price)| asset.amount * *price)
+            .sum();
+        if !target_value.is_zero() {
+            let mint_asset_amounts = self
+                .cluster_state
+                .target
+                .iter()
+                .map(|asset| asset.amount.multiply_ratio(probe_value, target_value))
+                .collect();
+            let create_tokens = query_penalty_mint(
+                deps,
+                self.block_height,
+                &self.cluster_state,
+                mint_asset_amounts,
+            )?
+            .create_tokens;
+            if !create_tokens.is_zero() {
+                self.create_price = Decimal::from_ratio(probe_value, create_tokens);
+            }
+        }
+
+        let redeem = query_penalty_redeem(deps, self.block_height, &self.cluster_state, probe)?;
+        if !redeem.token_cost.is_zero() {
+            self.redeem_price =
+                Decimal::from_ratio(self.basket_value(&redeem.redeem_assets), redeem.token_cost);
+        }
+        Ok(())
+    }
+
+    /// ## Description
+    /// Returns the value of `amounts` of the cluster assets at the oracle prices.
+    pub fn basket_value(&self, amounts: &[Uint128]) -> Uint128 {
+        amounts
+            .iter()
+            .zip(self.prices.iter())
+            .map(|(amount, price)| *amount * *price)
+            .sum()
+    }
+
+    /// ## Description
+    /// Returns the net asset value of the cluster, `prices · inventory`.
+    pub fn net_asset_value(&self) -> Uint128 {
+        self.basket_value(&self.cluster_state.inv)
+    }
+
+    /// ## Description
+    /// Returns the intrinsic price of CT, `net_asset_val / supply`.
+    pub fn intrinsic_price(&self) -> Decimal {
+        Decimal::from_ratio(
+            self.net_asset_value(),
+            self.cluster_state.outstanding_balance_tokens,
+        )
+    }
+
+    /// ## Description
+    /// Returns the price of CT on Astroport in UST, `base_amt / ct_amt * base_price` for an xyk
+    /// pair or the marginal price on the StableSwap invariant for a stable pair.
+    pub fn market_price(&self) -> StdResult<Decimal> {
+        let price = match self.stable_ann() {
+            Some(ann) => {
+                let d = compute_d(ann, self.ct_amount, self.base_amount)?;
+                marginal_price(ann, self.ct_amount, self.base_amount, d)
+            }
+            None => Decimal::from_ratio(self.base_amount, self.ct_amount),
+        };
+        Ok(Decimal::from_ratio(
+            Uint128::from(price.numerator())
+                .multiply_ratio(self.base_price.numerator(), self.base_price.denominator()),
+            price.denominator(),
+        ))
+    }
+
+    /// ## Description
+    /// Returns the largest loan worth searching. Any loan is bounded by the base side of the pair,
+    /// and on a stable pair by the trade that moves the marginal price to the effective price,
+    /// beyond which fees only reduce the profit.
+    pub fn max_loan(&self, direction: &ArbitrageDirection) -> StdResult<Uint128> {
+        let loan = self.pool_max_loan(direction)?;
+        Ok(match self.loan_cap {
+            Some(loan_cap) => std::cmp::min(loan, loan_cap),
+            None => loan,
+        })
+    }
+
+    /// Returns the largest loan the CT pair can absorb before the arbitrage closes.
+    fn pool_max_loan(&self, direction: &ArbitrageDirection) -> StdResult<Uint128> {
+        let ann = match self.stable_ann() {
+            Some(ann) if !self.base_price.is_zero() => ann,
+            _ => return Ok(self.base_amount),
+        };
+
+        // effective price of CT in the base asset
+        let effective_price = self.effective_price(direction);
+        let target_price = Decimal::from_ratio(
+            Uint128::from(effective_price.numerator())
+                .multiply_ratio(self.base_price.denominator(), self.base_price.numerator()),
+            effective_price.denominator(),
+        );
+        let (base_delta, ct_delta) = trade_to_price(
+            ann,
+            self.ct_amount,
+            self.base_amount,
+            target_price,
+            direction,
+        )?;
+
+        let loan = match direction {
+            ArbitrageDirection::Redeem => base_delta,
+            // the loan mints the CT sold, valued at the effective price
+            ArbitrageDirection::Create => ct_delta * target_price,
+        };
+        Ok(std::cmp::min(loan, self.base_amount))
+    }
+
+    /// Returns the amplification times the number of assets of a stable CT pair.
+    fn stable_ann(&self) -> Option<Uint128> {
+        match (&self.pair_type, self.amp) {
+            (PairType::Stable {}, Some(amp)) => Some(Uint128::from(2u8) * amp),
+            _ => None,
+        }
+    }
+
+    /// ## Description
+    /// Returns the effective price of CT for `direction`, as charged by the penalty contract.
+    pub fn effective_price(&self, direction: &ArbitrageDirection) -> Decimal {
+        match direction {
+            ArbitrageDirection::Redeem => self.redeem_price,
+            ArbitrageDirection::Create => self.create_price,
+        }
+    }
+
+    /// ## Description
+    /// Returns the profitable direction: redeem when CT trades below its effective redeem price,
+    /// create otherwise.
+    pub fn direction(&self) -> StdResult<ArbitrageDirection> {
+        Ok(if self.market_price()? < self.redeem_price {
+            ArbitrageDirection::Redeem
+        } else {
+            ArbitrageDirection::Create
+        })
+    }
+}
+
+/// ## Description
+/// Searches the loan amount that maximizes the net profit of an arbitrage, in the base asset.
+/// Returns the loan amount and the expected profit, both zero if no loan is profitable.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **state** is a reference to an object of type [`State`].
+///
+/// - **context** is a reference to an object of type [`ArbitrageContext`].
+///
+/// - **direction** is a reference to an object of type [`ArbitrageDirection`].
+pub fn optimize_loan(
+    deps: Deps,
+    state: &State,
+    context: &ArbitrageContext,
+    direction: &ArbitrageDirection,
+) -> StdResult<(Uint128, Uint128)> {
+    let flash_loan_fee = query_flash_loan_fee(&deps.querier, &state.vault_address)?;
+    let profit = |loan: Uint128| -> StdResult<i128> {
+        if loan.is_zero() {
+            return Ok(0);
+        }
+        let simulation =
+            simulate_arbitrage(deps, state, context, direction, loan, &flash_loan_fee)?;
+        Ok(simulation.net_profit.u128() as i128 - simulation.net_loss.u128() as i128)
+    };
+
+    golden_section_search(context.max_loan(direction)?, profit)
+}
+
+/// ## Description
+/// Runs a golden-section search of the loan maximizing `profit` on `[0, max_loan]`, the profit
+/// being unimodal in the loan. Returns the loan amount and its profit, both zero if no loan
+/// is profitable.
+///
+/// ## Params
+/// - **max_loan** is an object of type [`Uint128`] which bounds the search.
+///
+/// - **profit** is a closure returning the signed net profit of a loan.
+fn golden_section_search<F>(max_loan: Uint128, profit: F) -> StdResult<(Uint128, Uint128)>
+where
+    F: Fn(Uint128) -> StdResult<i128>,
+{
+    let mut low = 0u128;
+    let mut high = max_loan.u128();
+    let mut mid_low = high - golden_step(high - low);
+    let mut mid_high = low + golden_step(high - low);
+    let mut profit_low = profit(Uint128::from(mid_low))?;
+    let mut profit_high = profit(Uint128::from(mid_high))?;
+
+    for _ in 0..SEARCH_ITERATIONS {
+        if high - low < 3 {
+            break;
+        }
+        if profit_low < profit_high {
+            low = mid_low;
+            mid_low = mid_high;
+            profit_low = profit_high;
+            mid_high = low + golden_step(high - low);
+            profit_high = profit(Uint128::from(mid_high))?;
+        } else {
+            high = mid_high;
+            mid_high = mid_low;
+            profit_high = profit_low;
+            mid_low = high - golden_step(high - low);
+            profit_low = profit(Uint128::from(mid_low))?;
+        }
+    }
+
+    let (loan, best) = if profit_low < profit_high {
+        (mid_high, profit_high)
+    } else {
+        (mid_low, profit_low)
+    };
+    if best <= 0 {
+        return Ok((Uint128::zero(), Uint128::zero()));
+    }
+
+    Ok((Uint128::from(loan), Uint128::from(best as u128)))
+}
+
+/// ## Description
+/// Derives the slippage limits of a leg from its simulation, allowing every amount to
+/// fall short of the simulated one by `slippage_tolerance`.
+///
+/// ## Params
+/// - **simulation** is a reference to an object of type [`SimulateArbitrageResponse`].
+///
+/// - **slippage_tolerance** is an object of type [`Decimal`].
+pub fn slippage_limits(
+    simulation: &SimulateArbitrageResponse,
+    slippage_tolerance: Decimal,
+) -> SlippageLimits {
+    let min_amount = |amount: Uint128| amount * (Decimal::one() - slippage_tolerance);
+
+    let belief_prices = simulation
+        .basket_swaps
+        .iter()
+        .map(|swap| {
+            if swap.offer_asset.amount.is_zero() || swap.return_asset.amount.is_zero() {
+                None
+            } else {
+                Some(Decimal::from_ratio(
+                    swap.offer_asset.amount,
+                    swap.return_asset.amount,
+                ))
+            }
+        })
+        .collect();
+
+    let (min_basket_amounts, min_cluster_token) = match simulation.direction {
+        ArbitrageDirection::Redeem => (
+            vec![],
+            min_amount(simulation.cluster_token_swap.return_asset.amount),
+        ),
+        ArbitrageDirection::Create => (
+            simulation
+                .basket_swaps
+                .iter()
+                .map(|swap| min_amount(swap.return_asset.amount))
+                .collect(),
+            min_amount(simulation.cluster_token_swap.offer_asset.amount),
+        ),
+    };
+
+    SlippageLimits {
+        max_spread: slippage_tolerance,
+        belief_prices,
+        min_basket_amounts,
+        min_cluster_token,
+        min_base_returned: min_amount(simulation.base_recovered),
+    }
+}
+
+fn golden_step(width: u128) -> u128 {
+    Uint128::from(width)
+        .multiply_ratio(INV_GOLDEN_RATIO, INV_GOLDEN_RATIO_SCALE)
+        .u128()
+}
+
+/// ## Description
+/// Simulates an arbitrage with `loan_amount` of the base asset through the pools and returns every leg
+/// of the plan together with the fees, taxes and net profit.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **state** is a reference to an object of type [`State`].
+///
+/// - **context** is a reference to an object of type [`ArbitrageContext`].
+///
+/// - **direction** is a reference to an object of type [`ArbitrageDirection`].
+///
+/// - **loan_amount** is an object of type [`Uint128`].
+///
+/// - **flash_loan_fee** is a reference to an object of type [`Fee`] which is the vault fee.
+pub fn simulate_arbitrage(
+    deps: Deps,
+    state: &State,
+    context: &ArbitrageContext,
+    direction: &ArbitrageDirection,
+    loan_amount: Uint128,
+    flash_loan_fee: &Fee,
+) -> StdResult<SimulateArbitrageResponse> {
+    let cluster_state = &context.cluster_state;
+    let base = state.base_asset_info();
+
+    let mut basket_swaps = vec![];
+    let mut leg_tax = Uint128::zero();
+    let (cluster_token_swap, base_recovered) = match direction {
+        ArbitrageDirection::Redeem => {
+            // buy CT on Astroport
+            let ct_amount = simulate(
+                &deps.querier,
+                context.pair_contract.clone(),
+                &AstroportAsset {
+                    info: base.clone(),
+                    amount: loan_amount,
+                },
+            )?
+            .return_amount;
+
+            // redeem pro-rata with the penalty and sell every asset
+            let redeem_assets = if ct_amount.is_zero() {
+                vec![Uint128::zero(); cluster_state.target.len()]
+            } else {
+                query_penalty_redeem(deps, context.block_height, cluster_state, ct_amount)?
+                    .redeem_assets
+            };
+            // the base asset sent to buy CT is taxed
+            leg_tax += compute_tax(deps, &base, loan_amount)?;
+            let mut base_amount = Uint128::zero();
+            for (i, asset) in cluster_state.target.iter().enumerate() {
+                let amount = redeem_assets.get(i).cloned().unwrap_or_default();
+                // and so are the native assets sent to be sold
+                if asset.info != base {
+                    leg_tax += compute_tax(deps, &asset.info, amount)?;
+                }
+                let return_amount = simulate_asset_swap(
+                    deps,
+                    state,
+                    &AstroportAsset {
+                        info: asset.info.clone(),
+                        amount,
+                    },
+                    &base,
+                    context.block_height,
+                )?;
+                base_amount += return_amount;
+                basket_swaps.push(SimulatedSwap {
+                    offer_asset: AstroportAsset {
+                        info: asset.info.clone(),
+                        amount,
+                    },
+                    return_asset: AstroportAsset {
+                        info: base.clone(),
+                        amount: return_amount,
+                    },
+                });
+            }
+
+            let cluster_token_swap = SimulatedSwap {
+                offer_asset: AstroportAsset {
+                    info: base.clone(),
+                    amount: loan_amount,
+                },
+                return_asset: AstroportAsset {
+                    info: context.cluster_token.clone(),
+                    amount: ct_amount,
+                },
+            };
+            (cluster_token_swap, base_amount)
+        }
+        ArbitrageDirection::Create => {
+            let value_weights = cluster_state
+                .target
+                .iter()
+                .zip(context.prices.iter())
+                .map(|(asset, price)| asset.amount * *price)
+                .collect::<Vec<Uint128>>();
+            let total_value_weight: Uint128 = value_weights.iter().sum();
+
+            // buy the basket pro-rata
+            let mut basket_amounts = vec![Uint128::zero(); cluster_state.target.len()];
+            for (i, asset) in cluster_state.target.iter().enumerate() {
+                if total_value_weight.is_zero() {
+                    break;
+                }
+                let offer_amount = loan_amount.multiply_ratio(value_weights[i], total_value_weight);
+                // the base asset sent to swaps and deposits is taxed
+                if asset.info != base {
+                    leg_tax += compute_tax(deps, &base, offer_amount)?;
+                }
+                let amount = simulate_asset_swap(
+                    deps,
+                    state,
+                    &AstroportAsset {
+                        info: base.clone(),
+                        amount: offer_amount,
+                    },
+                    &asset.info,
+                    context.block_height,
+                )?;
+                basket_amounts[i] = amount;
+                basket_swaps.push(SimulatedSwap {
+                    offer_asset: AstroportAsset {
+                        info: base.clone(),
+                        amount: offer_amount,
+                    },
+                    return_asset: AstroportAsset {
+                        info: asset.info.clone(),
+                        amount,
+                    },
+                });
+            }
+
+            // the native assets sent to the incentives contract to mint are taxed
+            for (asset, amount) in cluster_state.target.iter().zip(basket_amounts.iter()) {
+                leg_tax += compute_tax(deps, &asset.info, *amount)?;
+            }
+
+            // mint CT with the penalty or bonus and sell it on Astroport
+            let ct_amount = if basket_amounts.iter().all(|amount| amount.is_zero()) {
+                Uint128::zero()
+            } else {
+                query_penalty_mint(deps, context.block_height, cluster_state, basket_amounts)?
+                    .create_tokens
+            };
+            let base_amount = if ct_amount.is_zero() {
+                Uint128::zero()
+            } else {
+                simulate(
+                    &deps.querier,
+                    context.pair_contract.clone(),
+                    &AstroportAsset {
+                        info: context.cluster_token.clone(),
+                        amount: ct_amount,
+                    },
+                )?
+                .return_amount
+            };
+
+            let cluster_token_swap = SimulatedSwap {
+                offer_asset: AstroportAsset {
+                    info: context.cluster_token.clone(),
+                    amount: ct_amount,
+                },
+                return_asset: AstroportAsset {
+                    info: base.clone(),
+                    amount: base_amount,
+                },
+            };
+            (cluster_token_swap, base_amount)
+        }
+    };
+
+    // the loan is taxed when sent out and the repayment on top of the loan and its fee, on top
+    // of every native transfer of the legs
+    let fee = flash_loan_fee.compute(loan_amount);
+    let tax = compute_tax(deps, &base, loan_amount)?
+        + compute_tax(deps, &base, loan_amount + fee)?
+        + leg_tax;
+    let cost = loan_amount + fee + tax;
+
+    Ok(SimulateArbitrageResponse {
+        direction: direction.clone(),
+        loan_amount,
+        basket_swaps,
+        cluster_token_swap,
+        base_recovered,
+        flash_loan_fee: fee,
+        tax,
+        net_profit: base_recovered.saturating_sub(cost),
+        net_loss: cost.saturating_sub(base_recovered),
+    })
+}
+
+/// ## Description
+/// Simulates swapping `offer_asset` to `to_asset` on the best venue, as chosen by
+/// [`query_best_venue`] when executing the swap.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **state** is a reference to an object of type [`State`].
+///
+/// - **offer_asset** is a reference to an object of type [`AstroportAsset`].
+///
+/// - **to_asset** is a reference to an object of type [`AstroportAssetInfo`].
+///
+/// - **block_height** is an object of type [`u64`] used to price the wrappers.
+pub fn simulate_asset_swap(
+    deps: Deps,
+    state: &State,
+    offer_asset: &AstroportAsset,
+    to_asset: &AstroportAssetInfo,
+    block_height: u64,
+) -> StdResult<Uint128> {
+    if offer_asset.amount.is_zero() {
+        return Ok(Uint128::zero());
+    }
+    if &offer_asset.info == to_asset {
+        return Ok(offer_asset.amount);
+    }
+
+    let (_, return_amount) = query_best_venue(deps, state, offer_asset, to_asset, block_height)?;
+    Ok(return_amount)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(base_amount: u128, loan_cap: Option<u128>) -> ArbitrageContext {
+        ArbitrageContext {
+            cluster_state: ClusterStateResponse {
+                outstanding_balance_tokens: Uint128::from(1_000_000u128),
+                prices: vec![],
+                inv: vec![],
+                penalty: "penalty".to_string(),
+                cluster_token: "cluster_token".to_string(),
+                target: vec![],
+                cluster_contract_address: "cluster".to_string(),
+                active: true,
+            },
+            prices: vec![],
+            pair_contract: Addr::unchecked("pair"),
+            cluster_token: AstroportAssetInfo::Token {
+                contract_addr: Addr::unchecked("cluster_token"),
+            },
+            base_amount: Uint128::from(base_amount),
+            base_price: Decimal::one(),
+            ct_amount: Uint128::from(1_000_000u128),
+            pair_type: PairType::Xyk {},
+            amp: None,
+            loan_cap: loan_cap.map(Uint128::from),
+            block_height: 12_345,
+            create_price: Decimal::one(),
+            redeem_price: Decimal::one(),
+        }
+    }
+
+    #[test]
+    fn search_finds_the_peak() {
+        // profit rises until 300_000 and falls after it
+        let peak = 300_000i128;
+        let profit = |loan: Uint128| -> StdResult<i128> {
+            let loan = loan.u128() as i128;
+            Ok(10_000 - (loan - peak).abs() / 10)
+        };
+        let (loan, expected_profit) =
+            golden_section_search(Uint128::from(1_000_000u128), profit).unwrap();
+        assert!((loan.u128() as i128 - peak).abs() <= 10, "{}", loan);
+        assert!(expected_profit.u128() >= 9_999);
+    }
+
+    #[test]
+    fn search_follows_a_monotonic_profit_to_the_bound() {
+        let profit = |loan: Uint128| -> StdResult<i128> { Ok(loan.u128() as i128 / 100) };
+        let (loan, expected_profit) =
+            golden_section_search(Uint128::from(1_000_000u128), profit).unwrap();
+        assert!(loan.u128() >= 999_000, "{}", loan);
+        assert_eq!(expected_profit.u128(), loan.u128() / 100);
+    }
+
+    #[test]
+    fn search_without_opportunity_borrows_nothing() {
+        let profit = |loan: Uint128| -> StdResult<i128> { Ok(-(loan.u128() as i128) / 100) };
+        assert_eq!(
+            golden_section_search(Uint128::from(1_000_000u128), profit).unwrap(),
+            (Uint128::zero(), Uint128::zero())
+        );
+        assert_eq!(
+            golden_section_search(Uint128::zero(), |_| Ok(0)).unwrap(),
+            (Uint128::zero(), Uint128::zero())
+        );
+    }
+
+    #[test]
+    fn max_loan_is_clamped_by_the_loan_cap() {
+        let direction = ArbitrageDirection::Redeem;
+        assert_eq!(
+            context(5_000_000, None).max_loan(&direction).unwrap(),
+            Uint128::from(5_000_000u128)
+        );
+        assert_eq!(
+            context(5_000_000, Some(1_000_000))
+                .max_loan(&direction)
+                .unwrap(),
+            Uint128::from(1_000_000u128)
+        );
+        assert_eq!(
+            context(500_000, Some(1_000_000))
+                .max_loan(&direction)
+                .unwrap(),
+            Uint128::from(500_000u128)
+        );
+    }
+}
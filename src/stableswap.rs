@@ -0,0 +1,315 @@
+use std::convert::TryFrom;
+
+use cosmwasm_std::{Decimal, Fraction, StdError, StdResult, Uint128, Uint256, Uint512};
+
+use crate::msg::ArbitrageDirection;
+
+// maximum number of Newton iterations used to solve the invariant, as in the Astroport stable pair
+const NEWTON_ITERATIONS: u32 = 32;
+// maximum number of bisection iterations used to reach a target price
+const BISECTION_ITERATIONS: u32 = 128;
+
+/// ## Description
+/// Computes the StableSwap invariant `D` of a two asset pool with the Newton method used by
+/// the Astroport stable pair. The iteration runs in [`Uint256`] so that 18 decimal pools don't
+/// overflow.
+///
+/// ## Params
+/// - **ann** is an object of type [`Uint128`] which is the pool amplification times the number of assets.
+///
+/// - **x** is an object of type [`Uint128`] which is the first pool balance.
+///
+/// - **y** is an object of type [`Uint128`] which is the second pool balance.
+pub fn compute_d(ann: Uint128, x: Uint128, y: Uint128) -> StdResult<Uint128> {
+    if ann.is_zero() {
+        return Err(StdError::generic_err(
+            "Stable pool amplification must be positive",
+        ));
+    }
+    let sum = x.checked_add(y)?;
+    if x.is_zero() || y.is_zero() {
+        return Ok(sum);
+    }
+
+    let ann = Uint256::from(ann);
+    let (x, y, sum) = (Uint256::from(x), Uint256::from(y), Uint256::from(sum));
+    let two = Uint256::from(2u8);
+    let three = Uint256::from(3u8);
+    let mut d = sum;
+    for _ in 0..NEWTON_ITERATIONS {
+        let d_p = d
+            .checked_mul(d)?
+            .checked_div(x.checked_mul(two)?)?
+            .checked_mul(d)?
+            .checked_div(y.checked_mul(two)?)?;
+        let d_prev = d;
+        let numerator = ann
+            .checked_mul(sum)?
+            .checked_add(d_p.checked_mul(two)?)?
+            .checked_mul(d)?;
+        let denominator = ann
+            .checked_sub(Uint256::from(1u8))?
+            .checked_mul(d)?
+            .checked_add(d_p.checked_mul(three)?)?;
+        d = numerator.checked_div(denominator)?;
+        if d == d_prev {
+            break;
+        }
+    }
+    to_uint128(d)
+}
+
+/// ## Description
+/// Returns the balance of the other asset that keeps the invariant `d` once one side of
+/// the pool holds `x`.
+///
+/// ## Params
+/// - **ann** is an object of type [`Uint128`].
+///
+/// - **x** is an object of type [`Uint128`] which is the new balance of one side of the pool.
+///
+/// - **d** is an object of type [`Uint128`] which is the pool invariant.
+pub fn compute_y(ann: Uint128, x: Uint128, d: Uint128) -> StdResult<Uint128> {
+    if ann.is_zero() || x.is_zero() {
+        return Err(StdError::generic_err(
+            "Stable pool balances must be positive",
+        ));
+    }
+
+    let ann = Uint256::from(ann);
+    let (x, d) = (Uint256::from(x), Uint256::from(d));
+    let two = Uint256::from(2u8);
+    // D³ / (4 x Ann) needs 512 bits before the division
+    let d_cubed = Uint512::from(d)
+        .checked_mul(Uint512::from(d))?
+        .checked_mul(Uint512::from(d))?;
+    let c = Uint256::try_from(d_cubed.checked_div(Uint512::from(
+        x.checked_mul(Uint256::from(4u8))?.checked_mul(ann)?,
+    ))?)
+    .map_err(|err| StdError::generic_err(err.to_string()))?;
+    let b = x.checked_add(d.checked_div(ann)?)?;
+
+    let mut y = d;
+    for _ in 0..NEWTON_ITERATIONS {
+        let y_prev = y;
+        let numerator = y.checked_mul(y)?.checked_add(c)?;
+        let denominator = y.checked_mul(two)?.checked_add(b)?.checked_sub(d)?;
+        y = numerator.checked_div(denominator)?;
+        if y == y_prev {
+            break;
+        }
+    }
+    to_uint128(y)
+}
+
+/// ## Description
+/// Returns the marginal price of `x` in units of `y` on the invariant curve,
+/// `(Ann + D³/4x²y) / (Ann + D³/4xy²)`.
+///
+/// ## Params
+/// - **ann** is an object of type [`Uint128`].
+///
+/// - **x** is an object of type [`Uint128`] which is the pool balance of the priced asset.
+///
+/// - **y** is an object of type [`Uint128`] which is the pool balance of the quote asset.
+///
+/// - **d** is an object of type [`Uint128`] which is the pool invariant.
+pub fn marginal_price(ann: Uint128, x: Uint128, y: Uint128, d: Uint128) -> Decimal {
+    let ann = Decimal::from_ratio(ann, 1u8);
+    let a = Decimal::from_ratio(d, x);
+    let b = Decimal::from_ratio(d, y);
+
+    let numerator = ann + quarter(decimal_mul(decimal_mul(a, a), b));
+    let denominator = ann + quarter(decimal_mul(decimal_mul(a, b), b));
+    Decimal::from_ratio(numerator.numerator(), denominator.numerator())
+}
+
+/// ## Description
+/// Searches the trade that moves the marginal price of CT to `target_price` while keeping
+/// the invariant. Returns the base asset and CT moved by the trade, base paid in and CT
+/// bought for redeem, base paid out for CT sold for create.
+///
+/// ## Params
+/// - **ann** is an object of type [`Uint128`].
+///
+/// - **ct_amount** is an object of type [`Uint128`] which is the CT balance of the pool.
+///
+/// - **base_amount** is an object of type [`Uint128`] which is the base asset balance of the pool.
+///
+/// - **target_price** is an object of type [`Decimal`] which is a price of CT in the base asset.
+///
+/// - **direction** is a reference to an object of type [`ArbitrageDirection`].
+pub fn trade_to_price(
+    ann: Uint128,
+    ct_amount: Uint128,
+    base_amount: Uint128,
+    target_price: Decimal,
+    direction: &ArbitrageDirection,
+) -> StdResult<(Uint128, Uint128)> {
+    if ct_amount.is_zero() || base_amount.is_zero() {
+        return Ok((Uint128::zero(), Uint128::zero()));
+    }
+
+    let d = compute_d(ann, ct_amount, base_amount)?;
+    // pool balances after moving `delta` of the base asset
+    let pool = |delta: Uint128| -> StdResult<(Uint128, Uint128)> {
+        let base = match direction {
+            ArbitrageDirection::Redeem => base_amount.checked_add(delta)?,
+            ArbitrageDirection::Create => base_amount.checked_sub(delta)?,
+        };
+        Ok((compute_y(ann, base, d)?, base))
+    };
+    // redeem raises the CT price, create lowers it
+    let reached = |delta: Uint128| -> StdResult<bool> {
+        let (ct, base) = pool(delta)?;
+        let price = marginal_price(ann, ct, base, d);
+        Ok(match direction {
+            ArbitrageDirection::Redeem => price >= target_price,
+            ArbitrageDirection::Create => price <= target_price,
+        })
+    };
+
+    let mut low = Uint128::zero();
+    let mut high = match direction {
+        ArbitrageDirection::Redeem => base_amount,
+        ArbitrageDirection::Create => base_amount - Uint128::from(1u8),
+    };
+    for _ in 0..BISECTION_ITERATIONS {
+        if high <= low + Uint128::from(1u8) {
+            break;
+        }
+        let mid = low + (high - low).multiply_ratio(1u8, 2u8);
+        if reached(mid)? {
+            high = mid;
+        } else {
+            low = mid;
+        }
+    }
+
+    let (ct, _) = pool(high)?;
+    Ok((high, abs_diff(ct, ct_amount)))
+}
+
+fn decimal_mul(a: Decimal, b: Decimal) -> Decimal {
+    Decimal::from_ratio(Uint128::from(a.numerator()) * b, a.denominator())
+}
+
+fn quarter(a: Decimal) -> Decimal {
+    Decimal::from_ratio(a.numerator(), a.denominator() * 4)
+}
+
+fn to_uint128(value: Uint256) -> StdResult<Uint128> {
+    Uint128::try_from(value).map_err(|err| StdError::generic_err(err.to_string()))
+}
+
+fn abs_diff(a: Uint128, b: Uint128) -> Uint128 {
+    if a > b {
+        a - b
+    } else {
+        b - a
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // reference values from the Astroport stable pair math, amp 100 on two assets
+    const ANN: u128 = 200;
+
+    #[test]
+    fn compute_d_matches_astroport() {
+        let cases = [
+            (
+                ANN,
+                1_000_000_000_000u128,
+                1_000_000_000_000u128,
+                2_000_000_000_000u128,
+            ),
+            (ANN, 1_000_000_000_000, 1_200_000_000_000, 2_199_909_252_099),
+            (20, 5_000_000_000, 1_000_000_000, 5_801_846_337),
+        ];
+        for (ann, x, y, d) in cases {
+            assert_eq!(
+                compute_d(ann.into(), x.into(), y.into()).unwrap(),
+                Uint128::from(d)
+            );
+        }
+    }
+
+    #[test]
+    fn compute_y_matches_astroport() {
+        let cases = [
+            (
+                ANN,
+                1_000_001_000_000u128,
+                2_000_000_000_000u128,
+                999_999_000_000u128,
+            ),
+            (ANN, 1_000_001_000_000, 2_199_909_252_099, 1_199_998_998_168),
+            (20, 5_001_000_000, 5_801_846_337, 999_262_597),
+        ];
+        for (ann, x, d, y) in cases {
+            assert_eq!(
+                compute_y(ann.into(), x.into(), d.into()).unwrap(),
+                Uint128::from(y)
+            );
+        }
+    }
+
+    #[test]
+    fn eighteen_decimal_pools_do_not_overflow() {
+        let x = Uint128::from(1_000_000_000_000_000_000_000_000u128);
+        let y = Uint128::from(1_300_000_000_000_000_000_000_000u128);
+        let d = compute_d(ANN.into(), x, y).unwrap();
+        assert_eq!(d, Uint128::from(2_299_802_982_603_651_243_108_383u128));
+
+        let new_x = x + Uint128::from(1_000_000_000_000_000_000_000u128);
+        assert_eq!(
+            compute_y(ANN.into(), new_x, d).unwrap(),
+            Uint128::from(1_298_997_334_525_275_810_728_951u128)
+        );
+    }
+
+    #[test]
+    fn zero_amplification_is_an_error() {
+        let amount = Uint128::from(1_000_000u128);
+        assert!(compute_d(Uint128::zero(), amount, amount).is_err());
+        assert!(compute_y(Uint128::zero(), amount, amount).is_err());
+    }
+
+    #[test]
+    fn empty_side_keeps_the_sum() {
+        let amount = Uint128::from(1_000_000u128);
+        assert_eq!(
+            compute_d(ANN.into(), amount, Uint128::zero()).unwrap(),
+            amount
+        );
+    }
+
+    #[test]
+    fn trade_to_price_moves_the_marginal_price() {
+        let ann = Uint128::from(ANN);
+        let ct_amount = Uint128::from(1_000_000_000_000u128);
+        let base_amount = Uint128::from(1_000_000_000_000u128);
+        let d = compute_d(ann, ct_amount, base_amount).unwrap();
+        assert_eq!(
+            marginal_price(ann, ct_amount, base_amount, d),
+            Decimal::one()
+        );
+
+        // redeem buys CT until it trades at the target
+        let target = Decimal::percent(101);
+        let (base_in, ct_out) = trade_to_price(
+            ann,
+            ct_amount,
+            base_amount,
+            target,
+            &ArbitrageDirection::Redeem,
+        )
+        .unwrap();
+        assert!(!base_in.is_zero() && !ct_out.is_zero());
+        let ct = compute_y(ann, base_amount + base_in, d).unwrap();
+        assert!(marginal_price(ann, ct, base_amount + base_in, d) >= target);
+    }
+}
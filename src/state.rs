@@ -1,12 +1,22 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use astroport::asset::Asset as AstroportAsset;
-use cosmwasm_std::{Addr, Uint128};
-use cw_storage_plus::Item;
+use astroport::asset::{Asset as AstroportAsset, AssetInfo as AstroportAssetInfo};
+use cosmwasm_std::{Addr, Decimal, Empty, Timestamp, Uint128};
+use cw0::Expiration;
+use cw_controllers::Admin;
+use cw_storage_plus::{Index, IndexList, IndexedMap, Item, Map, MultiIndex, U64Key};
+use terraswap::asset::AssetInfo;
+use white_whale::deposit_info::ArbBaseAsset;
+use white_whale::fee::Fee;
+
+use crate::msg::{
+    AccessMode, ArbitrageDirection, ArbitrageLeg, ClusterStrategy, Cooldown, KeeperReward,
+    WrapperAdapter,
+};
 
 //////////////////////////////////////////////////////////////////////
-/// STATE
+// STATE
 //////////////////////////////////////////////////////////////////////
 
 /// ## Description
@@ -14,16 +24,136 @@ use cw_storage_plus::Item;
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct State {
     pub vault_address: Addr,
-    pub incentive_addres: Addr,
+    pub incentive_address: Addr,
+    pub astroport_factory_address: Addr,
+    /// Astroport router used for basket assets without a direct pair
+    pub astroport_router_address: Addr,
+    /// Terraswap factory used to find alternative pools
+    pub terraswap_factory_address: Addr,
+    pub aust_token_address: Addr,
+    pub anchor_market_contract: Addr,
+    pub profit_threshold: Uint128,
+    /// Share of the simulated amounts that may be lost to slippage
+    pub slippage_tolerance: Decimal,
+    /// Asset borrowed from the vault and used to quote the CT pairs
+    pub base_asset: ArbBaseAsset,
+    /// Nebula cluster factory scanned for opportunities
+    pub nebula_factory_address: Option<Addr>,
+    /// Who can trigger arbitrages
+    pub access_mode: AccessMode,
+}
+
+/// ## Description
+/// A custom struct for reading the state stored by previous versions of the contract,
+/// the fields they did not have are none.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct LegacyState {
+    pub vault_address: Addr,
+    #[serde(alias = "incentive_addres")]
+    pub incentive_address: Addr,
     pub astroport_factory_address: Addr,
+    pub astroport_router_address: Option<Addr>,
+    pub terraswap_factory_address: Option<Addr>,
     pub aust_token_address: Addr,
     pub anchor_market_contract: Addr,
     pub profit_threshold: Uint128,
-    pub owner_address: Addr,
+    pub slippage_tolerance: Option<Decimal>,
+    pub base_asset: Option<ArbBaseAsset>,
+    pub nebula_factory_address: Option<Addr>,
+    pub access_mode: Option<AccessMode>,
+    /// Owner kept in the state before it moved to the admin
+    pub owner_address: Option<Addr>,
+}
+
+impl State {
+    /// ## Description
+    /// Returns the base asset as an Astroport asset info.
+    pub fn base_asset_info(&self) -> AstroportAssetInfo {
+        match &self.base_asset.asset_info {
+            AssetInfo::NativeToken { denom } => AstroportAssetInfo::NativeToken {
+                denom: denom.clone(),
+            },
+            AssetInfo::Token { contract_addr } => AstroportAssetInfo::Token {
+                contract_addr: Addr::unchecked(contract_addr),
+            },
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////
+// OWNERSHIP
+//////////////////////////////////////////////////////////////////////
+
+/// ## Description
+/// A custom struct for storing an ownership transfer waiting to be claimed.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct OwnershipProposal {
+    /// Address that can claim the ownership
+    pub owner: Addr,
+    /// When the proposal can no longer be claimed
+    pub expires: Expiration,
+}
+
+//////////////////////////////////////////////////////////////////////
+// CLUSTERS
+//////////////////////////////////////////////////////////////////////
+
+/// ## Description
+/// A custom struct for storing the settings of a registered cluster.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ClusterConfig {
+    /// Whether the cluster can be arbitraged
+    pub enabled: bool,
+    /// Threshold of arbitrage profit
+    pub profit_threshold: Uint128,
+    /// Maximum flash loan amount, unbounded if none
+    pub max_loan: Option<Uint128>,
+    /// How the cluster is minted and redeemed
+    pub strategy: ClusterStrategy,
+    /// Astroport CT pair used instead of the factory one
+    pub pair_address: Option<Addr>,
+    /// Minimum delay between two arbitrages
+    pub cooldown: Option<Cooldown>,
+}
+
+//////////////////////////////////////////////////////////////////////
+// PROFIT SPLIT
+//////////////////////////////////////////////////////////////////////
+
+/// ## Description
+/// A custom struct for storing how the arbitrage profit is shared. The caller receives what
+/// is left once the protocol and community fund shares are taken.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ProfitSplit {
+    /// Share of the profit sent to the caller
+    pub caller_fee: Fee,
+    /// Share of the profit sent to the treasury
+    pub protocol_fee: Fee,
+    /// Protocol treasury address
+    pub treasury_address: Addr,
+    /// Share of the profit sent to the community fund
+    pub community_fund_fee: Fee,
+    /// White Whale community fund contract address
+    pub community_fund_address: Addr,
+    /// Astroport pair of the base asset and WHALE, the community fund share is swapped to
+    /// WHALE and burned when set
+    pub whale_pair_address: Option<Addr>,
+}
+
+/// ## Description
+/// A custom struct for storing the protocol revenue accumulated over all arbitrages.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct ProtocolRevenue {
+    /// Base asset received by the treasury, net of tax
+    pub treasury_amount: Uint128,
+    /// Base asset given to the community fund net of tax, including the part swapped to WHALE
+    pub community_fund_amount: Uint128,
+    /// WHALE burned through the community fund
+    pub whale_burned: Uint128,
 }
 
 //////////////////////////////////////////////////////////////////////
-/// LOAN INFO
+// LOAN INFO
 //////////////////////////////////////////////////////////////////////
 
 /// ## Description
@@ -38,5 +168,179 @@ pub struct LoanInfo {
     pub prices: Vec<String>,
 }
 
+//////////////////////////////////////////////////////////////////////
+// ARBITRAGE FLOW
+//////////////////////////////////////////////////////////////////////
+
+/// ## Description
+/// A custom struct for storing the arbitrage in progress between the reply steps.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ArbitrageFlow {
+    pub user_address: Addr,
+    /// Address that triggered the arbitrage
+    pub keeper_address: Addr,
+    pub loan_amount: Uint128,
+    pub profit_threshold: Uint128,
+    pub legs: Vec<ArbitrageLeg>,
+    pub results: Vec<LegResult>,
+    /// Index of the leg being executed
+    pub current_leg: u64,
+    /// Base asset balance when the flash loan callback arrived, the loan included
+    pub initial_base_balance: Uint128,
+    /// Base asset balance when the leg being executed started
+    pub base_snapshot: Uint128,
+    /// Balances of the leg assets when the leg being executed started, without the leg loan
+    pub asset_snapshots: Vec<AstroportAsset>,
+}
+
+impl ArbitrageFlow {
+    pub fn leg(&self) -> &ArbitrageLeg {
+        &self.legs[self.current_leg as usize]
+    }
+
+    pub fn result(&self) -> &LegResult {
+        &self.results[self.current_leg as usize]
+    }
+
+    pub fn result_mut(&mut self) -> &mut LegResult {
+        &mut self.results[self.current_leg as usize]
+    }
+
+    /// Returns the snapshot of an asset for the leg being executed, zero if it was not taken.
+    pub fn asset_snapshot(&self, info: &AstroportAssetInfo) -> Uint128 {
+        self.asset_snapshots
+            .iter()
+            .find(|asset| asset.info.equal(info))
+            .map(|asset| asset.amount)
+            .unwrap_or_default()
+    }
+}
+
+/// ## Description
+/// A custom struct for storing the outcome of each step of a leg.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Default)]
+pub struct LegResult {
+    /// Cluster assets bought for create or received from redeem
+    pub basket_amounts: Vec<Uint128>,
+    /// CT minted or redeemed
+    pub cluster_token_amount: Uint128,
+    /// Base asset returned by selling CT or the redeemed assets
+    pub base_returned: Uint128,
+}
+
+//////////////////////////////////////////////////////////////////////
+// HISTORY
+//////////////////////////////////////////////////////////////////////
+
+/// ## Description
+/// A custom struct for storing an executed arbitrage leg.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ArbitrageRecord {
+    pub id: u64,
+    pub height: u64,
+    pub time: Timestamp,
+    pub cluster_address: Addr,
+    pub direction: ArbitrageDirection,
+    pub loan_amount: Uint128,
+    /// CT price on Astroport when the arbitrage was planned
+    pub market_price: Decimal,
+    /// CT price from the cluster inventory when the arbitrage was planned
+    pub intrinsic_price: Decimal,
+    /// Base asset returned by the leg above its loan
+    pub gross_profit: Uint128,
+    /// Share of the profit left once the flash loan is repaid, pro-rata of the loan in a batch
+    pub net_profit: Uint128,
+    /// Address the profit was sent to
+    pub user_address: Addr,
+}
+
+/// ## Description
+/// Secondary indexes of the arbitrage history.
+pub struct HistoryIndexes<'a> {
+    pub cluster: MultiIndex<'a, (Vec<u8>, Vec<u8>), ArbitrageRecord>,
+    pub user: MultiIndex<'a, (Vec<u8>, Vec<u8>), ArbitrageRecord>,
+}
+
+impl<'a> IndexList<ArbitrageRecord> for HistoryIndexes<'a> {
+    fn get_indexes(&'_ self) -> Box<dyn Iterator<Item = &'_ dyn Index<ArbitrageRecord>> + '_> {
+        let v: Vec<&dyn Index<ArbitrageRecord>> = vec![&self.cluster, &self.user];
+        Box::new(v.into_iter())
+    }
+}
+
+/// ## Description
+/// Returns the arbitrage history keyed by record id and indexed by cluster and user.
+pub fn history<'a>() -> IndexedMap<'a, U64Key, ArbitrageRecord, HistoryIndexes<'a>> {
+    let indexes = HistoryIndexes {
+        cluster: MultiIndex::new(
+            |record, pk| (record.cluster_address.as_bytes().to_vec(), pk),
+            "history",
+            "history__cluster",
+        ),
+        user: MultiIndex::new(
+            |record, pk| (record.user_address.as_bytes().to_vec(), pk),
+            "history",
+            "history__user",
+        ),
+    };
+    IndexedMap::new("history", indexes)
+}
+
+//////////////////////////////////////////////////////////////////////
+// STATS
+//////////////////////////////////////////////////////////////////////
+
+/// ## Description
+/// A custom struct for storing running aggregates of the executed arbitrages.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct ArbitrageStats {
+    /// Number of redeem arbitrages
+    pub redeem_count: u64,
+    /// Number of create arbitrages
+    pub create_count: u64,
+    /// Cumulative flash loan amount
+    pub loan_volume: Uint128,
+    /// Cumulative net profit
+    pub total_profit: Uint128,
+    /// Best net profit of a single arbitrage
+    pub best_profit: Uint128,
+    /// Block height of the last arbitrage
+    pub last_height: u64,
+    /// Block time of the last arbitrage
+    pub last_time: Timestamp,
+}
+
+impl ArbitrageStats {
+    /// ## Description
+    /// Adds an executed arbitrage to the aggregates.
+    pub fn add(&mut self, record: &ArbitrageRecord) {
+        match record.direction {
+            ArbitrageDirection::Redeem => self.redeem_count += 1,
+            ArbitrageDirection::Create => self.create_count += 1,
+        }
+        self.loan_volume += record.loan_amount;
+        self.total_profit += record.net_profit;
+        self.best_profit = std::cmp::max(self.best_profit, record.net_profit);
+        self.last_height = record.height;
+        self.last_time = record.time;
+    }
+}
+
 pub const STATE: Item<State> = Item::new("state");
+pub const LEGACY_STATE: Item<LegacyState> = Item::new("state");
+pub const ADMIN: Admin = Admin::new("admin");
+pub const OWNERSHIP_PROPOSAL: Item<OwnershipProposal> = Item::new("ownership_proposal");
+pub const GUARDIAN: Item<Addr> = Item::new("guardian");
+pub const PAUSED: Item<bool> = Item::new("paused");
+pub const PAUSED_CLUSTERS: Map<&Addr, Empty> = Map::new("paused_clusters");
+pub const ARBITRAGE_FLOW: Item<ArbitrageFlow> = Item::new("arbitrage_flow");
+pub const CLUSTERS: Map<&Addr, ClusterConfig> = Map::new("clusters");
+pub const WRAPPERS: Map<&Addr, WrapperAdapter> = Map::new("wrappers");
+pub const EXECUTORS: Map<&Addr, Empty> = Map::new("executors");
+pub const PROFIT_SPLIT: Item<ProfitSplit> = Item::new("profit_split");
+pub const PROTOCOL_REVENUE: Item<ProtocolRevenue> = Item::new("protocol_revenue");
+pub const KEEPER_REWARD: Item<KeeperReward> = Item::new("keeper_reward");
+pub const HISTORY_COUNT: Item<u64> = Item::new("history_count");
+pub const GLOBAL_STATS: Item<ArbitrageStats> = Item::new("global_stats");
+pub const CLUSTER_STATS: Map<&Addr, ArbitrageStats> = Map::new("cluster_stats");
 // pub const LOAN_INFO: Item<LoanInfo> = Item::new("loan_info");
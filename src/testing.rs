@@ -0,0 +1,430 @@
+use cosmwasm_std::testing::{mock_env, mock_info, MockApi, MockQuerier, MockStorage};
+use cosmwasm_std::{
+    coin, from_binary, from_slice, to_binary, Addr, Binary, Coin, ContractResult, CosmosMsg,
+    Decimal, DepsMut, Event, OwnedDeps, Querier, QuerierResult, QueryRequest, Reply, Response,
+    StdError, StdResult, SubMsgExecutionResponse, SystemError, SystemResult, Uint128, WasmMsg,
+    WasmQuery,
+};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::contract::instantiate;
+use crate::msg::{
+    ClusterStateResponse, ExecuteMsg, InstantiateMsg, PenaltyCreateResponse, PenaltyQueryMsg,
+    PenaltyRedeemResponse, QueryMsgNebula,
+};
+use crate::stableswap::{compute_d, compute_y};
+
+use astroport::asset::{Asset as AstroportAsset, AssetInfo as AstroportAssetInfo, PairInfo};
+use astroport::factory::{PairType, QueryMsg as AstroportFactoryQueryMsg};
+use astroport::pair::{
+    ConfigResponse as PairConfigResponse, QueryMsg as AstroportPairQueryMsg, SimulationResponse,
+    StablePoolConfig,
+};
+use cw20::{BalanceResponse as Cw20BalanceResponse, Cw20QueryMsg};
+use terra_cosmwasm::{
+    TaxCapResponse, TaxRateResponse, TerraMsgWrapper, TerraQuery, TerraQueryWrapper,
+};
+use terraswap::asset::AssetInfo;
+use white_whale::fee::{Fee, VaultFee};
+use white_whale::ust_vault::msg::{ExecuteMsg as WhiteWhaleExecuteMsg, FeeResponse, VaultQueryMsg};
+
+pub const OWNER: &str = "owner";
+pub const VAULT: &str = "vault";
+pub const ASTROPORT_FACTORY: &str = "astroport_factory";
+// commission of the mocked xyk pairs, in thousandths
+const PAIR_COMMISSION: u128 = 3;
+
+type ContractHandler = Box<dyn Fn(&Binary) -> StdResult<Binary>>;
+
+/// ## Description
+/// A querier mocking the Terra treasury, bank balances, cw20 balances and contracts answering
+/// smart queries, so that the execute paths can be tested.
+pub struct WasmMockQuerier {
+    base: MockQuerier<TerraQueryWrapper>,
+    contracts: HashMap<String, ContractHandler>,
+    token_balances: HashMap<String, HashMap<String, Uint128>>,
+    astroport_pairs: Vec<PairInfo>,
+    tax_rate: Decimal,
+    tax_cap: Uint128,
+    query_count: Cell<u64>,
+}
+
+/// ## Description
+/// Returns dependencies backed by a [`WasmMockQuerier`], the contract holding `contract_balance`.
+pub fn mock_dependencies(
+    contract_balance: &[Coin],
+) -> OwnedDeps<MockStorage, MockApi, WasmMockQuerier> {
+    let contract_address = mock_env().contract.address;
+    OwnedDeps {
+        storage: MockStorage::default(),
+        api: MockApi::default(),
+        querier: WasmMockQuerier::new(MockQuerier::new(&[(
+            contract_address.as_str(),
+            contract_balance,
+        )])),
+    }
+}
+
+impl Querier for WasmMockQuerier {
+    fn raw_query(&self, bin_request: &[u8]) -> QuerierResult {
+        let request: QueryRequest<TerraQueryWrapper> = match from_slice(bin_request) {
+            Ok(v) => v,
+            Err(e) => {
+                return SystemResult::Err(SystemError::InvalidRequest {
+                    error: format!("Parsing query request: {}", e),
+                    request: bin_request.into(),
+                })
+            }
+        };
+        self.handle_query(&request)
+    }
+}
+
+impl WasmMockQuerier {
+    pub fn new(base: MockQuerier<TerraQueryWrapper>) -> Self {
+        WasmMockQuerier {
+            base,
+            contracts: HashMap::new(),
+            token_balances: HashMap::new(),
+            astroport_pairs: vec![],
+            tax_rate: Decimal::zero(),
+            tax_cap: Uint128::zero(),
+            query_count: Cell::new(0),
+        }
+    }
+
+    /// Returns the number of queries answered so far.
+    pub fn query_count(&self) -> u64 {
+        self.query_count.get()
+    }
+
+    pub fn handle_query(&self, request: &QueryRequest<TerraQueryWrapper>) -> QuerierResult {
+        self.query_count.set(self.query_count.get() + 1);
+        match request {
+            QueryRequest::Custom(TerraQueryWrapper { query_data, .. }) => match query_data {
+                TerraQuery::TaxRate {} => reply(to_binary(&TaxRateResponse {
+                    rate: self.tax_rate,
+                })),
+                TerraQuery::TaxCap { .. } => {
+                    reply(to_binary(&TaxCapResponse { cap: self.tax_cap }))
+                }
+                _ => SystemResult::Err(SystemError::UnsupportedRequest {
+                    kind: "terra".to_string(),
+                }),
+            },
+            QueryRequest::Wasm(WasmQuery::Smart { contract_addr, msg }) => {
+                if contract_addr == ASTROPORT_FACTORY {
+                    return reply(self.query_astroport_factory(msg));
+                }
+                if let Some(handler) = self.contracts.get(contract_addr) {
+                    return reply(handler(msg));
+                }
+                match self.token_balances.get(contract_addr) {
+                    Some(balances) => reply(match from_binary(msg) {
+                        Ok(Cw20QueryMsg::Balance { address }) => to_binary(&Cw20BalanceResponse {
+                            balance: balances.get(&address).cloned().unwrap_or_default(),
+                        }),
+                        _ => Err(StdError::generic_err("Unsupported token query")),
+                    }),
+                    None => SystemResult::Err(SystemError::NoSuchContract {
+                        addr: contract_addr.clone(),
+                    }),
+                }
+            }
+            _ => self.base.handle_query(request),
+        }
+    }
+
+    /// Answers the smart queries sent to `address` with `handler`.
+    pub fn with_contract<F>(&mut self, address: &str, handler: F)
+    where
+        F: Fn(&Binary) -> StdResult<Binary> + 'static,
+    {
+        self.contracts
+            .insert(address.to_string(), Box::new(handler));
+    }
+
+    /// Sets the native balance of `address`.
+    pub fn with_balance(&mut self, address: &str, balance: &[Coin]) {
+        self.base.update_balance(address, balance.to_vec());
+    }
+
+    /// Sets the cw20 balance of `holder` in `token`.
+    pub fn with_token_balance(&mut self, token: &str, holder: &str, amount: u128) {
+        self.token_balances
+            .entry(token.to_string())
+            .or_default()
+            .insert(holder.to_string(), Uint128::new(amount));
+    }
+
+    /// Sets the Terra tax rate and the cap of every denom.
+    pub fn with_tax(&mut self, rate: Decimal, cap: u128) {
+        self.tax_rate = rate;
+        self.tax_cap = Uint128::new(cap);
+    }
+
+    fn query_astroport_factory(&self, msg: &Binary) -> StdResult<Binary> {
+        match from_binary(msg)? {
+            AstroportFactoryQueryMsg::Pair { asset_infos } => to_binary(
+                self.astroport_pairs
+                    .iter()
+                    .find(|pair| {
+                        asset_infos
+                            .iter()
+                            .all(|info| pair.asset_infos.contains(info))
+                    })
+                    .ok_or_else(|| StdError::generic_err("Pair not found"))?,
+            ),
+            _ => Err(StdError::generic_err("Unsupported factory query")),
+        }
+    }
+}
+
+fn reply(response: StdResult<Binary>) -> QuerierResult {
+    SystemResult::Ok(ContractResult::from(response))
+}
+
+/// ## Description
+/// Instantiates the contract with UST as base asset, a zero profit threshold and a 1% slippage
+/// tolerance, `OWNER` being the owner.
+pub fn mock_instantiate(deps: DepsMut) {
+    instantiate(
+        deps,
+        mock_env(),
+        mock_info(OWNER, &[]),
+        InstantiateMsg {
+            vault_address: VAULT.to_string(),
+            incentive_address: "incentives".to_string(),
+            astroport_factory_address: ASTROPORT_FACTORY.to_string(),
+            astroport_router_address: "astroport_router".to_string(),
+            terraswap_factory_address: "terraswap_factory".to_string(),
+            aust_token_address: "aust".to_string(),
+            anchor_market_contract: "anchor_market".to_string(),
+            profit_threshold: Uint128::zero(),
+            slippage_tolerance: Decimal::percent(1),
+            base_asset: AssetInfo::NativeToken {
+                denom: "uusd".to_string(),
+            },
+            nebula_factory_address: None,
+        },
+    )
+    .unwrap();
+}
+
+/// ## Description
+/// Mocks the White Whale vault charging `flash_loan_fee` on flash loans.
+pub fn mock_vault(querier: &mut WasmMockQuerier, flash_loan_fee: Decimal) {
+    querier.with_contract(VAULT, move |msg| match from_binary(msg)? {
+        VaultQueryMsg::Fees {} => to_binary(&FeeResponse {
+            fees: VaultFee {
+                flash_loan_fee: Fee {
+                    share: flash_loan_fee,
+                },
+                treasury_fee: Fee {
+                    share: Decimal::zero(),
+                },
+                commission_fee: Fee {
+                    share: Decimal::zero(),
+                },
+                treasury_addr: Addr::unchecked("vault_treasury"),
+            },
+        }),
+        _ => Err(StdError::generic_err("Unsupported vault query")),
+    });
+}
+
+/// ## Description
+/// Mocks a cluster `name` holding `inventory` UST for a `supply` of CT, its penalty contract
+/// minting and redeeming at the net asset value, and its Astroport xyk pair holding
+/// `pool_base` UST and `pool_ct` CT. The CT is `{name}_token` and the pair `{name}_pair`.
+pub fn mock_cluster(
+    querier: &mut WasmMockQuerier,
+    name: &str,
+    inventory: u128,
+    supply: u128,
+    pool_base: u128,
+    pool_ct: u128,
+) {
+    let cluster_token = format!("{}_token", name);
+    let penalty = format!("{}_penalty", name);
+    let pair = format!("{}_pair", name);
+    let ust = AstroportAssetInfo::NativeToken {
+        denom: "uusd".to_string(),
+    };
+    let ct = AstroportAssetInfo::Token {
+        contract_addr: Addr::unchecked(cluster_token.as_str()),
+    };
+
+    let cluster_state = ClusterStateResponse {
+        outstanding_balance_tokens: Uint128::new(supply),
+        prices: vec!["1".to_string()],
+        inv: vec![Uint128::new(inventory)],
+        penalty: penalty.clone(),
+        cluster_token: cluster_token.clone(),
+        target: vec![AstroportAsset {
+            info: ust.clone(),
+            amount: Uint128::new(1),
+        }],
+        cluster_contract_address: name.to_string(),
+        active: true,
+    };
+    querier.with_contract(name, move |msg| match from_binary(msg)? {
+        QueryMsgNebula::ClusterState {} => to_binary(&cluster_state),
+    });
+
+    querier.with_contract(penalty.as_str(), |msg| match from_binary(msg)? {
+        PenaltyQueryMsg::Mint {
+            cluster_token_supply,
+            inventory,
+            mint_asset_amounts,
+            asset_prices,
+            ..
+        } => to_binary(&PenaltyCreateResponse {
+            create_tokens: cluster_token_supply.multiply_ratio(
+                basket_value(&mint_asset_amounts, &asset_prices)?,
+                basket_value(&inventory, &asset_prices)?,
+            ),
+            penalty: Uint128::zero(),
+            attributes: vec![],
+        }),
+        PenaltyQueryMsg::Redeem {
+            cluster_token_supply,
+            inventory,
+            max_tokens,
+            ..
+        } => to_binary(&PenaltyRedeemResponse {
+            redeem_assets: inventory
+                .iter()
+                .map(|amount| amount.multiply_ratio(max_tokens, cluster_token_supply))
+                .collect(),
+            penalty: Uint128::zero(),
+            token_cost: max_tokens,
+            attributes: vec![],
+        }),
+    });
+
+    let pair_info = PairInfo {
+        asset_infos: [ust.clone(), ct],
+        contract_addr: Addr::unchecked(pair.as_str()),
+        liquidity_token: Addr::unchecked(format!("{}_lp", pair)),
+        pair_type: PairType::Xyk {},
+    };
+    querier.with_balance(pair.as_str(), &[coin(pool_base, "uusd")]);
+    querier.with_token_balance(cluster_token.as_str(), pair.as_str(), pool_ct);
+    querier.astroport_pairs.push(pair_info.clone());
+    querier.with_contract(pair.as_str(), move |msg| match from_binary(msg)? {
+        AstroportPairQueryMsg::Pair {} => to_binary(&pair_info),
+        AstroportPairQueryMsg::Simulation { offer_asset } => {
+            let (offer_pool, ask_pool) = if offer_asset.info == ust {
+                (pool_base, pool_ct)
+            } else {
+                (pool_ct, pool_base)
+            };
+            let offer_amount = offer_asset.amount.u128();
+            let return_amount = ask_pool * offer_amount / (offer_pool + offer_amount);
+            let commission_amount = return_amount * PAIR_COMMISSION / 1000;
+            to_binary(&SimulationResponse {
+                return_amount: Uint128::new(return_amount - commission_amount),
+                spread_amount: Uint128::zero(),
+                commission_amount: Uint128::new(commission_amount),
+            })
+        }
+        _ => Err(StdError::generic_err("Unsupported pair query")),
+    });
+}
+
+fn basket_value(amounts: &[Uint128], prices: &[String]) -> StdResult<Uint128> {
+    amounts
+        .iter()
+        .zip(prices.iter())
+        .map(|(amount, price)| Ok(*amount * Decimal::from_str(price)?))
+        .sum()
+}
+
+/// ## Description
+/// Mocks a cluster like [`mock_cluster`] whose CT trades on a stable pair with `amp`, the pair
+/// config having no parameters if none.
+pub fn mock_stable_cluster(
+    querier: &mut WasmMockQuerier,
+    name: &str,
+    inventory: u128,
+    supply: u128,
+    pool_base: u128,
+    pool_ct: u128,
+    amp: Option<u64>,
+) {
+    mock_cluster(querier, name, inventory, supply, pool_base, pool_ct);
+
+    let pair = format!("{}_pair", name);
+    let pair_info = querier
+        .astroport_pairs
+        .iter_mut()
+        .find(|pair_info| pair_info.contract_addr == pair)
+        .unwrap();
+    pair_info.pair_type = PairType::Stable {};
+    let pair_info = pair_info.clone();
+    let ust = pair_info.asset_infos[0].clone();
+    querier.with_contract(pair.as_str(), move |msg| match from_binary(msg)? {
+        AstroportPairQueryMsg::Pair {} => to_binary(&pair_info),
+        AstroportPairQueryMsg::Config {} => to_binary(&PairConfigResponse {
+            block_time_last: 0,
+            params: amp
+                .map(|amp| {
+                    to_binary(&StablePoolConfig {
+                        amp: Decimal::from_ratio(amp, 1u64),
+                    })
+                })
+                .transpose()?,
+        }),
+        AstroportPairQueryMsg::Simulation { offer_asset } => {
+            let ann = Uint128::new(2 * amp.unwrap_or_default() as u128);
+            let (offer_pool, ask_pool) = if offer_asset.info == ust {
+                (pool_base, pool_ct)
+            } else {
+                (pool_ct, pool_base)
+            };
+            let d = compute_d(ann, Uint128::new(offer_pool), Uint128::new(ask_pool))?;
+            let ask_left = compute_y(ann, Uint128::new(offer_pool) + offer_asset.amount, d)?;
+            let return_amount = ask_pool - ask_left.u128();
+            let commission_amount = return_amount * PAIR_COMMISSION / 1000;
+            to_binary(&SimulationResponse {
+                return_amount: Uint128::new(return_amount - commission_amount),
+                spread_amount: Uint128::zero(),
+                commission_amount: Uint128::new(commission_amount),
+            })
+        }
+        _ => Err(StdError::generic_err("Unsupported pair query")),
+    });
+}
+
+/// ## Description
+/// Returns the callback a flash loan response asks the vault to execute.
+pub fn flash_loan_callback(response: &Response<TerraMsgWrapper>) -> ExecuteMsg {
+    let payload = match &response.messages[0].msg {
+        CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr, msg, ..
+        }) if contract_addr == VAULT => match from_binary(msg).unwrap() {
+            WhiteWhaleExecuteMsg::FlashLoan { payload } => payload,
+            _ => panic!("not a flash loan"),
+        },
+        _ => panic!("not a vault message"),
+    };
+    from_binary(&payload.callback).unwrap()
+}
+
+/// ## Description
+/// Returns a successful reply `id` carrying the wasm events of `contract` with `attributes`.
+pub fn mock_reply(id: u64, contract: &str, attributes: &[(&str, &str)]) -> Reply {
+    let event = Event::new("wasm")
+        .add_attribute("contract_address", contract)
+        .add_attributes(attributes.iter().cloned());
+    Reply {
+        id,
+        result: ContractResult::Ok(SubMsgExecutionResponse {
+            events: vec![event],
+            data: None,
+        }),
+    }
+}
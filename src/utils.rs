@@ -1,45 +1,64 @@
 use cosmwasm_std::{
-    coin, to_binary, Addr, CosmosMsg, Deps, QuerierWrapper, QueryRequest, StdResult, Uint128,
-    WasmMsg, WasmQuery,
+    coin, to_binary, Addr, BankMsg, CosmosMsg, Decimal, Deps, Event, Fraction, QuerierWrapper,
+    QueryRequest, StdError, StdResult, SubMsg, Uint128, WasmMsg, WasmQuery,
 };
 
 use cw20::Cw20ExecuteMsg;
 
-use crate::msg::{ClusterStateResponse, QueryMsgNebula};
+use crate::error::ContractError;
+use crate::msg::{
+    ClusterStateResponse, PenaltyCreateResponse, PenaltyQueryMsg, PenaltyRedeemResponse,
+    QueryMsgNebula, WrapperAdapter,
+};
+use crate::state::{State, CLUSTERS, WRAPPERS};
+use crate::wrapper::{create_unwrap_msgs, create_wrap_msgs, simulate_unwrap, simulate_wrap};
+
+use terra_cosmwasm::{create_swap_msg, TerraMsgWrapper, TerraQuerier};
+
+use terraswap::asset::{Asset, AssetInfo};
+use terraswap::pair::{
+    Cw20HookMsg as TerraswapCw20HookMsg, ExecuteMsg as TerraswapExecuteMsg,
+    QueryMsg as TerraswapQueryMsg, SimulationResponse as TerraswapSimulationResponse,
+};
+use terraswap::querier::query_pair_info as terraswap_query_pair_info;
 
-use terra_cosmwasm::{create_swap_msg, TerraMsgWrapper};
+use white_whale::astroport_helper::{
+    AstroportCw20HookMsg, AstroportExecuteMsg as AstroportRouterExecuteMsg,
+    AstroportQueryMsg as AstroportRouterQueryMsg, SimulateSwapOperationsResponse, SwapOperation,
+};
+use white_whale::fee::Fee;
+use white_whale::ust_vault::msg::{FeeResponse, VaultQueryMsg};
 
+use astroport::asset::PairInfo;
 use astroport::asset::{Asset as AstroportAsset, AssetInfo as AstroportAssetInfo};
-use astroport::pair::{Cw20HookMsg as AstroportCw20HookMsg, ExecuteMsg as AstroportExecuteMsg};
-use astroport::querier::query_pair_info;
+use astroport::pair::{
+    Cw20HookMsg as AstroportPairCw20HookMsg, ExecuteMsg as AstroportExecuteMsg,
+    QueryMsg as AstroportPairQueryMsg,
+};
+use astroport::querier::{query_pair_info, simulate};
 
-use moneymarket::market::{Cw20HookMsg as AnchorCw20HookMsg, ExecuteMsg as AnchorExecuteMsg};
+use std::fmt;
+
+// intermediate assets tried when a basket asset has no direct pair
+const ROUTE_HOP_DENOMS: [&str; 2] = ["uusd", "uluna"];
 /// ## Description
 /// Swap token from Astroport pool
 ///
 /// ## Params
-/// - **querier** is a reference to an object of type [`QuerierWrapper`].
-///
 /// - **offer_asset** is an object of type [`AstroportAsset`].
 ///
-/// - **to_asset** is an object of type [`AstroportAssetInfo`].
+/// - **pair_contract** is an object of type [`Addr`].
 ///
-/// - **astroport_factory_address** is an object of type [`Addr`].
+/// - **belief_price** is an object of type [`Option<Decimal>`] which is the expected price of the swap.
 ///
+/// - **max_spread** is an object of type [`Option<Decimal>`] which is the accepted spread from the belief price.
 pub fn create_astroport_swap_msg(
-    querier: &QuerierWrapper,
     offer_asset: AstroportAsset,
-    to_asset: AstroportAssetInfo,
-    astroport_factory_address: Addr,
+    pair_contract: Addr,
+    belief_price: Option<Decimal>,
+    max_spread: Option<Decimal>,
 ) -> StdResult<CosmosMsg<TerraMsgWrapper>> {
-    // query pair contract
-    let pair_contract = query_pair_info(
-        querier,
-        astroport_factory_address,
-        &[to_asset, offer_asset.clone().info],
-    )?
-    .contract_addr
-    .to_string();
+    let pair_contract = pair_contract.to_string();
 
     match offer_asset.info.clone() {
         AstroportAssetInfo::Token { contract_addr } => {
@@ -49,9 +68,9 @@ pub fn create_astroport_swap_msg(
                 msg: to_binary(&Cw20ExecuteMsg::Send {
                     contract: pair_contract,
                     amount: offer_asset.amount,
-                    msg: to_binary(&AstroportCw20HookMsg::Swap {
-                        max_spread: None,
-                        belief_price: None,
+                    msg: to_binary(&AstroportPairCw20HookMsg::Swap {
+                        max_spread,
+                        belief_price,
                         to: None,
                     })?,
                 })?,
@@ -63,8 +82,8 @@ pub fn create_astroport_swap_msg(
                 contract_addr: pair_contract,
                 msg: to_binary(&AstroportExecuteMsg::Swap {
                     offer_asset: offer_asset.clone(),
-                    belief_price: None,
-                    max_spread: None,
+                    belief_price,
+                    max_spread,
                     to: None,
                 })?,
                 funds: vec![coin(offer_asset.amount.u128(), denom)],
@@ -83,32 +102,509 @@ pub fn create_terraswap_swap_msg(
     Ok(message)
 }
 
-pub fn create_aust_swap_msg(
-    anchor_market_contract: Addr,
-    aust_contract_contract: Addr,
-    amount: Uint128,
-    to_ust: bool,
+/// ## Description
+/// A venue able to swap one asset to another.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Venue {
+    /// The Terra market module, for two native tokens
+    Market,
+    /// Mints a registered wrapper token from its underlying asset
+    Wrap(Addr, WrapperAdapter),
+    /// Burns a registered wrapper token to its underlying asset
+    Unwrap(Addr, WrapperAdapter),
+    /// A Terraswap pair
+    Terraswap(Addr),
+    /// An Astroport pair
+    Astroport(Addr),
+    /// The Astroport router following the operations
+    AstroportRouter(Vec<SwapOperation>),
+}
+
+impl fmt::Display for Venue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Venue::Market => write!(f, "market"),
+            Venue::Wrap(_, adapter) => write!(f, "{}_mint", adapter),
+            Venue::Unwrap(_, adapter) => write!(f, "{}_burn", adapter),
+            Venue::Terraswap(_) => write!(f, "terraswap"),
+            Venue::Astroport(_) => write!(f, "astroport"),
+            Venue::AstroportRouter(_) => write!(f, "astroport_router"),
+        }
+    }
+}
+
+/// ## Description
+/// Simulates `offer_asset` to `to_asset` on every available venue, the Terra market, the
+/// registered wrappers, Terraswap and Astroport, and returns the one with the best output
+/// together with that output.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **state** is a reference to an object of type [`State`].
+///
+/// - **offer_asset** is a reference to an object of type [`AstroportAsset`].
+///
+/// - **to_asset** is a reference to an object of type [`AstroportAssetInfo`].
+///
+/// - **block_height** is an object of type [`u64`] which is the height wrappers are priced at.
+pub fn query_best_venue(
+    deps: Deps,
+    state: &State,
+    offer_asset: &AstroportAsset,
+    to_asset: &AstroportAssetInfo,
+    block_height: u64,
+) -> StdResult<(Venue, Uint128)> {
+    let mut quotes: Vec<(Venue, Uint128)> = vec![];
+
+    if let (
+        AstroportAssetInfo::NativeToken { denom: offer_denom },
+        AstroportAssetInfo::NativeToken { denom: to_denom },
+    ) = (&offer_asset.info, to_asset)
+    {
+        if let Ok(response) = TerraQuerier::new(&deps.querier).query_swap(
+            coin(offer_asset.amount.u128(), offer_denom.as_str()),
+            to_denom.as_str(),
+        ) {
+            quotes.push((Venue::Market, response.receive.amount));
+        }
+    }
+
+    // registered wrappers are minted from and burned to their underlying asset
+    if let AstroportAssetInfo::Token { contract_addr } = to_asset {
+        if let Some(adapter) = WRAPPERS.may_load(deps.storage, contract_addr)? {
+            if adapter.underlying().equal(&offer_asset.info) {
+                if let Ok(amount) =
+                    simulate_wrap(deps, state, &adapter, offer_asset.amount, block_height)
+                {
+                    quotes.push((Venue::Wrap(contract_addr.clone(), adapter), amount));
+                }
+            }
+        }
+    }
+    if let AstroportAssetInfo::Token { contract_addr } = &offer_asset.info {
+        if let Some(adapter) = WRAPPERS.may_load(deps.storage, contract_addr)? {
+            if adapter.underlying().equal(to_asset) {
+                if let Ok(Some(amount)) =
+                    simulate_unwrap(deps, state, &adapter, offer_asset.amount, block_height)
+                {
+                    quotes.push((Venue::Unwrap(contract_addr.clone(), adapter), amount));
+                }
+            }
+        }
+    }
+
+    if let Ok(pair_info) = terraswap_query_pair_info(
+        &deps.querier,
+        state.terraswap_factory_address.clone(),
+        &[
+            to_terraswap_asset_info(&offer_asset.info),
+            to_terraswap_asset_info(to_asset),
+        ],
+    ) {
+        let pair_contract = deps.api.addr_validate(pair_info.contract_addr.as_str())?;
+        if let Ok(amount) = simulate_terraswap_swap(&deps.querier, &pair_contract, offer_asset) {
+            quotes.push((Venue::Terraswap(pair_contract), amount));
+        }
+    }
+
+    match query_swap_route(&deps.querier, state, &offer_asset.info, to_asset) {
+        Ok(SwapRoute::Direct) => {
+            let pair_info = query_pair_info(
+                &deps.querier,
+                state.astroport_factory_address.clone(),
+                &[offer_asset.info.clone(), to_asset.clone()],
+            )?;
+            if let Ok(response) =
+                simulate(&deps.querier, pair_info.contract_addr.clone(), offer_asset)
+            {
+                quotes.push((
+                    Venue::Astroport(pair_info.contract_addr),
+                    response.return_amount,
+                ));
+            }
+        }
+        Ok(SwapRoute::Operations(operations)) => {
+            if let Ok(amount) = simulate_router_swap(
+                &deps.querier,
+                offer_asset.amount,
+                operations.clone(),
+                &state.astroport_router_address,
+            ) {
+                quotes.push((Venue::AstroportRouter(operations), amount));
+            }
+        }
+        Err(_) => {}
+    }
+
+    // keep the first venue on ties
+    quotes
+        .into_iter()
+        .fold(None, |best: Option<(Venue, Uint128)>, quote| match best {
+            Some(best) if best.1 >= quote.1 => Some(best),
+            _ => Some(quote),
+        })
+        .ok_or_else(|| {
+            StdError::generic_err(format!(
+                "No venue to swap {} to {}",
+                offer_asset.info, to_asset
+            ))
+        })
+}
+
+/// ## Description
+/// Swaps `offer_asset` to `to_asset` through `venue`, usually chosen with [`query_best_venue`].
+/// Wrappers may need several messages.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **state** is a reference to an object of type [`State`].
+///
+/// - **offer_asset** is an object of type [`AstroportAsset`].
+///
+/// - **to_asset** is an object of type [`AstroportAssetInfo`].
+///
+/// - **venue** is an object of type [`Venue`].
+///
+/// - **belief_price** is an object of type [`Option<Decimal>`] which bounds the pool swaps
+///   together with **max_spread**.
+///
+/// - **max_spread** is an object of type [`Option<Decimal>`].
+pub fn create_asset_swap_msgs(
+    deps: Deps,
+    state: &State,
+    offer_asset: AstroportAsset,
+    to_asset: AstroportAssetInfo,
+    venue: Venue,
+    belief_price: Option<Decimal>,
+    max_spread: Option<Decimal>,
+) -> StdResult<Vec<CosmosMsg<TerraMsgWrapper>>> {
+    let message = match venue {
+        Venue::Market => match (&offer_asset.info, &to_asset) {
+            (
+                AstroportAssetInfo::NativeToken { denom: offer_denom },
+                AstroportAssetInfo::NativeToken { denom: to_denom },
+            ) => create_terraswap_swap_msg(
+                offer_asset.amount.u128(),
+                offer_denom.clone(),
+                to_denom.clone(),
+            ),
+            _ => Err(StdError::generic_err("The market only swaps native tokens")),
+        },
+        Venue::Wrap(_, adapter) => {
+            return create_wrap_msgs(deps, state, &adapter, offer_asset.amount)
+        }
+        Venue::Unwrap(token, adapter) => {
+            return create_unwrap_msgs(deps, state, &token, &adapter, offer_asset.amount)
+        }
+        Venue::Terraswap(pair_contract) => {
+            create_terraswap_pool_swap_msg(offer_asset, pair_contract, belief_price, max_spread)
+        }
+        Venue::Astroport(pair_contract) => {
+            create_astroport_swap_msg(offer_asset, pair_contract, belief_price, max_spread)
+        }
+        Venue::AstroportRouter(operations) => {
+            // the router has no spread check, bound the end of the route instead
+            let minimum_receive = match (belief_price, max_spread) {
+                (Some(belief_price), Some(max_spread)) if !belief_price.is_zero() => Some(
+                    offer_asset
+                        .amount
+                        .multiply_ratio(belief_price.denominator(), belief_price.numerator())
+                        * (Decimal::one() - max_spread),
+                ),
+                _ => None,
+            };
+            create_router_swap_msg(
+                offer_asset,
+                operations,
+                minimum_receive,
+                state.astroport_router_address.clone(),
+            )
+        }
+    }?;
+    Ok(vec![message])
+}
+
+/// ## Description
+/// Swaps `offer_asset` in a Terraswap pair.
+///
+/// ## Params
+/// - **offer_asset** is an object of type [`AstroportAsset`].
+///
+/// - **pair_contract** is an object of type [`Addr`].
+///
+/// - **belief_price** is an object of type [`Option<Decimal>`] which is the expected price of the swap.
+///
+/// - **max_spread** is an object of type [`Option<Decimal>`] which is the accepted spread from the belief price.
+pub fn create_terraswap_pool_swap_msg(
+    offer_asset: AstroportAsset,
+    pair_contract: Addr,
+    belief_price: Option<Decimal>,
+    max_spread: Option<Decimal>,
 ) -> StdResult<CosmosMsg<TerraMsgWrapper>> {
-    let message = if to_ust {
-        WasmMsg::Execute {
-            contract_addr: aust_contract_contract.to_string(),
+    let message = match &offer_asset.info {
+        AstroportAssetInfo::Token { contract_addr } => WasmMsg::Execute {
+            contract_addr: contract_addr.to_string(),
             msg: to_binary(&Cw20ExecuteMsg::Send {
-                contract: anchor_market_contract.to_string(),
-                amount,
-                msg: to_binary(&AnchorCw20HookMsg::RedeemStable {})?,
+                contract: pair_contract.to_string(),
+                amount: offer_asset.amount,
+                msg: to_binary(&TerraswapCw20HookMsg::Swap {
+                    belief_price,
+                    max_spread,
+                    to: None,
+                })?,
             })?,
             funds: vec![],
+        },
+        AstroportAssetInfo::NativeToken { denom } => WasmMsg::Execute {
+            contract_addr: pair_contract.to_string(),
+            msg: to_binary(&TerraswapExecuteMsg::Swap {
+                offer_asset: Asset {
+                    info: to_terraswap_asset_info(&offer_asset.info),
+                    amount: offer_asset.amount,
+                },
+                belief_price,
+                max_spread,
+                to: None,
+            })?,
+            funds: vec![coin(offer_asset.amount.u128(), denom)],
+        },
+    };
+    Ok(CosmosMsg::Wasm(message))
+}
+
+/// ## Description
+/// Returns the amount received by swapping `offer_asset` in a Terraswap pair.
+///
+/// ## Params
+/// - **querier** is a reference to an object of type [`QuerierWrapper`].
+///
+/// - **pair_contract** is a reference to an object of type [`Addr`].
+///
+/// - **offer_asset** is a reference to an object of type [`AstroportAsset`].
+pub fn simulate_terraswap_swap(
+    querier: &QuerierWrapper,
+    pair_contract: &Addr,
+    offer_asset: &AstroportAsset,
+) -> StdResult<Uint128> {
+    let response: TerraswapSimulationResponse = querier.query_wasm_smart(
+        pair_contract.to_string(),
+        &TerraswapQueryMsg::Simulation {
+            offer_asset: Asset {
+                info: to_terraswap_asset_info(&offer_asset.info),
+                amount: offer_asset.amount,
+            },
+        },
+    )?;
+    Ok(response.return_amount)
+}
+
+/// ## Description
+/// A route between two assets on Astroport.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SwapRoute {
+    /// The assets share a pair in the factory
+    Direct,
+    /// The assets are swapped through the router with these operations
+    Operations(Vec<SwapOperation>),
+}
+
+/// ## Description
+/// Returns the route to swap `offer_asset` to `to_asset`. A direct pair is preferred, otherwise
+/// a route with a single hop through UST, LUNA or the base asset is searched.
+///
+/// ## Params
+/// - **querier** is a reference to an object of type [`QuerierWrapper`].
+///
+/// - **state** is a reference to an object of type [`State`].
+///
+/// - **offer_asset** is a reference to an object of type [`AstroportAssetInfo`].
+///
+/// - **to_asset** is a reference to an object of type [`AstroportAssetInfo`].
+pub fn query_swap_route(
+    querier: &QuerierWrapper,
+    state: &State,
+    offer_asset: &AstroportAssetInfo,
+    to_asset: &AstroportAssetInfo,
+) -> StdResult<SwapRoute> {
+    if query_pair_info(
+        querier,
+        state.astroport_factory_address.clone(),
+        &[offer_asset.clone(), to_asset.clone()],
+    )
+    .is_ok()
+    {
+        return Ok(SwapRoute::Direct);
+    }
+
+    let mut hops: Vec<AstroportAssetInfo> = ROUTE_HOP_DENOMS
+        .iter()
+        .map(|denom| AstroportAssetInfo::NativeToken {
+            denom: denom.to_string(),
+        })
+        .collect();
+    hops.push(state.base_asset_info());
+
+    for hop in hops {
+        if &hop == offer_asset || &hop == to_asset {
+            continue;
         }
-    } else {
-        WasmMsg::Execute {
-            contract_addr: anchor_market_contract.to_string(),
-            msg: to_binary(&AnchorExecuteMsg::DepositStable {})?,
-            funds: vec![coin(amount.u128(), "uusd")],
+        let first = swap_operation(querier, state, offer_asset, &hop);
+        let second = swap_operation(querier, state, &hop, to_asset);
+        if let (Some(first), Some(second)) = (first, second) {
+            return Ok(SwapRoute::Operations(vec![first, second]));
         }
+    }
+
+    Err(StdError::generic_err(format!(
+        "No Astroport route from {} to {}",
+        offer_asset, to_asset
+    )))
+}
+
+/// Returns the router operation swapping `offer_asset` to `ask_asset`, if any venue has it.
+fn swap_operation(
+    querier: &QuerierWrapper,
+    state: &State,
+    offer_asset: &AstroportAssetInfo,
+    ask_asset: &AstroportAssetInfo,
+) -> Option<SwapOperation> {
+    match (offer_asset, ask_asset) {
+        (
+            AstroportAssetInfo::NativeToken { denom: offer_denom },
+            AstroportAssetInfo::NativeToken { denom: ask_denom },
+        ) => Some(SwapOperation::NativeSwap {
+            offer_denom: offer_denom.clone(),
+            ask_denom: ask_denom.clone(),
+        }),
+        _ => query_pair_info(
+            querier,
+            state.astroport_factory_address.clone(),
+            &[offer_asset.clone(), ask_asset.clone()],
+        )
+        .ok()
+        .map(|_| SwapOperation::AstroSwap {
+            offer_asset_info: to_terraswap_asset_info(offer_asset),
+            ask_asset_info: to_terraswap_asset_info(ask_asset),
+        }),
+    }
+}
+
+/// ## Description
+/// Swaps `offer_asset` through the Astroport router following `operations`.
+///
+/// ## Params
+/// - **offer_asset** is an object of type [`AstroportAsset`].
+///
+/// - **operations** is an object of type [`Vec<SwapOperation>`].
+///
+/// - **minimum_receive** is an object of type [`Option<Uint128>`] which is checked at the end of the route.
+///
+/// - **astroport_router_address** is an object of type [`Addr`].
+pub fn create_router_swap_msg(
+    offer_asset: AstroportAsset,
+    operations: Vec<SwapOperation>,
+    minimum_receive: Option<Uint128>,
+    astroport_router_address: Addr,
+) -> StdResult<CosmosMsg<TerraMsgWrapper>> {
+    let message = match offer_asset.info {
+        AstroportAssetInfo::Token { contract_addr } => WasmMsg::Execute {
+            contract_addr: contract_addr.to_string(),
+            msg: to_binary(&Cw20ExecuteMsg::Send {
+                contract: astroport_router_address.to_string(),
+                amount: offer_asset.amount,
+                msg: to_binary(&AstroportCw20HookMsg::ExecuteSwapOperations {
+                    operations,
+                    minimum_receive,
+                    to: None,
+                })?,
+            })?,
+            funds: vec![],
+        },
+        AstroportAssetInfo::NativeToken { denom } => WasmMsg::Execute {
+            contract_addr: astroport_router_address.to_string(),
+            msg: to_binary(&AstroportRouterExecuteMsg::ExecuteSwapOperations {
+                operations,
+                minimum_receive,
+                to: None,
+            })?,
+            funds: vec![coin(offer_asset.amount.u128(), denom)],
+        },
     };
     Ok(CosmosMsg::Wasm(message))
 }
 
+/// ## Description
+/// Returns the amount received by swapping `offer_amount` through the Astroport router.
+///
+/// ## Params
+/// - **querier** is a reference to an object of type [`QuerierWrapper`].
+///
+/// - **offer_amount** is an object of type [`Uint128`].
+///
+/// - **operations** is an object of type [`Vec<SwapOperation>`].
+///
+/// - **astroport_router_address** is a reference to an object of type [`Addr`].
+pub fn simulate_router_swap(
+    querier: &QuerierWrapper,
+    offer_amount: Uint128,
+    operations: Vec<SwapOperation>,
+    astroport_router_address: &Addr,
+) -> StdResult<Uint128> {
+    let response: SimulateSwapOperationsResponse = querier.query_wasm_smart(
+        astroport_router_address.to_string(),
+        &AstroportRouterQueryMsg::SimulateSwapOperations {
+            offer_amount,
+            operations,
+        },
+    )?;
+    Ok(response.amount)
+}
+
+/// ## Description
+/// Converts an Astroport asset info to the Terraswap one used by the router operations.
+///
+/// ## Params
+/// - **asset_info** is a reference to an object of type [`AstroportAssetInfo`].
+pub fn to_terraswap_asset_info(asset_info: &AstroportAssetInfo) -> AssetInfo {
+    match asset_info {
+        AstroportAssetInfo::NativeToken { denom } => AssetInfo::NativeToken {
+            denom: denom.clone(),
+        },
+        AstroportAssetInfo::Token { contract_addr } => AssetInfo::Token {
+            contract_addr: contract_addr.to_string(),
+        },
+    }
+}
+
+/// ## Description
+/// Transfers `asset` to `recipient`, with a bank send for native tokens or a cw20 transfer.
+///
+/// ## Params
+/// - **asset** is an object of type [`AstroportAsset`].
+///
+/// - **recipient** is a reference to an object of type [`Addr`].
+pub fn create_transfer_msg(
+    asset: AstroportAsset,
+    recipient: &Addr,
+) -> StdResult<CosmosMsg<TerraMsgWrapper>> {
+    Ok(match asset.info {
+        AstroportAssetInfo::NativeToken { denom } => CosmosMsg::Bank(BankMsg::Send {
+            to_address: recipient.to_string(),
+            amount: vec![coin(asset.amount.u128(), denom)],
+        }),
+        AstroportAssetInfo::Token { contract_addr } => CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: contract_addr.to_string(),
+            msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                recipient: recipient.to_string(),
+                amount: asset.amount,
+            })?,
+            funds: vec![],
+        }),
+    })
+}
+
 /// ## Description
 /// Returns the state of a cluster.
 ///
@@ -116,7 +612,7 @@ pub fn create_aust_swap_msg(
 /// - **deps** is an object of type [`Deps`].
 ///
 /// - **cluster** is a reference to an object of type [`Addr`] which is
-///     the address of a cluster.
+///   the address of a cluster.
 pub fn get_cluster_state(deps: Deps, cluster: &Addr) -> StdResult<ClusterStateResponse> {
     // Query the cluster state
     deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
@@ -124,3 +620,276 @@ pub fn get_cluster_state(deps: Deps, cluster: &Addr) -> StdResult<ClusterStateRe
         msg: to_binary(&QueryMsgNebula::ClusterState {})?,
     }))
 }
+
+/// ## Description
+/// Returns the CT minted by a cluster for `mint_asset_amounts`, as computed by its penalty contract.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **block_height** is an object of type [`u64`].
+///
+/// - **cluster_state** is a reference to an object of type [`ClusterStateResponse`].
+///
+/// - **mint_asset_amounts** is an object of type [`Vec<Uint128>`] in the order of the target.
+pub fn query_penalty_mint(
+    deps: Deps,
+    block_height: u64,
+    cluster_state: &ClusterStateResponse,
+    mint_asset_amounts: Vec<Uint128>,
+) -> StdResult<PenaltyCreateResponse> {
+    deps.querier.query_wasm_smart(
+        cluster_state.penalty.clone(),
+        &PenaltyQueryMsg::Mint {
+            block_height,
+            cluster_token_supply: cluster_state.outstanding_balance_tokens,
+            inventory: cluster_state.inv.clone(),
+            mint_asset_amounts,
+            asset_prices: cluster_state.prices.clone(),
+            target_weights: cluster_state
+                .target
+                .iter()
+                .map(|asset| asset.amount)
+                .collect(),
+        },
+    )
+}
+
+/// ## Description
+/// Returns the assets received and the CT burned by a pro-rata redeem of `max_tokens`, as computed
+/// by the penalty contract of the cluster.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **block_height** is an object of type [`u64`].
+///
+/// - **cluster_state** is a reference to an object of type [`ClusterStateResponse`].
+///
+/// - **max_tokens** is an object of type [`Uint128`].
+pub fn query_penalty_redeem(
+    deps: Deps,
+    block_height: u64,
+    cluster_state: &ClusterStateResponse,
+    max_tokens: Uint128,
+) -> StdResult<PenaltyRedeemResponse> {
+    deps.querier.query_wasm_smart(
+        cluster_state.penalty.clone(),
+        &PenaltyQueryMsg::Redeem {
+            block_height,
+            cluster_token_supply: cluster_state.outstanding_balance_tokens,
+            inventory: cluster_state.inv.clone(),
+            max_tokens,
+            redeem_asset_amounts: vec![],
+            asset_prices: cluster_state.prices.clone(),
+            target_weights: cluster_state
+                .target
+                .iter()
+                .map(|asset| asset.amount)
+                .collect(),
+        },
+    )
+}
+
+/// ## Description
+/// Returns the cluster token of a cluster and its Astroport pair with the base asset.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **state** is a reference to an object of type [`State`].
+///
+/// - **cluster** is a reference to an object of type [`Addr`] which is the address of a cluster.
+pub fn query_cluster_pair(deps: Deps, state: &State, cluster: &Addr) -> StdResult<(Addr, Addr)> {
+    let cluster_token = deps
+        .api
+        .addr_validate(get_cluster_state(deps, cluster)?.cluster_token.as_str())?;
+    let pair_info = query_cluster_pair_info(deps, state, cluster, &cluster_token)?;
+    Ok((cluster_token, pair_info.contract_addr))
+}
+
+/// ## Description
+/// Returns the Astroport CT pair of a cluster, the registered override if any, otherwise the
+/// pair of the base asset found through the factory.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **state** is a reference to an object of type [`State`].
+///
+/// - **cluster** is a reference to an object of type [`Addr`] which is the cluster contract.
+///
+/// - **cluster_token** is a reference to an object of type [`Addr`].
+pub fn query_cluster_pair_info(
+    deps: Deps,
+    state: &State,
+    cluster: &Addr,
+    cluster_token: &Addr,
+) -> StdResult<PairInfo> {
+    let pair_address = CLUSTERS
+        .may_load(deps.storage, cluster)?
+        .and_then(|cluster| cluster.pair_address);
+    match pair_address {
+        Some(pair_address) => deps
+            .querier
+            .query_wasm_smart(pair_address, &AstroportPairQueryMsg::Pair {}),
+        None => query_pair_info(
+            &deps.querier,
+            state.astroport_factory_address.clone(),
+            &[
+                state.base_asset_info(),
+                AstroportAssetInfo::Token {
+                    contract_addr: cluster_token.clone(),
+                },
+            ],
+        ),
+    }
+}
+
+/// ## Description
+/// Returns the belief price of a swap from its offer amount and the minimum it must return
+/// under `max_spread`, so that the pool enforces that minimum.
+///
+/// ## Params
+/// - **offer_amount** is an object of type [`Uint128`].
+///
+/// - **min_return** is an object of type [`Uint128`].
+///
+/// - **max_spread** is an object of type [`Decimal`].
+pub fn belief_price(
+    offer_amount: Uint128,
+    min_return: Uint128,
+    max_spread: Decimal,
+) -> Option<Decimal> {
+    if min_return.is_zero() {
+        return None;
+    }
+    Some(Decimal::from_ratio(
+        offer_amount * (Decimal::one() - max_spread),
+        min_return,
+    ))
+}
+
+/// ## Description
+/// Converts messages to sub messages, requesting a reply with `id` once the last one succeeds.
+///
+/// ## Params
+/// - **messages** is an object of type [`Vec<CosmosMsg<TerraMsgWrapper>>`].
+///
+/// - **id** is an object of type [`u64`] which is the reply id of the step.
+pub fn reply_on_last(
+    messages: Vec<CosmosMsg<TerraMsgWrapper>>,
+    id: u64,
+) -> Vec<SubMsg<TerraMsgWrapper>> {
+    let last = messages.len().saturating_sub(1);
+    messages
+        .into_iter()
+        .enumerate()
+        .map(|(i, msg)| {
+            if i == last {
+                SubMsg::reply_on_success(msg, id)
+            } else {
+                SubMsg::new(msg)
+            }
+        })
+        .collect()
+}
+
+/// ## Description
+/// Returns the first amount found under `key` in the wasm events of a sub message.
+///
+/// ## Params
+/// - **events** is a reference to a slice of [`Event`].
+///
+/// - **key** is a reference to an object of type [`str`] which is the attribute key.
+pub fn find_event_amount(events: &[Event], key: &str) -> Uint128 {
+    events
+        .iter()
+        .filter(|event| event.ty == "wasm")
+        .flat_map(|event| event.attributes.iter())
+        .find(|attribute| attribute.key == key)
+        .and_then(|attribute| attribute.value.parse::<u128>().ok())
+        .map(Uint128::from)
+        .unwrap_or_default()
+}
+
+/// ## Description
+/// Returns the amount found under `key` in the wasm events emitted by `contract_address`, if any.
+///
+/// ## Params
+/// - **events** is a reference to a slice of [`Event`].
+///
+/// - **contract_address** is a reference to an object of type [`Addr`] which is the emitter.
+///
+/// - **key** is a reference to an object of type [`str`] which is the attribute key.
+pub fn find_contract_event_amount(
+    events: &[Event],
+    contract_address: &Addr,
+    key: &str,
+) -> Option<Uint128> {
+    events
+        .iter()
+        .filter(|event| event.ty == "wasm")
+        .filter(|event| {
+            event.attributes.iter().any(|attribute| {
+                (attribute.key == "contract_address" || attribute.key == "_contract_address")
+                    && attribute.value == contract_address.as_str()
+            })
+        })
+        .flat_map(|event| event.attributes.iter())
+        .find(|attribute| attribute.key == key)
+        .and_then(|attribute| attribute.value.parse::<u128>().ok())
+        .map(Uint128::from)
+}
+
+/// ## Description
+/// Returns the flash loan fee currently charged by the White Whale vault.
+///
+/// ## Params
+/// - **querier** is a reference to an object of type [`QuerierWrapper`].
+///
+/// - **vault_address** is a reference to an object of type [`Addr`].
+pub fn query_flash_loan_fee(querier: &QuerierWrapper, vault_address: &Addr) -> StdResult<Fee> {
+    let response: FeeResponse = querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+        contract_addr: vault_address.to_string(),
+        msg: to_binary(&VaultQueryMsg::Fees {})?,
+    }))?;
+    Ok(response.fees.flash_loan_fee)
+}
+
+/// ## Description
+/// Returns the Terra tax paid on top of sending `amount` of an asset. Tokens are not taxed.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **asset_info** is a reference to an object of type [`AstroportAssetInfo`].
+///
+/// - **amount** is an object of type [`Uint128`].
+pub fn compute_tax(
+    deps: Deps,
+    asset_info: &AstroportAssetInfo,
+    amount: Uint128,
+) -> StdResult<Uint128> {
+    match asset_info {
+        AstroportAssetInfo::NativeToken { denom } => {
+            let terra_querier = TerraQuerier::new(&deps.querier);
+            let tax_rate = terra_querier.query_tax_rate()?.rate;
+            let tax_cap = terra_querier.query_tax_cap(denom.as_str())?.cap;
+            Ok(std::cmp::min(amount * tax_rate, tax_cap))
+        }
+        AstroportAssetInfo::Token { .. } => Ok(Uint128::zero()),
+    }
+}
+
+/// ## Description
+/// Checks that a slippage tolerance leaves something to receive, i.e. is below one.
+///
+/// ## Params
+/// - **slippage_tolerance** is an object of type [`Decimal`].
+pub fn assert_slippage_tolerance(slippage_tolerance: Decimal) -> Result<(), ContractError> {
+    if slippage_tolerance >= Decimal::one() {
+        return Err(ContractError::InvalidSlippageTolerance {});
+    }
+    Ok(())
+}
@@ -0,0 +1,542 @@
+use cosmwasm_std::{
+    coin, to_binary, Addr, CosmosMsg, Decimal, Deps, Fraction, StdError, StdResult, Uint128,
+    WasmMsg,
+};
+use cw20::Cw20ExecuteMsg;
+use terra_cosmwasm::TerraMsgWrapper;
+
+use crate::msg::{BassetHubExecuteMsg, BassetHubQueryMsg, BassetHubStateResponse, WrapperAdapter};
+use crate::state::State;
+use crate::utils::create_astroport_swap_msg;
+
+use astroport::asset::{Asset as AstroportAsset, AssetInfo as AstroportAssetInfo, PairInfo};
+use astroport::pair::{
+    Cw20HookMsg as AstroportPairCw20HookMsg, ExecuteMsg as AstroportPairExecuteMsg,
+    QueryMsg as AstroportPairQueryMsg,
+};
+use astroport::querier::{query_fee_info, query_supply, simulate};
+
+use moneymarket::market::{Cw20HookMsg as AnchorCw20HookMsg, ExecuteMsg as AnchorExecuteMsg};
+
+use white_whale::query::anchor::{AnchorQuery, EpochStateResponse};
+
+// decimal places of the Decimal atomics
+const DECIMAL_FRACTIONAL: u128 = 1_000_000_000_000_000_000;
+
+/// ## Description
+/// Returns the value of one wrapper token in its underlying asset.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **state** is a reference to an object of type [`State`].
+///
+/// - **adapter** is a reference to an object of type [`WrapperAdapter`].
+///
+/// - **block_height** is an object of type [`u64`] which is the height the Anchor exchange rate is computed at.
+pub fn query_wrapper_price(
+    deps: Deps,
+    state: &State,
+    adapter: &WrapperAdapter,
+    block_height: u64,
+) -> StdResult<Decimal> {
+    match adapter {
+        WrapperAdapter::Anchor { market, .. } => {
+            let response: EpochStateResponse = deps.querier.query_wasm_smart(
+                market.to_string(),
+                &AnchorQuery::EpochState {
+                    block_height: Some(block_height),
+                    distributed_interest: None,
+                },
+            )?;
+            Ok(Decimal::from(response.exchange_rate))
+        }
+        WrapperAdapter::BassetHub { hub, .. } => {
+            let response: BassetHubStateResponse = deps
+                .querier
+                .query_wasm_smart(hub.to_string(), &BassetHubQueryMsg::State {})?;
+            Ok(response.bluna_exchange_rate)
+        }
+        // both sides of the pool valued at the spot price
+        WrapperAdapter::AstroportLp { pair, underlying } => {
+            let pool = query_lp_pool(deps, state, pair, underlying)?;
+            if pool.total_share.is_zero() {
+                return Ok(Decimal::zero());
+            }
+            Ok(Decimal::from_ratio(
+                pool.underlying_amount + pool.underlying_amount,
+                pool.total_share,
+            ))
+        }
+    }
+}
+
+/// ## Description
+/// Returns the wrapper tokens minted for `amount` of the underlying asset.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **state** is a reference to an object of type [`State`].
+///
+/// - **adapter** is a reference to an object of type [`WrapperAdapter`].
+///
+/// - **amount** is an object of type [`Uint128`] which is the underlying amount.
+///
+/// - **block_height** is an object of type [`u64`].
+pub fn simulate_wrap(
+    deps: Deps,
+    state: &State,
+    adapter: &WrapperAdapter,
+    amount: Uint128,
+    block_height: u64,
+) -> StdResult<Uint128> {
+    match adapter {
+        WrapperAdapter::Anchor { .. } | WrapperAdapter::BassetHub { .. } => {
+            let price = query_wrapper_price(deps, state, adapter, block_height)?;
+            if price.is_zero() {
+                return Err(StdError::generic_err("Wrapper without exchange rate"));
+            }
+            Ok(amount.multiply_ratio(price.denominator(), price.numerator()))
+        }
+        WrapperAdapter::AstroportLp { pair, underlying } => {
+            let pool = query_lp_pool(deps, state, pair, underlying)?;
+            let (swap_amount, other_amount) = simulate_lp_zap(deps, &pool, pair, amount)?;
+            let provided = amount - swap_amount;
+            // the pool keeps the side provided in excess
+            Ok(std::cmp::min(
+                provided.multiply_ratio(pool.total_share, pool.underlying_amount + swap_amount),
+                other_amount.multiply_ratio(pool.total_share, pool.other_amount - other_amount),
+            ))
+        }
+    }
+}
+
+/// ## Description
+/// Returns the underlying asset received for `amount` of wrapper tokens, none when the
+/// wrapper cannot be burned at once.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **state** is a reference to an object of type [`State`].
+///
+/// - **adapter** is a reference to an object of type [`WrapperAdapter`].
+///
+/// - **amount** is an object of type [`Uint128`] which is the wrapper amount.
+///
+/// - **block_height** is an object of type [`u64`].
+pub fn simulate_unwrap(
+    deps: Deps,
+    state: &State,
+    adapter: &WrapperAdapter,
+    amount: Uint128,
+    block_height: u64,
+) -> StdResult<Option<Uint128>> {
+    match adapter {
+        WrapperAdapter::Anchor { .. } => {
+            let price = query_wrapper_price(deps, state, adapter, block_height)?;
+            Ok(Some(amount * price))
+        }
+        WrapperAdapter::BassetHub { .. } => Ok(None),
+        WrapperAdapter::AstroportLp { pair, underlying } => {
+            let pool = query_lp_pool(deps, state, pair, underlying)?;
+            let (underlying_out, other_out) = simulate_lp_withdraw(deps, &pool, amount)?;
+            // the other side is sold in the pool left after the withdrawal
+            let return_amount = (pool.underlying_amount - underlying_out)
+                .multiply_ratio(other_out, pool.other_amount);
+            Ok(Some(
+                underlying_out + return_amount * (Decimal::one() - pool.fee_rate),
+            ))
+        }
+    }
+}
+
+/// ## Description
+/// Mints wrapper tokens with `amount` of the underlying asset.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **state** is a reference to an object of type [`State`].
+///
+/// - **adapter** is a reference to an object of type [`WrapperAdapter`].
+///
+/// - **amount** is an object of type [`Uint128`] which is the underlying amount.
+pub fn create_wrap_msgs(
+    deps: Deps,
+    state: &State,
+    adapter: &WrapperAdapter,
+    amount: Uint128,
+) -> StdResult<Vec<CosmosMsg<TerraMsgWrapper>>> {
+    match adapter {
+        WrapperAdapter::Anchor { market, denom } => Ok(vec![CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: market.to_string(),
+            msg: to_binary(&AnchorExecuteMsg::DepositStable {})?,
+            funds: vec![coin(amount.u128(), denom)],
+        })]),
+        WrapperAdapter::BassetHub { hub, denom } => Ok(vec![CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: hub.to_string(),
+            msg: to_binary(&BassetHubExecuteMsg::Bond {})?,
+            funds: vec![coin(amount.u128(), denom)],
+        })]),
+        WrapperAdapter::AstroportLp { pair, underlying } => {
+            let pool = query_lp_pool(deps, state, pair, underlying)?;
+            let (swap_amount, other_amount) = simulate_lp_zap(deps, &pool, pair, amount)?;
+
+            // swaps part of the underlying asset, then provides both sides
+            let mut messages = vec![create_astroport_swap_msg(
+                AstroportAsset {
+                    info: underlying.clone(),
+                    amount: swap_amount,
+                },
+                pair.clone(),
+                Some(Decimal::from_ratio(swap_amount, other_amount)),
+                Some(state.slippage_tolerance),
+            )?];
+            let assets = [
+                AstroportAsset {
+                    info: underlying.clone(),
+                    amount: amount - swap_amount,
+                },
+                AstroportAsset {
+                    info: pool.other.clone(),
+                    amount: other_amount,
+                },
+            ];
+            let mut funds = vec![];
+            for asset in assets.iter() {
+                match &asset.info {
+                    AstroportAssetInfo::NativeToken { denom } => {
+                        funds.push(coin(asset.amount.u128(), denom));
+                    }
+                    AstroportAssetInfo::Token { contract_addr } => {
+                        messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
+                            contract_addr: contract_addr.to_string(),
+                            msg: to_binary(&Cw20ExecuteMsg::IncreaseAllowance {
+                                spender: pair.to_string(),
+                                amount: asset.amount,
+                                expires: None,
+                            })?,
+                            funds: vec![],
+                        }));
+                    }
+                }
+            }
+            funds.sort_by(|c1, c2| c1.denom.cmp(&c2.denom));
+            messages.push(CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: pair.to_string(),
+                msg: to_binary(&AstroportPairExecuteMsg::ProvideLiquidity {
+                    assets,
+                    slippage_tolerance: None,
+                    auto_stake: None,
+                    receiver: None,
+                })?,
+                funds,
+            }));
+            Ok(messages)
+        }
+    }
+}
+
+/// ## Description
+/// Burns `amount` of wrapper tokens for the underlying asset.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **state** is a reference to an object of type [`State`].
+///
+/// - **token** is a reference to an object of type [`Addr`] which is the wrapper token.
+///
+/// - **adapter** is a reference to an object of type [`WrapperAdapter`].
+///
+/// - **amount** is an object of type [`Uint128`] which is the wrapper amount.
+pub fn create_unwrap_msgs(
+    deps: Deps,
+    state: &State,
+    token: &Addr,
+    adapter: &WrapperAdapter,
+    amount: Uint128,
+) -> StdResult<Vec<CosmosMsg<TerraMsgWrapper>>> {
+    match adapter {
+        WrapperAdapter::Anchor { market, .. } => Ok(vec![CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr: token.to_string(),
+            msg: to_binary(&Cw20ExecuteMsg::Send {
+                contract: market.to_string(),
+                amount,
+                msg: to_binary(&AnchorCw20HookMsg::RedeemStable {})?,
+            })?,
+            funds: vec![],
+        })]),
+        WrapperAdapter::BassetHub { .. } => {
+            Err(StdError::generic_err("bLUNA cannot be unbonded at once"))
+        }
+        WrapperAdapter::AstroportLp { pair, underlying } => {
+            let pool = query_lp_pool(deps, state, pair, underlying)?;
+            let (underlying_out, other_out) = simulate_lp_withdraw(deps, &pool, amount)?;
+
+            // withdraws both sides, then sells the other side in the same pool
+            let mut messages = vec![CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: token.to_string(),
+                msg: to_binary(&Cw20ExecuteMsg::Send {
+                    contract: pair.to_string(),
+                    amount,
+                    msg: to_binary(&AstroportPairCw20HookMsg::WithdrawLiquidity {})?,
+                })?,
+                funds: vec![],
+            })];
+            let return_amount = (pool.underlying_amount - underlying_out)
+                .multiply_ratio(other_out, pool.other_amount)
+                * (Decimal::one() - pool.fee_rate);
+            if !other_out.is_zero() && !return_amount.is_zero() {
+                messages.push(create_astroport_swap_msg(
+                    AstroportAsset {
+                        info: pool.other,
+                        amount: other_out,
+                    },
+                    pair.clone(),
+                    Some(Decimal::from_ratio(other_out, return_amount)),
+                    Some(state.slippage_tolerance),
+                )?);
+            }
+            Ok(messages)
+        }
+    }
+}
+
+/// ## Description
+/// Checks that an Astroport LP adapter matches its wrapper token and pair.
+///
+/// ## Params
+/// - **deps** is an object of type [`Deps`].
+///
+/// - **token** is a reference to an object of type [`Addr`] which is the wrapper token.
+///
+/// - **pair** is a reference to an object of type [`Addr`].
+///
+/// - **underlying** is a reference to an object of type [`AstroportAssetInfo`].
+pub fn assert_lp_wrapper(
+    deps: Deps,
+    token: &Addr,
+    pair: &Addr,
+    underlying: &AstroportAssetInfo,
+) -> StdResult<bool> {
+    let pair_info: PairInfo = deps
+        .querier
+        .query_wasm_smart(pair.to_string(), &AstroportPairQueryMsg::Pair {})?;
+    Ok(pair_info.liquidity_token == *token
+        && pair_info
+            .asset_infos
+            .iter()
+            .any(|asset_info| asset_info.equal(underlying)))
+}
+
+/// An Astroport pool seen from the underlying side of an LP wrapper.
+struct LpPool {
+    underlying: AstroportAssetInfo,
+    other: AstroportAssetInfo,
+    underlying_amount: Uint128,
+    other_amount: Uint128,
+    total_share: Uint128,
+    fee_rate: Decimal,
+}
+
+fn query_lp_pool(
+    deps: Deps,
+    state: &State,
+    pair: &Addr,
+    underlying: &AstroportAssetInfo,
+) -> StdResult<LpPool> {
+    let pair_info: PairInfo = deps
+        .querier
+        .query_wasm_smart(pair.to_string(), &AstroportPairQueryMsg::Pair {})?;
+    let pools = pair_info.query_pools(&deps.querier, pair.clone())?;
+    let (underlying_pool, other_pool) = if pools[0].info.equal(underlying) {
+        (&pools[0], &pools[1])
+    } else {
+        (&pools[1], &pools[0])
+    };
+    Ok(LpPool {
+        underlying: underlying_pool.info.clone(),
+        other: other_pool.info.clone(),
+        underlying_amount: underlying_pool.amount,
+        other_amount: other_pool.amount,
+        total_share: query_supply(&deps.querier, pair_info.liquidity_token.clone())?,
+        fee_rate: query_fee_info(
+            &deps.querier,
+            state.astroport_factory_address.clone(),
+            pair_info.pair_type,
+        )?
+        .total_fee_rate,
+    })
+}
+
+/// Returns the underlying amount swapped before providing `amount` from one side of the pool,
+/// and the other side received, net of tax.
+fn simulate_lp_zap(
+    deps: Deps,
+    pool: &LpPool,
+    pair: &Addr,
+    amount: Uint128,
+) -> StdResult<(Uint128, Uint128)> {
+    let swap_amount = zap_swap_amount(pool, amount)?;
+
+    let offer_asset = AstroportAsset {
+        info: pool.underlying.clone(),
+        amount: swap_amount,
+    };
+    let return_amount = simulate(&deps.querier, pair.clone(), &offer_asset)?.return_amount;
+    let received = AstroportAsset {
+        info: pool.other.clone(),
+        amount: return_amount,
+    };
+    let other_amount = return_amount - received.compute_tax(&deps.querier)?;
+    if other_amount.is_zero() {
+        return Err(StdError::generic_err("Nothing to provide"));
+    }
+    Ok((swap_amount, other_amount))
+}
+
+/// Returns the underlying amount to swap so that the rest of `amount` and the other side
+/// bought with it match the pool ratio once the swap is done.
+fn zap_swap_amount(pool: &LpPool, amount: Uint128) -> StdResult<Uint128> {
+    if pool.underlying_amount.is_zero() || pool.other_amount.is_zero() {
+        return Err(StdError::generic_err("Empty liquidity pool"));
+    }
+
+    // s = R (sqrt((2 - f)^2 + 4 (1 - f) a / R) - (2 - f)) / (2 (1 - f))
+    let two_minus_fee = (Decimal::one() + Decimal::one() - pool.fee_rate).numerator();
+    let one_minus_fee = (Decimal::one() - pool.fee_rate).numerator();
+    let discriminant = Uint128::from(two_minus_fee * two_minus_fee / DECIMAL_FRACTIONAL)
+        + Uint128::from(4 * one_minus_fee).multiply_ratio(amount, pool.underlying_amount);
+    let root = Decimal::from_ratio(discriminant, DECIMAL_FRACTIONAL).sqrt();
+    let swap_amount = pool.underlying_amount.multiply_ratio(
+        root.numerator().saturating_sub(two_minus_fee),
+        2 * one_minus_fee,
+    );
+    Ok(std::cmp::min(swap_amount, amount))
+}
+
+/// Returns both sides withdrawn for `amount` of LP tokens, rounded down like the pair does.
+fn withdraw_amounts(pool: &LpPool, amount: Uint128) -> StdResult<(Uint128, Uint128)> {
+    if pool.total_share.is_zero() {
+        return Err(StdError::generic_err("Empty liquidity pool"));
+    }
+    let share_ratio = Decimal::from_ratio(amount, pool.total_share);
+    Ok((
+        pool.underlying_amount * share_ratio,
+        pool.other_amount * share_ratio,
+    ))
+}
+
+/// Returns both sides withdrawn for `amount` of LP tokens, net of tax.
+fn simulate_lp_withdraw(
+    deps: Deps,
+    pool: &LpPool,
+    amount: Uint128,
+) -> StdResult<(Uint128, Uint128)> {
+    let (underlying_amount, other_amount) = withdraw_amounts(pool, amount)?;
+    let underlying = AstroportAsset {
+        info: pool.underlying.clone(),
+        amount: underlying_amount,
+    };
+    let other = AstroportAsset {
+        info: pool.other.clone(),
+        amount: other_amount,
+    };
+    Ok((
+        underlying.amount - underlying.compute_tax(&deps.querier)?,
+        other.amount - other.compute_tax(&deps.querier)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(underlying_amount: u128, other_amount: u128, total_share: u128) -> LpPool {
+        LpPool {
+            underlying: AstroportAssetInfo::NativeToken {
+                denom: "uusd".to_string(),
+            },
+            other: AstroportAssetInfo::Token {
+                contract_addr: Addr::unchecked("token"),
+            },
+            underlying_amount: Uint128::from(underlying_amount),
+            other_amount: Uint128::from(other_amount),
+            total_share: Uint128::from(total_share),
+            fee_rate: Decimal::permille(3),
+        }
+    }
+
+    // xyk swap of the Astroport pair, commission taken from the return
+    fn swap(pool: &LpPool, offer_amount: Uint128) -> Uint128 {
+        let return_amount = pool.other_amount
+            - pool.other_amount.multiply_ratio(
+                pool.underlying_amount,
+                pool.underlying_amount + offer_amount,
+            );
+        return_amount - return_amount * pool.fee_rate
+    }
+
+    #[test]
+    fn zap_provides_at_the_pool_ratio() {
+        let pool = pool(1_000_000_000_000, 2_000_000_000_000, 1_400_000_000_000);
+        for amount in [1_000_000u128, 10_000_000_000, 500_000_000_000] {
+            let amount = Uint128::from(amount);
+            let swap_amount = zap_swap_amount(&pool, amount).unwrap();
+            let other_amount = swap(&pool, swap_amount);
+
+            // shares minted by each side once the swap moved the pool, the closed form charges
+            // the fee on the offer while the pair charges it on the return
+            let underlying_share = (amount - swap_amount)
+                .multiply_ratio(pool.total_share, pool.underlying_amount + swap_amount);
+            let other_share =
+                other_amount.multiply_ratio(pool.total_share, pool.other_amount - other_amount);
+            let diff = if underlying_share > other_share {
+                underlying_share - other_share
+            } else {
+                other_share - underlying_share
+            };
+            assert!(
+                diff <= underlying_share.multiply_ratio(1u8, 1_000u16) + Uint128::from(1u8),
+                "{} {}",
+                underlying_share,
+                other_share
+            );
+        }
+    }
+
+    #[test]
+    fn zap_needs_both_sides() {
+        let pool = pool(1_000_000, 0, 1_000_000);
+        assert!(zap_swap_amount(&pool, Uint128::from(1_000u128)).is_err());
+    }
+
+    #[test]
+    fn withdraw_is_pro_rata_rounded_down() {
+        let pool = pool(10, 1_000_000_000_007, 3);
+        assert_eq!(
+            withdraw_amounts(&pool, Uint128::from(3u8)).unwrap(),
+            (Uint128::from(10u8), Uint128::from(1_000_000_000_007u128))
+        );
+        assert_eq!(
+            withdraw_amounts(&pool, Uint128::from(1u8)).unwrap(),
+            (Uint128::from(3u8), Uint128::from(333_333_333_335u128))
+        );
+
+        // splitting a withdrawal never returns more than the pool holds
+        let (first_underlying, first_other) = withdraw_amounts(&pool, Uint128::from(1u8)).unwrap();
+        let (second_underlying, second_other) =
+            withdraw_amounts(&pool, Uint128::from(2u8)).unwrap();
+        assert!(first_underlying + second_underlying <= pool.underlying_amount);
+        assert!(first_other + second_other <= pool.other_amount);
+    }
+
+    #[test]
+    fn withdraw_from_an_empty_pool_fails() {
+        let pool = pool(0, 0, 0);
+        assert!(withdraw_amounts(&pool, Uint128::from(1u8)).is_err());
+    }
+}